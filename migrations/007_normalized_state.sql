@@ -0,0 +1,172 @@
+-- 规范化的项目状态表，替代 user_state.state_json 的整体读写
+
+CREATE TABLE IF NOT EXISTS projects (
+    id TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'ACTIVE',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_projects_user_id ON projects(user_id);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    session_id TEXT PRIMARY KEY,
+    project_id TEXT NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    stage TEXT NOT NULL,
+    context_snapshot TEXT NOT NULL DEFAULT ''
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_project_id ON sessions(project_id);
+
+CREATE TABLE IF NOT EXISTS conversation_turns (
+    id BIGSERIAL PRIMARY KEY,
+    session_id TEXT NOT NULL REFERENCES sessions(session_id) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_conversation_turns_session_id ON conversation_turns(session_id);
+
+CREATE TABLE IF NOT EXISTS idea_events (
+    event_id TEXT PRIMARY KEY,
+    seq BIGSERIAL,
+    session_id TEXT NOT NULL REFERENCES sessions(session_id) ON DELETE CASCADE,
+    user_input TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_idea_events_session_id ON idea_events(session_id);
+
+CREATE TABLE IF NOT EXISTS prd_versions (
+    prd_id TEXT PRIMARY KEY,
+    project_id TEXT NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    version INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    diff_from_prev TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (project_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS task_runs (
+    task_id TEXT PRIMARY KEY,
+    seq BIGSERIAL,
+    project_id TEXT NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    plan_json TEXT NOT NULL DEFAULT '',
+    run_status TEXT NOT NULL,
+    logs TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_task_runs_project_id ON task_runs(project_id);
+
+CREATE TABLE IF NOT EXISTS deployment_runs (
+    deploy_id TEXT PRIMARY KEY,
+    seq BIGSERIAL,
+    project_id TEXT NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    env TEXT NOT NULL,
+    version TEXT NOT NULL,
+    result TEXT NOT NULL,
+    rollback_hint TEXT NOT NULL DEFAULT '',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_deployment_runs_project_id ON deployment_runs(project_id);
+
+-- 把已有的 state_json 拆分写入上述表（原 JSONB 保留作备份，不再读写）
+INSERT INTO projects (id, user_id, name, status, created_at, updated_at)
+SELECT p.key,
+       us.user_id,
+       COALESCE(p.value->>'name', 'celadon-project'),
+       COALESCE(p.value->>'status', 'ACTIVE'),
+       COALESCE((p.value->>'created_at')::timestamptz, now()),
+       COALESCE((p.value->>'updated_at')::timestamptz, now())
+FROM user_state us,
+     jsonb_each(COALESCE(us.state_json->'projects', '{}'::jsonb)) AS p
+ON CONFLICT (id) DO NOTHING;
+
+INSERT INTO sessions (session_id, project_id, stage, context_snapshot)
+SELECT s.key,
+       s.value->>'project_id',
+       COALESCE(s.value->>'stage', 'CLARIFYING'),
+       COALESCE(s.value->>'context_snapshot', '')
+FROM user_state us,
+     jsonb_each(COALESCE(us.state_json->'sessions', '{}'::jsonb)) AS s
+WHERE EXISTS (SELECT 1 FROM projects p WHERE p.id = s.value->>'project_id')
+ON CONFLICT (session_id) DO NOTHING;
+
+INSERT INTO conversation_turns (session_id, role, content, created_at)
+SELECT t.value->>'session_id',
+       COALESCE(t.value->>'role', 'user'),
+       COALESCE(t.value->>'content', ''),
+       COALESCE((t.value->>'created_at')::timestamptz, now())
+FROM user_state us,
+     jsonb_array_elements(COALESCE(us.state_json->'conversation_turns', '[]'::jsonb))
+         WITH ORDINALITY AS t(value, ord)
+WHERE EXISTS (SELECT 1 FROM sessions s WHERE s.session_id = t.value->>'session_id')
+ORDER BY us.user_id, t.ord;
+
+-- 早期数据只有 idea_events，没有 conversation_turns：按用户输入补齐对话
+INSERT INTO conversation_turns (session_id, role, content, created_at)
+SELECT e.value->>'session_id',
+       'user',
+       COALESCE(e.value->>'user_input', ''),
+       COALESCE((e.value->>'created_at')::timestamptz, now())
+FROM user_state us,
+     jsonb_array_elements(COALESCE(us.state_json->'idea_events', '[]'::jsonb))
+         WITH ORDINALITY AS e(value, ord)
+WHERE jsonb_array_length(COALESCE(us.state_json->'conversation_turns', '[]'::jsonb)) = 0
+  AND EXISTS (SELECT 1 FROM sessions s WHERE s.session_id = e.value->>'session_id')
+ORDER BY us.user_id, e.ord;
+
+INSERT INTO idea_events (event_id, session_id, user_input, created_at)
+SELECT COALESCE(e.value->>'event_id', gen_random_uuid()::text),
+       e.value->>'session_id',
+       COALESCE(e.value->>'user_input', ''),
+       COALESCE((e.value->>'created_at')::timestamptz, now())
+FROM user_state us,
+     jsonb_array_elements(COALESCE(us.state_json->'idea_events', '[]'::jsonb))
+         WITH ORDINALITY AS e(value, ord)
+WHERE EXISTS (SELECT 1 FROM sessions s WHERE s.session_id = e.value->>'session_id')
+ORDER BY us.user_id, e.ord
+ON CONFLICT (event_id) DO NOTHING;
+
+INSERT INTO prd_versions (prd_id, project_id, version, content, diff_from_prev)
+SELECT COALESCE(v.value->>'prd_id', gen_random_uuid()::text),
+       v.value->>'project_id',
+       (v.value->>'version')::integer,
+       COALESCE(v.value->>'content', ''),
+       v.value->>'diff_from_prev'
+FROM user_state us,
+     jsonb_array_elements(COALESCE(us.state_json->'prd_versions', '[]'::jsonb)) AS v
+WHERE EXISTS (SELECT 1 FROM projects p WHERE p.id = v.value->>'project_id')
+ON CONFLICT DO NOTHING;
+
+INSERT INTO task_runs (task_id, project_id, plan_json, run_status, logs)
+SELECT COALESCE(t.value->>'task_id', gen_random_uuid()::text),
+       t.value->>'project_id',
+       COALESCE(t.value->>'plan_json', ''),
+       COALESCE(t.value->>'run_status', 'UNKNOWN'),
+       COALESCE(t.value->>'logs', '')
+FROM user_state us,
+     jsonb_array_elements(COALESCE(us.state_json->'task_runs', '[]'::jsonb))
+         WITH ORDINALITY AS t(value, ord)
+WHERE EXISTS (SELECT 1 FROM projects p WHERE p.id = t.value->>'project_id')
+ORDER BY us.user_id, t.ord
+ON CONFLICT (task_id) DO NOTHING;
+
+INSERT INTO deployment_runs (deploy_id, project_id, env, version, result, rollback_hint)
+SELECT COALESCE(d.value->>'deploy_id', gen_random_uuid()::text),
+       d.value->>'project_id',
+       COALESCE(d.value->>'env', 'staging'),
+       COALESCE(d.value->>'version', ''),
+       COALESCE(d.value->>'result', ''),
+       COALESCE(d.value->>'rollback_hint', '')
+FROM user_state us,
+     jsonb_array_elements(COALESCE(us.state_json->'deployment_runs', '[]'::jsonb))
+         WITH ORDINALITY AS d(value, ord)
+WHERE EXISTS (SELECT 1 FROM projects p WHERE p.id = d.value->>'project_id')
+ORDER BY us.user_id, d.ord
+ON CONFLICT (deploy_id) DO NOTHING;
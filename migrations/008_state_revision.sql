@@ -0,0 +1,2 @@
+-- 乐观并发控制：每次写入用户状态时 revision 加一，提交时比较
+ALTER TABLE user_state ADD COLUMN IF NOT EXISTS revision BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- 状态文档的 schema 版本；低于当前版本时加载会执行 schema 迁移后写回
+ALTER TABLE user_state ADD COLUMN IF NOT EXISTS schema_version INTEGER NOT NULL DEFAULT 0;
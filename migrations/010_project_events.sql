@@ -0,0 +1,13 @@
+-- 只追加的项目事件日志：按 seq 重放即可得到项目状态
+-- 不对 projects 建外键：ProjectPurged 事件与删除项目行在同一事务中写入，日志要比项目行留得久，
+-- 彻底删除项目时再由 delete_project_events 清理
+CREATE TABLE IF NOT EXISTS project_events (
+    seq BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    project_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_project_events_project ON project_events(user_id, project_id, seq);
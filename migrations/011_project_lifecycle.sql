@@ -0,0 +1,5 @@
+-- 项目归档 / 软删除：status 取 ACTIVE | ARCHIVED | DELETED，deleted_at 用于计算恢复期限
+
+ALTER TABLE projects ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+
+CREATE INDEX IF NOT EXISTS idx_projects_user_status ON projects(user_id, status);
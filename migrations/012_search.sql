@@ -0,0 +1,22 @@
+-- 全文检索：对话与 PRD 的 tsvector 索引，以及 workspace 文件的可检索副本
+
+CREATE INDEX IF NOT EXISTS idx_conversation_turns_fts
+    ON conversation_turns USING GIN (to_tsvector('simple', content));
+
+CREATE INDEX IF NOT EXISTS idx_prd_versions_fts
+    ON prd_versions USING GIN (to_tsvector('simple', content));
+
+CREATE TABLE IF NOT EXISTS workspace_documents (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    project_id TEXT NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    path TEXT NOT NULL,
+    content TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (project_id, path)
+);
+
+CREATE INDEX IF NOT EXISTS idx_workspace_documents_user ON workspace_documents(user_id);
+
+CREATE INDEX IF NOT EXISTS idx_workspace_documents_fts
+    ON workspace_documents USING GIN (to_tsvector('simple', content));
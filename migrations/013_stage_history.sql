@@ -0,0 +1,3 @@
+-- 会话阶段变更历史：[{ "from", "to", "at", "reason" }, ...]
+
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS stage_history JSONB NOT NULL DEFAULT '[]'::jsonb;
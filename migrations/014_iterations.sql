@@ -0,0 +1,41 @@
+-- 增量迭代：每轮由一批想法触发，关联 PRD 版本、开发任务与部署
+
+CREATE TABLE IF NOT EXISTS iterations (
+    iteration_id TEXT PRIMARY KEY,
+    project_id TEXT NOT NULL REFERENCES projects(id) ON DELETE CASCADE,
+    session_id TEXT NOT NULL,
+    number INTEGER NOT NULL,
+    idea_event_ids JSONB NOT NULL DEFAULT '[]'::jsonb,
+    prd_version INTEGER,
+    task_ids JSONB NOT NULL DEFAULT '[]'::jsonb,
+    deploy_id TEXT,
+    status TEXT NOT NULL DEFAULT 'OPEN',
+    opened_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    delivered_at TIMESTAMPTZ,
+    UNIQUE (project_id, number)
+);
+
+CREATE INDEX IF NOT EXISTS idx_iterations_project_id ON iterations(project_id);
+
+-- 已有项目整体视为第 1 轮，已有部署即视为已交付
+INSERT INTO iterations (iteration_id, project_id, session_id, number, idea_event_ids,
+                        prd_version, task_ids, deploy_id, status, opened_at, delivered_at)
+SELECT gen_random_uuid()::text,
+       p.id,
+       (SELECT s.session_id FROM sessions s WHERE s.project_id = p.id ORDER BY s.session_id LIMIT 1),
+       1,
+       COALESCE((SELECT jsonb_agg(e.event_id ORDER BY e.seq)
+                 FROM idea_events e JOIN sessions s ON s.session_id = e.session_id
+                 WHERE s.project_id = p.id), '[]'::jsonb),
+       (SELECT max(v.version) FROM prd_versions v WHERE v.project_id = p.id),
+       COALESCE((SELECT jsonb_agg(t.task_id ORDER BY t.seq)
+                 FROM task_runs t WHERE t.project_id = p.id), '[]'::jsonb),
+       (SELECT d.deploy_id FROM deployment_runs d WHERE d.project_id = p.id ORDER BY d.seq DESC LIMIT 1),
+       CASE WHEN EXISTS (SELECT 1 FROM deployment_runs d WHERE d.project_id = p.id)
+            THEN 'DELIVERED' ELSE 'OPEN' END,
+       p.created_at,
+       CASE WHEN EXISTS (SELECT 1 FROM deployment_runs d WHERE d.project_id = p.id)
+            THEN p.updated_at END
+FROM projects p
+WHERE EXISTS (SELECT 1 FROM sessions s WHERE s.project_id = p.id)
+ON CONFLICT DO NOTHING;
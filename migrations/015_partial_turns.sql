@@ -0,0 +1,3 @@
+-- 流式澄清被中断时，助手回复以 partial = true 保存
+
+ALTER TABLE conversation_turns ADD COLUMN IF NOT EXISTS partial BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,3 @@
+-- 会话的结构化需求清单：{ "scope", "tech_stack", "budget", "deadline", "success_criteria", "open_questions", "updated_at" }
+
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS requirements JSONB NOT NULL DEFAULT '{}'::jsonb;
@@ -0,0 +1,3 @@
+-- 项目级开关：{ "auto_generate_prd": bool }
+
+ALTER TABLE projects ADD COLUMN IF NOT EXISTS settings JSONB NOT NULL DEFAULT '{}'::jsonb;
@@ -0,0 +1,3 @@
+-- 会话的滚动摘要：{ "content", "covered_turns", "updated_at" }，较早的轮次折叠进摘要后不再整段发给模型
+
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS summary JSONB NOT NULL DEFAULT '{}'::jsonb;
@@ -0,0 +1,13 @@
+-- 提示词模板版本：每次保存或回滚追加一行，同名同项目的最大版本生效；project_id 为空串表示全局模板
+
+CREATE TABLE IF NOT EXISTS prompt_templates (
+    id BIGSERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    project_id TEXT NOT NULL DEFAULT '',
+    version INT NOT NULL,
+    content TEXT NOT NULL,
+    note TEXT,
+    created_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    UNIQUE (name, project_id, version)
+);
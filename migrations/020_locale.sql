@@ -0,0 +1,8 @@
+-- 用户语言设置（zh / en，NULL 表示跟随 Accept-Language），以及按语言区分的提示词模板（空串表示不限语言）
+
+ALTER TABLE users ADD COLUMN IF NOT EXISTS locale TEXT;
+
+ALTER TABLE prompt_templates ADD COLUMN IF NOT EXISTS locale TEXT NOT NULL DEFAULT '';
+ALTER TABLE prompt_templates DROP CONSTRAINT IF EXISTS prompt_templates_name_project_id_version_key;
+ALTER TABLE prompt_templates ADD CONSTRAINT prompt_templates_name_project_id_locale_version_key
+    UNIQUE (name, project_id, locale, version);
@@ -0,0 +1,19 @@
+-- 模型调用用量：每次调用一行，按用户、项目、角色（planner/executor/reflector）与模型汇总；
+-- cost 为写入时按 CELADON_LLM_PRICES 折算的美元费用。项目删除后记录保留，不设外键
+
+CREATE TABLE IF NOT EXISTS llm_usage (
+    usage_id TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    project_id TEXT NOT NULL,
+    session_id TEXT,
+    role TEXT NOT NULL,
+    model TEXT NOT NULL,
+    prompt_tokens BIGINT NOT NULL DEFAULT 0,
+    completion_tokens BIGINT NOT NULL DEFAULT 0,
+    cost DOUBLE PRECISION NOT NULL DEFAULT 0,
+    estimated BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS idx_llm_usage_user_project ON llm_usage(user_id, project_id);
+CREATE INDEX IF NOT EXISTS idx_llm_usage_created_at ON llm_usage(created_at);
@@ -0,0 +1,8 @@
+-- 管理员为用户设置的每月用量上限（UTC 自然月），为空表示不限；项目上限保存在 projects.settings.budget
+
+CREATE TABLE IF NOT EXISTS user_budgets (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    max_tokens BIGINT,
+    max_cost DOUBLE PRECISION,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
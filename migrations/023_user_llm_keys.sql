@@ -0,0 +1,12 @@
+-- 用户自带的模型 Key：每个用户每个角色（planner/executor/reflector）一组服务商、模型与 Key。
+-- api_key 为 CELADON_MASTER_KEY 加密后的密文，接口只返回掩码；优先于 system_settings 中的全局 Key
+
+CREATE TABLE IF NOT EXISTS user_llm_keys (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    provider TEXT NOT NULL,
+    model TEXT NOT NULL,
+    api_key TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, role)
+);
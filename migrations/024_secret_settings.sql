@@ -0,0 +1,8 @@
+-- 密钥类设置（API Key 等）用信封加密保存：value 为密文，data_key 为 CELADON_SETTINGS_KEK 加密的数据密钥。
+-- data_key 为空的密钥行仍是明文，服务启动时配置了 KEK 即自动加密
+
+ALTER TABLE system_settings ADD COLUMN IF NOT EXISTS secret BOOLEAN NOT NULL DEFAULT false;
+ALTER TABLE system_settings ADD COLUMN IF NOT EXISTS data_key TEXT;
+
+UPDATE system_settings SET secret = true
+WHERE key LIKE '%API_KEY' OR key = 'CELADON_LLM_FALLBACKS';
@@ -0,0 +1,2 @@
+-- TESTING 阶段提交的测试结论（{"passed", "notes", "tested_at"}），部署前必须通过
+ALTER TABLE iterations ADD COLUMN IF NOT EXISTS test_result JSONB;
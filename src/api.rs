@@ -1,9 +1,16 @@
 use crate::auth;
-use crate::common::AppResult;
+use crate::clients::{self, LlmError};
+use crate::common::{AppResult, StateConflict};
 use crate::db;
-use crate::service::CeladonService;
-use axum::extract::{Path, State, Query};
-use axum::http::header::AUTHORIZATION;
+use crate::i18n::{self, Locale, Message};
+use crate::models::{Budget, ProjectSettingsPatch, ProjectStatus, RequirementsSheet, UsageRole};
+use crate::service::{CeladonService, DevLogEvent};
+use crate::store;
+use crate::usage::BudgetExceeded;
+use crate::workflow::StageError;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, State, Query};
+use axum::http::header::{ACCEPT_LANGUAGE, AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response, Json, sse::{Event, Sse}};
 use axum::routing::{get, post};
@@ -12,43 +19,84 @@ use futures_core::stream::Stream;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 use std::convert::Infallible;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::{fs, path::PathBuf};
 use tower_http::cors::{Any, CorsLayer};
 use uuid::Uuid;
 
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{Mutex, mpsc};
 use std::sync::Arc;
-use zene::AgentEvent;
 
 #[derive(Clone)]
 struct ApiState {
     storage_dir: PathBuf,
     pool: Option<db::Pool>,
-    streams: Arc<Mutex<HashMap<String, mpsc::UnboundedReceiver<AgentEvent>>>>,
+    streams: Arc<Mutex<HashMap<String, mpsc::UnboundedReceiver<DevLogEvent>>>>,
 }
 
 #[derive(Debug)]
-struct ApiError(String);
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST,
+            message: message.into(),
+        }
+    }
+}
 
 impl From<Box<dyn std::error::Error + Send + Sync>> for ApiError {
     fn from(value: Box<dyn std::error::Error + Send + Sync>) -> Self {
-        Self(value.to_string())
+        // 写入冲突在服务层已重试过，仍失败则返回 409，由客户端重新加载后再提交
+        let status = if value.is::<StateConflict>() {
+            StatusCode::CONFLICT
+        } else if let Some(e) = value.downcast_ref::<StageError>() {
+            // 阶段不允许该动作为 409，缺少 PRD 等前置产物为 422
+            match e {
+                StageError::IllegalTransition { .. } => StatusCode::CONFLICT,
+                StageError::MissingPrerequisite { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            }
+        } else if value.is::<BudgetExceeded>() {
+            StatusCode::PAYMENT_REQUIRED
+        } else if let Some(e) = value.downcast_ref::<LlmError>() {
+            // 模型服务的问题不是调用方的错，按上游失败的类型返回；原始信息只记日志
+            if let Some(detail) = e.detail() {
+                tracing::error!("LLM 调用失败（{}）: {detail}", e.code());
+            }
+            match e {
+                LlmError::NotConfigured => StatusCode::SERVICE_UNAVAILABLE,
+                LlmError::Quota(_) => StatusCode::PAYMENT_REQUIRED,
+                LlmError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+                LlmError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+                LlmError::ContextTooLong(_) => StatusCode::PAYLOAD_TOO_LARGE,
+                LlmError::Auth(_) | LlmError::Provider(_) => StatusCode::BAD_GATEWAY,
+            }
+        } else {
+            StatusCode::BAD_REQUEST
+        };
+        Self {
+            status,
+            message: value.to_string(),
+        }
     }
 }
 
 impl From<String> for ApiError {
     fn from(value: String) -> Self {
-        Self(value)
+        Self::new(value)
     }
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
         (
-            StatusCode::BAD_REQUEST,
+            self.status,
             Json(json!({
-                "error": self.0
+                "error": self.message
             })),
         )
             .into_response()
@@ -81,6 +129,13 @@ struct DevRunRequest {
     dry_run: Option<bool>,
 }
 
+#[derive(Deserialize)]
+struct TestResultRequest {
+    session_id: String,
+    passed: bool,
+    notes: Option<String>,
+}
+
 #[derive(Deserialize)]
 struct DeployRequest {
     session_id: String,
@@ -105,7 +160,14 @@ struct WaitingListRequest {
     idea: String,
 }
 
+/// 导入包的请求体上限（含 workspace，默认 2MB 不够用）
+const MAX_BUNDLE_BYTES: usize = 256 * 1024 * 1024;
+
+/// 清理过期软删除项目的默认间隔（秒）
+const DEFAULT_PURGE_INTERVAL_SECS: u64 = 3600;
+
 pub async fn serve(storage_dir: PathBuf, port: u16, pool: Option<db::Pool>) -> AppResult<()> {
+    store::prepare(&storage_dir).await?;
     let state = ApiState {
         storage_dir,
         pool,
@@ -116,22 +178,65 @@ pub async fn serve(storage_dir: PathBuf, port: u16, pool: Option<db::Pool>) -> A
         .route("/api/start", post(start))
         .route("/api/waiting-list", post(join_waiting_list))
         .route("/api/idea", post(idea))
+        .route("/api/idea/stream", post(idea_stream))
+        .route("/api/start/stream", post(start_stream))
         .route("/api/prd/generate", post(generate_prd))
         .route("/api/dev/run", post(run_dev))
         .route("/api/dev/files", get(dev_files))
         .route("/api/dev/files/content", get(dev_file_content))
         .route("/api/dev/stream/{session_id}", get(dev_stream))
+        .route("/api/test/start", post(start_tests))
+        .route("/api/test/result", post(record_test_result))
         .route("/api/deploy", post(run_deploy))
         .route("/api/projects", get(list_projects))
+        .route(
+            "/api/projects/{project_id}",
+            axum::routing::patch(update_project).delete(delete_project),
+        )
+        .route("/api/projects/{project_id}/events", get(project_events))
+        .route(
+            "/api/projects/{project_id}/settings",
+            get(get_project_settings).put(put_project_settings),
+        )
+        .route("/api/projects/{project_id}/export", get(export_project))
+        .route(
+            "/api/projects/import",
+            post(import_project).layer(DefaultBodyLimit::max(MAX_BUNDLE_BYTES)),
+        )
+        .route("/api/search", get(search))
+        .route("/api/usage", get(get_usage))
+        .route("/api/sessions/{session_id}/actions", get(session_actions))
+        .route(
+            "/api/sessions/{session_id}/requirements",
+            get(get_requirements).put(put_requirements),
+        )
         .route("/api/status/{session_id}", get(status));
         if state.pool.is_some() {
         app = app
             .route("/api/register", post(register))
             .route("/api/login", post(login))
             .route("/api/me", get(me))
+            .route("/api/me/locale", axum::routing::put(update_locale))
+            .route("/api/me/llm-keys", get(list_llm_keys))
+            .route(
+                "/api/me/llm-keys/{role}",
+                axum::routing::put(put_llm_key).delete(delete_llm_key),
+            )
             .route("/api/logout", post(logout))
             .route("/api/admin/settings", get(get_all_settings))
             .route("/api/admin/settings", post(update_system_setting))
+            .route("/api/admin/settings/rotate", post(rotate_settings_key))
+            .route("/api/admin/prompts", get(list_prompts))
+            .route(
+                "/api/admin/prompts/{name}",
+                get(get_prompt).put(save_prompt),
+            )
+            .route("/api/admin/prompts/{name}/preview", post(preview_prompt))
+            .route("/api/admin/prompts/{name}/rollback", post(rollback_prompt))
+            .route("/api/admin/llm/attempts", get(get_llm_attempts))
+            .route("/api/admin/usage", get(get_admin_usage))
+            .route("/api/admin/budgets", get(get_user_budgets))
+            .route("/api/admin/budgets/{user_id}", axum::routing::put(put_user_budget))
             .route("/api/admin/providers", get(get_providers))
             .route("/api/admin/providers_info", get(get_providers_info));
     }
@@ -140,7 +245,12 @@ pub async fn serve(storage_dir: PathBuf, port: u16, pool: Option<db::Pool>) -> A
         .allow_methods(Any)
         .allow_headers(Any);
 
-    let app = app.with_state(state).layer(cors);
+    let app = app
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn(with_locale))
+        .layer(cors);
+
+    tokio::spawn(purge_expired_loop(state.clone()));
 
     let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
     println!("Celadon API running on http://localhost:{port}");
@@ -148,10 +258,57 @@ pub async fn serve(storage_dir: PathBuf, port: u16, pool: Option<db::Pool>) -> A
     Ok(())
 }
 
+/// 定时彻底删除超过恢复期的软删除项目，间隔由 CELADON_PURGE_INTERVAL_SECS 设置（默认 3600 秒）
+async fn purge_expired_loop(state: ApiState) {
+    let secs = std::env::var("CELADON_PURGE_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_PURGE_INTERVAL_SECS);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(secs));
+    loop {
+        interval.tick().await;
+        let users = match store::stored_users(&state.storage_dir, state.pool.as_ref()).await {
+            Ok(users) => users,
+            Err(e) => {
+                tracing::error!("清理过期项目失败: {e}");
+                continue;
+            }
+        };
+        for user_id in users {
+            if let Err(e) = purge_expired_for(&state, user_id).await {
+                tracing::error!("清理过期项目失败（{}）: {e}", user_id.map(|id| id.to_string()).unwrap_or_default());
+            }
+        }
+    }
+}
+
+async fn purge_expired_for(state: &ApiState, user_id: Option<Uuid>) -> AppResult<()> {
+    let mut service = match (&state.pool, user_id) {
+        (Some(pool), Some(uid)) => {
+            CeladonService::load_with_db(state.storage_dir.clone(), pool.clone(), uid).await?
+        }
+        _ => CeladonService::load(state.storage_dir.clone()).await?,
+    };
+    service.purge_expired().await?;
+    Ok(())
+}
+
 async fn health() -> Json<Value> {
     Json(json!({ "status": "ok" }))
 }
 
+/// 按 Accept-Language 确定本次请求的语言；登录用户的语言设置在 resolve_user_id 中覆盖
+async fn with_locale(request: axum::extract::Request, next: axum::middleware::Next) -> Response {
+    let locale = request
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Locale::from_accept_language)
+        .unwrap_or_else(i18n::default_locale);
+    i18n::scope(locale, next.run(request)).await
+}
+
 /// 从请求头解析 "Bearer <uuid>"，失败返回 None
 fn bearer_token_from_headers(headers: &axum::http::HeaderMap) -> Option<Uuid> {
     let v = headers.get(AUTHORIZATION)?.to_str().ok()?;
@@ -170,10 +327,13 @@ async fn resolve_user_id(
         Some(p) => p,
     };
     let token = bearer_token_from_headers(headers)
-        .ok_or_else(|| ApiError("需要登录".to_string()))?;
+        .ok_or_else(|| ApiError::new(i18n::t("login_required")))?;
     let user_id = auth::verify_token(pool, token)
         .await
-        .map_err(|e| ApiError(e.to_string()))?;
+        .map_err(|e| ApiError::new(e.to_string()))?;
+    if let Ok(Some(locale)) = auth::get_user_locale(pool, user_id).await {
+        i18n::set_current(locale);
+    }
     Ok(Some(user_id))
 }
 
@@ -197,13 +357,13 @@ async fn register(
     State(state): State<ApiState>,
     Json(req): Json<RegisterRequest>,
 ) -> ApiResult {
-    let pool = state.pool.as_ref().ok_or_else(|| ApiError("未启用用户系统".to_string()))?;
+    let pool = state.pool.as_ref().ok_or_else(|| ApiError::new(i18n::t("users_disabled")))?;
     let user_id = auth::register(pool, &req.email, &req.password)
         .await
-        .map_err(|e| ApiError(e.to_string()))?;
+        .map_err(|e| ApiError::new(e.to_string()))?;
     let (_, token) = auth::login(pool, &req.email, &req.password)
         .await
-        .map_err(|e| ApiError(e.to_string()))?;
+        .map_err(|e| ApiError::new(e.to_string()))?;
     Ok(Json(json!({
         "user_id": user_id.to_string(),
         "token": token.to_string(),
@@ -211,23 +371,218 @@ async fn register(
 }
 
 async fn login(State(state): State<ApiState>, Json(req): Json<LoginRequest>) -> ApiResult {
-    let pool = state.pool.as_ref().ok_or_else(|| ApiError("未启用用户系统".to_string()))?;
+    let pool = state.pool.as_ref().ok_or_else(|| ApiError::new(i18n::t("users_disabled")))?;
     let (user_id, token) = auth::login(pool, &req.email, &req.password)
         .await
-        .map_err(|e| ApiError(e.to_string()))?;
+        .map_err(|e| ApiError::new(e.to_string()))?;
     Ok(Json(json!({
         "user_id": user_id.to_string(),
         "token": token.to_string(),
     })))
 }
 
+#[derive(Deserialize)]
+struct ProjectListQuery {
+    status: Option<ProjectStatus>,
+}
+
 async fn list_projects(
     State(state): State<ApiState>,
     headers: axum::http::HeaderMap,
+    Query(query): Query<ProjectListQuery>,
 ) -> ApiResult {
     let user_id = resolve_user_id(&state, &headers).await?;
     let service = make_service(&state, user_id).await?;
-    let out = service.list_projects().map_err(ApiError::from)?;
+    let out = service.list_projects(query.status).map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn session_actions(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(session_id): Path<String>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service.next_actions(&session_id).await.map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn get_requirements(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(session_id): Path<String>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let service = make_service(&state, user_id).await?;
+    let out = service.requirements(&session_id).map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn put_requirements(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(session_id): Path<String>,
+    Json(req): Json<RequirementsSheet>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service
+        .update_requirements(&session_id, req)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+async fn search(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Query(query): Query<SearchQuery>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let limit = query.limit.unwrap_or(20).min(100);
+    let out = service.search(&query.q, limit).await.map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+#[derive(Deserialize)]
+struct UpdateProjectRequest {
+    status: ProjectStatus,
+}
+
+/// 归档 / 取消归档 / 恢复：`{"status": "ARCHIVED" | "ACTIVE"}`
+async fn update_project(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(project_id): Path<String>,
+    Json(req): Json<UpdateProjectRequest>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service
+        .set_project_status(&project_id, req.status)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn get_project_settings(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(project_id): Path<String>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let service = make_service(&state, user_id).await?;
+    let out = service.project_settings(&project_id).map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+/// `{"auto_generate_prd": true}`：澄清完成后自动生成 PRD；只修改请求中出现的字段
+async fn put_project_settings(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(project_id): Path<String>,
+    Json(req): Json<ProjectSettingsPatch>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service
+        .update_project_settings(&project_id, req)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+#[derive(Deserialize)]
+struct DeleteProjectQuery {
+    hard: Option<bool>,
+}
+
+/// 默认软删除；`?hard=true` 时彻底删除，不可恢复
+async fn delete_project(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(project_id): Path<String>,
+    Query(query): Query<DeleteProjectQuery>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = if query.hard.unwrap_or(false) {
+        service.purge_project(&project_id).await
+    } else {
+        service.set_project_status(&project_id, ProjectStatus::Deleted).await
+    }
+    .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn project_events(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(project_id): Path<String>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service.project_events(&project_id).await.map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    project_id: Option<String>,
+}
+
+async fn get_usage(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Query(query): Query<UsageQuery>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service
+        .usage_report(query.project_id.as_deref())
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn export_project(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(project_id): Path<String>,
+) -> Result<Response, ApiError> {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let (file_name, bytes) = service
+        .export_project(&project_id)
+        .await
+        .map_err(ApiError::from)?;
+    Ok((
+        [
+            (CONTENT_TYPE, "application/gzip".to_string()),
+            (CONTENT_DISPOSITION, format!("attachment; filename=\"{file_name}\"")),
+        ],
+        bytes,
+    )
+        .into_response())
+}
+
+/// 请求体为 `celadon export` 生成的 .tar.gz 原始字节
+async fn import_project(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    body: Bytes,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service.import_project(&body).await.map_err(ApiError::from)?;
     Ok(Json(out))
 }
 
@@ -256,6 +611,37 @@ async fn idea(
     Ok(Json(out))
 }
 
+/// 流式澄清：SSE 依次推送 started、若干 delta，最后 done 或 error
+async fn idea_stream(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<IdeaRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let service = make_service(&state, user_id).await?;
+    let receiver = service
+        .append_idea_stream(req.session_id, req.text)
+        .await
+        .map_err(ApiError::from)?;
+    let stream = CeladonService::stream_clarify_events(receiver);
+    Ok(Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::new()))
+}
+
+async fn start_stream(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<StartRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let service = make_service(&state, user_id).await?;
+    let receiver = service
+        .start_stream(req.idea, req.name)
+        .await
+        .map_err(ApiError::from)?;
+    let stream = CeladonService::stream_clarify_events(receiver);
+    Ok(Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::new()))
+}
+
 async fn generate_prd(
     State(state): State<ApiState>,
     headers: axum::http::HeaderMap,
@@ -395,20 +781,21 @@ async fn run_dev(
     Json(req): Json<DevRunRequest>,
 ) -> ApiResult {
     let user_id = resolve_user_id(&state, &headers).await?;
-    let mut service = make_service(&state, user_id).await?;
+    let service = make_service(&state, user_id).await?;
+    // 运行在后台记用量、查预算，不依赖客户端订阅事件流
     let (out, receiver_opt) = service
-        .run_dev(
-            &req.session_id,
+        .run_dev_stream(
+            req.session_id.clone(),
             req.instruction,
             req.dry_run.unwrap_or_default(),
         )
         .await
         .map_err(ApiError::from)?;
-        
-    if let Some(rx) = receiver_opt {
-        state.streams.lock().await.insert(req.session_id.clone(), rx);
+
+    if let Some(receiver) = receiver_opt {
+        state.streams.lock().await.insert(req.session_id, receiver);
     }
-    
+
     Ok(Json(out))
 }
 
@@ -418,12 +805,13 @@ async fn dev_stream(
     Query(params): Query<HashMap<String, String>>,
     Path(session_id): Path<String>,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    // 只校验登录状态，事件由 run_dev 启动的后台任务产生
     let _user_id = if let Some(token) = params.get("token") {
         if let Some(pool) = &state.pool {
-            let parsed_token = Uuid::parse_str(token).map_err(|_| ApiError("Invalid token format".to_string()))?;
+            let parsed_token = Uuid::parse_str(token).map_err(|_| ApiError::new(i18n::t("invalid_token")))?;
             let u_id = auth::verify_token(pool, parsed_token)
                 .await
-                .map_err(|e| ApiError(e.to_string()))?;
+                .map_err(|e| ApiError::new(e.to_string()))?;
             Some(u_id)
         } else {
             None
@@ -435,14 +823,42 @@ async fn dev_stream(
     let receiver = {
         let mut streams = state.streams.lock().await;
         streams.remove(&session_id).ok_or_else(|| {
-            ApiError(format!("No active stream for session {}", session_id))
+            ApiError::new(Message::new("no_active_stream").arg("id", &session_id).to_string())
         })?
     };
 
-    let stream = CeladonService::stream_dev_logs(receiver).map_err(ApiError::from)?;
+    let stream = CeladonService::stream_dev_logs(receiver);
     Ok(Sse::new(stream).keep_alive(axum::response::sse::KeepAlive::new()))
 }
 
+async fn start_tests(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<SessionRequest>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service
+        .start_tests(&req.session_id)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn record_test_result(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<TestResultRequest>,
+) -> ApiResult {
+    let user_id = resolve_user_id(&state, &headers).await?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service
+        .record_test_result(&req.session_id, req.passed, req.notes.unwrap_or_default())
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
 async fn run_deploy(
     State(state): State<ApiState>,
     headers: axum::http::HeaderMap,
@@ -466,8 +882,8 @@ async fn status(
     Path(session_id): Path<String>,
 ) -> ApiResult {
     let user_id = resolve_user_id(&state, &headers).await?;
-    let service = make_service(&state, user_id).await?;
-    let out = service.status(&session_id).map_err(ApiError::from)?;
+    let mut service = make_service(&state, user_id).await?;
+    let out = service.status(&session_id).await.map_err(ApiError::from)?;
     Ok(Json(out))
 }
 
@@ -475,36 +891,125 @@ async fn me(
     State(state): State<ApiState>,
     headers: axum::http::HeaderMap,
 ) -> ApiResult {
-    let pool = state.pool.as_ref().ok_or_else(|| ApiError("未启用用户系统".to_string()))?;
+    let pool = state.pool.as_ref().ok_or_else(|| ApiError::new(i18n::t("users_disabled")))?;
     let user_id = resolve_user_id(&state, &headers)
         .await?
-        .ok_or_else(|| ApiError("需要登录".to_string()))?;
+        .ok_or_else(|| ApiError::new(i18n::t("login_required")))?;
     let email = auth::get_user_email(pool, user_id)
         .await
-        .map_err(|e| ApiError(e.to_string()))?;
+        .map_err(|e| ApiError::new(e.to_string()))?;
     
     let admin_email = std::env::var("CELADON_ADMIN_EMAIL").ok();
     let is_admin = admin_email.map(|a| a == email).unwrap_or(false);
 
+    let preferred_locale = auth::get_user_locale(pool, user_id)
+        .await
+        .map_err(|e| ApiError::new(e.to_string()))?;
+
     Ok(Json(json!({ 
         "email": email,
-        "is_admin": is_admin
+        "is_admin": is_admin,
+        "locale": i18n::current(),
+        "preferred_locale": preferred_locale
     })))
 }
 
+#[derive(Deserialize)]
+struct UpdateLocaleRequest {
+    locale: Option<String>,
+}
+
+/// `{"locale": "en"}` 设置界面与提示词语言；`null` 表示跟随 Accept-Language
+async fn update_locale(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Json(req): Json<UpdateLocaleRequest>,
+) -> ApiResult {
+    let pool = state.pool.as_ref().ok_or_else(|| ApiError::new(i18n::t("users_disabled")))?;
+    let user_id = resolve_user_id(&state, &headers)
+        .await?
+        .ok_or_else(|| ApiError::new(i18n::t("login_required")))?;
+    let locale = match req.locale.as_deref() {
+        Some(tag) => Some(Locale::parse(tag).ok_or_else(|| {
+            ApiError::new(Message::new("unsupported_locale").arg("locale", tag).to_string())
+        })?),
+        None => None,
+    };
+    auth::set_user_locale(pool, user_id, locale)
+        .await
+        .map_err(|e| ApiError::new(e.to_string()))?;
+    if let Some(locale) = locale {
+        i18n::set_current(locale);
+    }
+    Ok(Json(json!({ "ok": true, "locale": locale })))
+}
+
 async fn logout(
     State(state): State<ApiState>,
     headers: axum::http::HeaderMap,
 ) -> ApiResult {
-    let pool = state.pool.as_ref().ok_or_else(|| ApiError("未启用用户系统".to_string()))?;
+    let pool = state.pool.as_ref().ok_or_else(|| ApiError::new(i18n::t("users_disabled")))?;
     let token = bearer_token_from_headers(&headers)
-        .ok_or_else(|| ApiError("需要登录".to_string()))?;
+        .ok_or_else(|| ApiError::new(i18n::t("login_required")))?;
     auth::logout(pool, token)
         .await
-        .map_err(|e| ApiError(e.to_string()))?;
+        .map_err(|e| ApiError::new(e.to_string()))?;
     Ok(Json(json!({ "ok": true })))
 }
 
+#[derive(Deserialize)]
+struct LlmKeyRequest {
+    provider: String,
+    model: String,
+    api_key: String,
+}
+
+/// 已登录用户的服务，用于只涉及本人设置的接口
+async fn user_service(state: &ApiState, headers: &axum::http::HeaderMap) -> Result<CeladonService, ApiError> {
+    let user_id = resolve_user_id(state, headers)
+        .await?
+        .ok_or_else(|| ApiError::new(i18n::t("login_required")))?;
+    make_service(state, Some(user_id)).await
+}
+
+fn parse_role(role: &str) -> Result<UsageRole, ApiError> {
+    serde_json::from_value(json!(role))
+        .map_err(|_| ApiError::new(Message::new("unknown_llm_role").arg("role", role).to_string()))
+}
+
+async fn list_llm_keys(State(state): State<ApiState>, headers: axum::http::HeaderMap) -> ApiResult {
+    let service = user_service(&state, &headers).await?;
+    let keys = service.llm_keys().await.map_err(ApiError::from)?;
+    Ok(Json(json!({ "keys": keys })))
+}
+
+/// `{"provider", "model", "api_key"}`，Key 加密后保存，响应中只有掩码
+async fn put_llm_key(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(role): Path<String>,
+    Json(req): Json<LlmKeyRequest>,
+) -> ApiResult {
+    let role = parse_role(&role)?;
+    let service = user_service(&state, &headers).await?;
+    let out = service
+        .set_llm_key(role, &req.provider, &req.model, &req.api_key)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn delete_llm_key(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(role): Path<String>,
+) -> ApiResult {
+    let role = parse_role(&role)?;
+    let service = user_service(&state, &headers).await?;
+    let out = service.delete_llm_key(role).await.map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
 async fn join_waiting_list(
     State(state): State<ApiState>,
     Json(req): Json<WaitingListRequest>,
@@ -518,19 +1023,19 @@ async fn check_admin(
     state: &ApiState,
     headers: &axum::http::HeaderMap,
 ) -> Result<CeladonService, ApiError> {
-    let pool = state.pool.as_ref().ok_or_else(|| ApiError("未启用用户系统".to_string()))?;
+    let pool = state.pool.as_ref().ok_or_else(|| ApiError::new(i18n::t("users_disabled")))?;
     let user_id = resolve_user_id(state, headers).await?
-        .ok_or_else(|| ApiError("需要登录".to_string()))?;
+        .ok_or_else(|| ApiError::new(i18n::t("login_required")))?;
     
     let email = auth::get_user_email(pool, user_id)
         .await
-        .map_err(|e| ApiError(e.to_string()))?;
+        .map_err(|e| ApiError::new(e.to_string()))?;
 
     let admin_email = std::env::var("CELADON_ADMIN_EMAIL")
-        .map_err(|_| ApiError("未配置管理员邮箱".to_string()))?;
+        .map_err(|_| ApiError::new(i18n::t("admin_not_configured")))?;
 
     if email != admin_email {
-         return Err(ApiError("权限不足".to_string()));
+         return Err(ApiError::new(i18n::t("forbidden")));
     }
 
     make_service(state, Some(user_id)).await
@@ -549,6 +1054,9 @@ async fn get_all_settings(
 struct UpdateSettingRequest {
     key: String,
     value: String,
+    /// 是否为密钥（加密保存、列表中只显示掩码）；不传则沿用原有标记
+    #[serde(default)]
+    secret: Option<bool>,
 }
 
 async fn update_system_setting(
@@ -557,10 +1065,130 @@ async fn update_system_setting(
     Json(req): Json<UpdateSettingRequest>,
 ) -> ApiResult {
     let mut service = check_admin(&state, &headers).await?;
-    service.update_setting(&req.key, &req.value).await.map_err(ApiError::from)?;
+    service
+        .update_setting(&req.key, &req.value, req.secret)
+        .await
+        .map_err(ApiError::from)?;
     Ok(Json(json!({ "ok": true })))
 }
 
+async fn rotate_settings_key(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let out = service.rotate_settings_key().await.map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn list_prompts(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let out = service.list_prompts().await.map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+#[derive(Deserialize)]
+struct PromptScopeQuery {
+    project_id: Option<String>,
+    locale: Option<Locale>,
+}
+
+async fn get_prompt(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(name): Path<String>,
+    Query(query): Query<PromptScopeQuery>,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let out = service
+        .prompt_history(&name, query.project_id.as_deref(), query.locale)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+#[derive(Deserialize)]
+struct SavePromptRequest {
+    content: String,
+    project_id: Option<String>,
+    locale: Option<Locale>,
+    note: Option<String>,
+}
+
+/// 保存为新版本；带 project_id 时只覆盖该项目
+async fn save_prompt(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(name): Path<String>,
+    Json(req): Json<SavePromptRequest>,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let out = service
+        .save_prompt(
+            &name,
+            req.project_id.as_deref(),
+            req.locale,
+            &req.content,
+            req.note.as_deref(),
+        )
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+#[derive(Deserialize)]
+struct PreviewPromptRequest {
+    content: Option<String>,
+    project_id: Option<String>,
+    locale: Option<Locale>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+}
+
+async fn preview_prompt(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(name): Path<String>,
+    Json(req): Json<PreviewPromptRequest>,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let out = service
+        .preview_prompt(
+            &name,
+            req.project_id.as_deref(),
+            req.locale,
+            req.content,
+            req.variables,
+        )
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+#[derive(Deserialize)]
+struct RollbackPromptRequest {
+    version: i32,
+    project_id: Option<String>,
+    locale: Option<Locale>,
+}
+
+async fn rollback_prompt(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(name): Path<String>,
+    Json(req): Json<RollbackPromptRequest>,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let out = service
+        .rollback_prompt(&name, req.project_id.as_deref(), req.locale, req.version)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
 async fn get_providers(
     State(state): State<ApiState>,
     headers: axum::http::HeaderMap,
@@ -579,3 +1207,51 @@ async fn get_providers_info(
     let providers_data = llm_providers::get_providers_data();
     Ok(Json(json!(providers_data)))
 }
+
+#[derive(Deserialize)]
+struct AttemptsQuery {
+    limit: Option<usize>,
+}
+
+async fn get_llm_attempts(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Query(query): Query<AttemptsQuery>,
+) -> ApiResult {
+    let _ = check_admin(&state, &headers).await?;
+    let attempts = clients::recent_attempts(query.limit.unwrap_or(50));
+    Ok(Json(json!({ "attempts": attempts })))
+}
+
+async fn get_admin_usage(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let out = service.admin_usage_report().await.map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn get_user_budgets(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let out = service.user_budgets().await.map_err(ApiError::from)?;
+    Ok(Json(out))
+}
+
+async fn put_user_budget(
+    State(state): State<ApiState>,
+    headers: axum::http::HeaderMap,
+    Path(user_id): Path<String>,
+    Json(budget): Json<Budget>,
+) -> ApiResult {
+    let service = check_admin(&state, &headers).await?;
+    let user_id = Uuid::parse_str(&user_id).map_err(|_| ApiError::new(i18n::t("user_not_found")))?;
+    let out = service
+        .set_user_budget(user_id, budget)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(Json(out))
+}
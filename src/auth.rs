@@ -2,18 +2,14 @@
 
 use crate::common::AppResult;
 use crate::db::Pool;
+use crate::i18n::{Locale, Message};
 use bcrypt::{hash, verify, DEFAULT_COST};
 use sqlx::Row;
-use std::io;
 use uuid::Uuid;
 
-fn err_msg(s: &str) -> Box<dyn std::error::Error + Send + Sync> {
-    Box::new(io::Error::other(s))
-}
-
 /// 注册：写入 users，返回 user_id
 pub async fn register(pool: &Pool, email: &str, password: &str) -> AppResult<Uuid> {
-    let password_hash = hash(password, DEFAULT_COST).map_err(|e| format!("密码哈希失败: {e}"))?;
+    let password_hash = hash(password, DEFAULT_COST).map_err(|e| Message::new("password_hash_failed").arg("error", e))?;
     let id = Uuid::new_v4();
     let email = email.trim().to_lowercase();
     let row = sqlx::query("INSERT INTO users (id, email, password_hash) VALUES ($1, $2, $3) RETURNING id")
@@ -25,9 +21,9 @@ pub async fn register(pool: &Pool, email: &str, password: &str) -> AppResult<Uui
         .map_err(|e| {
             let msg = e.to_string();
             if msg.contains("unique") || msg.contains("duplicate") {
-                "邮箱已被注册".to_string()
+                Message::new("email_taken").to_string()
             } else {
-                format!("注册失败: {e}")
+                Message::new("register_failed").arg("error", e).to_string()
             }
         })?;
     Ok(row.get::<Uuid, _>("id"))
@@ -42,12 +38,12 @@ pub async fn login(pool: &Pool, email: &str, password: &str) -> AppResult<(Uuid,
     .bind(&email)
     .fetch_optional(pool)
     .await
-    .map_err(|e| format!("登录查询失败: {e}"))?
-    .ok_or_else(|| err_msg("邮箱或密码错误"))?;
+    .map_err(|e| Message::new("login_query_failed").arg("error", e))?
+    .ok_or_else(|| Message::new("invalid_credentials"))?;
     let (user_id, password_hash) = row;
     let ok = verify(password, &password_hash).unwrap_or(false);
     if !ok {
-        return Err("邮箱或密码错误".into());
+        return Err(Message::new("invalid_credentials").into());
     }
     let token = Uuid::new_v4();
     sqlx::query(
@@ -57,7 +53,7 @@ pub async fn login(pool: &Pool, email: &str, password: &str) -> AppResult<(Uuid,
     .bind(user_id)
     .execute(pool)
     .await
-    .map_err(|e| format!("写入 token 失败: {e}"))?;
+    .map_err(|e| Message::new("token_write_failed").arg("error", e))?;
     Ok((user_id, token))
 }
 
@@ -69,8 +65,8 @@ pub async fn verify_token(pool: &Pool, token: Uuid) -> AppResult<Uuid> {
     .bind(token)
     .fetch_optional(pool)
     .await
-    .map_err(|e| format!("token 校验失败: {e}"))?
-    .ok_or_else(|| err_msg("无效或过期的登录状态，请重新登录"))?;
+    .map_err(|e| Message::new("token_check_failed").arg("error", e))?
+    .ok_or_else(|| Message::new("token_expired"))?;
     Ok(row.0)
 }
 
@@ -80,7 +76,7 @@ pub async fn logout(pool: &Pool, token: Uuid) -> AppResult<()> {
         .bind(token)
         .execute(pool)
         .await
-        .map_err(|e| format!("登出失败: {e}"))?;
+        .map_err(|e| Message::new("logout_failed").arg("error", e))?;
     Ok(())
 }
 
@@ -90,7 +86,27 @@ pub async fn get_user_email(pool: &Pool, user_id: Uuid) -> AppResult<String> {
         .bind(user_id)
         .fetch_optional(pool)
         .await
-        .map_err(|e| format!("查询用户失败: {e}"))?
-        .ok_or_else(|| err_msg("用户不存在"))?;
+        .map_err(|e| Message::new("user_query_failed").arg("error", e))?
+        .ok_or_else(|| Message::new("user_not_found"))?;
     Ok(row.0)
 }
+
+/// 用户选择的语言；未设置时为 None，按请求的 Accept-Language 决定
+pub async fn get_user_locale(pool: &Pool, user_id: Uuid) -> AppResult<Option<Locale>> {
+    let row = sqlx::query_as::<_, (Option<String>,)>("SELECT locale FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| Message::new("user_query_failed").arg("error", e))?;
+    Ok(row.and_then(|r| r.0).and_then(|tag| Locale::parse(&tag)))
+}
+
+pub async fn set_user_locale(pool: &Pool, user_id: Uuid, locale: Option<Locale>) -> AppResult<()> {
+    sqlx::query("UPDATE users SET locale = $2 WHERE id = $1")
+        .bind(user_id)
+        .bind(locale.map(Locale::code))
+        .execute(pool)
+        .await
+        .map_err(|e| Message::new("locale_update_failed").arg("error", e))?;
+    Ok(())
+}
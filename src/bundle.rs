@@ -0,0 +1,247 @@
+//! 项目导出/导入包：一个 .tar.gz，内含 manifest.json、PRD markdown 与 workspace 目录
+
+use crate::common::AppResult;
+use crate::i18n::Message;
+use crate::models::{
+    ConversationTurn, DeploymentRun, IdeaEvent, Iteration, PrdVersion, Project, Session, TaskRun,
+};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tar::{Archive, Builder, EntryType, Header};
+
+const MANIFEST: &str = "manifest.json";
+const BUNDLE_FORMAT: &str = "celadon-project-bundle";
+const BUNDLE_VERSION: u32 = 1;
+/// 导入包最多包含的条目数与解压后的总字节数，超出即拒绝（防止压缩炸弹）
+const MAX_ENTRIES: usize = 10_000;
+const MAX_UNPACKED_BYTES: u64 = 512 * 1024 * 1024;
+
+/// 导出与检索时跳过的目录（与 dev 文件树一致）
+pub(crate) const SKIPPED_DIRS: &[&str] = &["node_modules", "target", ".git"];
+
+/// manifest.json：单个项目的全部结构化数据
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProjectBundle {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: String,
+    pub project: Project,
+    pub sessions: Vec<Session>,
+    pub conversation_turns: Vec<ConversationTurn>,
+    pub idea_events: Vec<IdeaEvent>,
+    pub prd_versions: Vec<PrdVersion>,
+    pub task_runs: Vec<TaskRun>,
+    pub deployment_runs: Vec<DeploymentRun>,
+    #[serde(default)]
+    pub iterations: Vec<Iteration>,
+}
+
+impl ProjectBundle {
+    pub fn new(project: Project, exported_at: String) -> Self {
+        Self {
+            format: BUNDLE_FORMAT.to_string(),
+            version: BUNDLE_VERSION,
+            exported_at,
+            project,
+            sessions: Vec::new(),
+            conversation_turns: Vec::new(),
+            idea_events: Vec::new(),
+            prd_versions: Vec::new(),
+            task_runs: Vec::new(),
+            deployment_runs: Vec::new(),
+            iterations: Vec::new(),
+        }
+    }
+}
+
+/// 打包：manifest + `prd/` 下的 markdown + `workspace/` 下的文件
+pub fn write_archive(bundle: &ProjectBundle, prd_dir: &Path, workspace: &Path) -> AppResult<Vec<u8>> {
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut builder = Builder::new(encoder);
+
+    let manifest = serde_json::to_vec_pretty(bundle)?;
+    let mut header = Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_entry_type(EntryType::Regular);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, manifest.as_slice())?;
+
+    if prd_dir.is_dir() {
+        append_tree(&mut builder, prd_dir, Path::new("prd"))?;
+    }
+    if workspace.is_dir() {
+        append_tree(&mut builder, workspace, Path::new("workspace"))?;
+    }
+
+    let encoder = builder.into_inner()?;
+    Ok(encoder.finish()?)
+}
+
+fn append_tree<W: std::io::Write>(builder: &mut Builder<W>, dir: &Path, prefix: &Path) -> AppResult<()> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.filter_map(|e| e.ok()).collect();
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let name = entry.file_name();
+        let path = entry.path();
+        let target = prefix.join(&name);
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if SKIPPED_DIRS.iter().any(|s| name == *s) {
+                continue;
+            }
+            append_tree(builder, &path, &target)?;
+        } else if file_type.is_file() {
+            builder.append_path_with_name(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// 解包结果：manifest、PRD 文件（相对 prd/ 的路径 → 内容）以及 workspace 文件
+pub struct UnpackedBundle {
+    pub manifest: ProjectBundle,
+    pub prd_files: HashMap<PathBuf, Vec<u8>>,
+    pub workspace_files: HashMap<PathBuf, Vec<u8>>,
+}
+
+pub fn read_archive(bytes: &[u8]) -> AppResult<UnpackedBundle> {
+    read_archive_within(bytes, MAX_ENTRIES, MAX_UNPACKED_BYTES)
+}
+
+fn read_archive_within(bytes: &[u8], max_entries: usize, max_bytes: u64) -> AppResult<UnpackedBundle> {
+    let mut archive = Archive::new(GzDecoder::new(bytes));
+    let mut manifest: Option<ProjectBundle> = None;
+    let mut prd_files = HashMap::new();
+    let mut workspace_files = HashMap::new();
+    let mut unpacked: u64 = 0;
+
+    for (index, entry) in archive.entries()?.enumerate() {
+        if index >= max_entries {
+            return Err(Message::new("bundle_too_many_entries").arg("max", max_entries).into());
+        }
+        let mut entry = entry?;
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+        let path = safe_relative(&entry.path()?)
+            .ok_or_else(|| {
+                let path = entry.path().map(|p| p.display().to_string()).unwrap_or_default();
+                Message::new("bundle_unsafe_path").arg("path", path)
+            })?;
+        // 不信任头部声明的大小，按实际读出的字节计数，多读一个字节用于判断是否超限
+        let mut content = Vec::new();
+        (&mut entry).take(max_bytes - unpacked + 1).read_to_end(&mut content)?;
+        unpacked += content.len() as u64;
+        if unpacked > max_bytes {
+            return Err(Message::new("bundle_too_large").arg("max", max_bytes).into());
+        }
+
+        if path == Path::new(MANIFEST) {
+            manifest = Some(serde_json::from_slice(&content).map_err(|e| Message::new("bundle_invalid_manifest").arg("error", e))?);
+        } else if let Ok(rel) = path.strip_prefix("prd") {
+            prd_files.insert(rel.to_path_buf(), content);
+        } else if let Ok(rel) = path.strip_prefix("workspace") {
+            workspace_files.insert(rel.to_path_buf(), content);
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| Message::new("bundle_missing_manifest"))?;
+    if manifest.format != BUNDLE_FORMAT {
+        return Err(Message::new("bundle_wrong_format").arg("format", &manifest.format).into());
+    }
+    if manifest.version > BUNDLE_VERSION {
+        return Err(Message::new("bundle_version_unsupported")
+            .arg("version", manifest.version)
+            .arg("supported", BUNDLE_VERSION)
+            .into());
+    }
+    Ok(UnpackedBundle {
+        manifest,
+        prd_files,
+        workspace_files,
+    })
+}
+
+/// 只接受由普通路径段组成的相对路径，拒绝 `..`、绝对路径等
+fn safe_relative(path: &Path) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!out.as_os_str().is_empty()).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::i18n::Locale;
+
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (name, content) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_entry_type(EntryType::Regular);
+            header.set_cksum();
+            builder.append_data(&mut header, name, *content).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn error(result: AppResult<UnpackedBundle>) -> String {
+        match result {
+            Ok(_) => panic!("archive should be rejected"),
+            // 固定按英文渲染，不受 CELADON_LOCALE 影响
+            Err(e) => e.downcast_ref::<Message>().expect("localized error").render(Locale::En),
+        }
+    }
+
+    #[test]
+    fn rejects_too_many_entries() {
+        let bytes = archive(&[("workspace/a", b"a"), ("workspace/b", b"b"), ("workspace/c", b"c")]);
+        assert!(error(read_archive_within(&bytes, 2, 1024)).contains("more than 2 entries"));
+    }
+
+    #[test]
+    fn rejects_archives_that_unpack_past_the_byte_cap() {
+        // 高度可压缩的内容：压缩后很小，解压后超出上限
+        let zeros = vec![0u8; 4096];
+        let bytes = archive(&[("workspace/a", &zeros), ("workspace/b", &zeros)]);
+        assert!(bytes.len() < 1024);
+        assert!(error(read_archive_within(&bytes, 10, 6000)).contains("more than 6000 bytes"));
+    }
+
+    #[test]
+    fn accepts_archives_within_the_caps() {
+        let project = Project {
+            id: "p1".to_string(),
+            name: "demo".to_string(),
+            status: crate::models::ProjectStatus::Active,
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+            updated_at: "2024-01-01T00:00:00Z".to_string(),
+            deleted_at: None,
+            settings: Default::default(),
+        };
+        let manifest = serde_json::to_vec(&ProjectBundle::new(project, "2024-01-02T00:00:00Z".to_string())).unwrap();
+        let bytes = archive(&[(MANIFEST, &manifest), ("prd/v1.md", b"# PRD"), ("workspace/a", b"hello")]);
+
+        // 条目数与字节数恰好等于上限时仍可读取
+        let cap = (manifest.len() + "# PRD".len() + "hello".len()) as u64;
+        let unpacked = read_archive_within(&bytes, 3, cap).unwrap();
+        assert_eq!(unpacked.manifest.project.id, "p1");
+        assert_eq!(unpacked.prd_files[Path::new("v1.md")], b"# PRD");
+        assert_eq!(unpacked.workspace_files[Path::new("a")], b"hello");
+    }
+}
@@ -0,0 +1,188 @@
+//! 模型调用的录制与回放，用真实对话做提示词与流程改动的回归样例。
+//!
+//! CELADON_CASSETTE=record 时照常调用模型，并把每次请求与回复（含 Zene 开发事件）按顺序
+//! 写入 CELADON_CASSETTE_PATH（默认 `.celadon/cassettes/default.json`，每次启动重新录制）；
+//! CELADON_CASSETTE=replay 时不访问任何服务商，按请求从文件中取回复。
+//! 回放只取内容完全相同且未用过的同类记录，对不上（如提示词或流程已修改）时直接返回错误，
+//! 不会用其他请求的回复顶替。
+
+use crate::common::AppResult;
+use crate::i18n::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    Record,
+    Replay,
+}
+
+/// 调用类型，回放时只在同类记录中查找
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CallKind {
+    Chat,
+    Stream,
+    Dev,
+}
+
+impl CallKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            CallKind::Chat => "chat",
+            CallKind::Stream => "stream",
+            CallKind::Dev => "dev",
+        }
+    }
+}
+
+/// 一次模型回复
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedReply {
+    pub content: String,
+    pub model: String,
+    /// 服务商返回的 [prompt_tokens, completion_tokens]；为空时回放同样按文本估算
+    #[serde(default)]
+    pub usage: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    kind: CallKind,
+    request: Value,
+    response: Value,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Tape {
+    interactions: Vec<Interaction>,
+    #[serde(skip)]
+    used: Vec<bool>,
+}
+
+pub struct Cassette {
+    mode: CassetteMode,
+    path: PathBuf,
+    tape: Mutex<Tape>,
+}
+
+static ACTIVE: OnceLock<Option<Cassette>> = OnceLock::new();
+
+/// 当前进程的录制文件；未设置 CELADON_CASSETTE 时为 None
+pub fn active() -> Option<&'static Cassette> {
+    ACTIVE.get_or_init(Cassette::from_env).as_ref()
+}
+
+/// 是否处于回放模式（此时无需配置任何 API Key）
+pub fn replaying() -> bool {
+    active().is_some_and(|c| c.mode == CassetteMode::Replay)
+}
+
+impl Cassette {
+    fn from_env() -> Option<Self> {
+        let mode = match std::env::var("CELADON_CASSETTE").ok()?.trim() {
+            "record" => CassetteMode::Record,
+            "replay" => CassetteMode::Replay,
+            "" | "off" => return None,
+            other => {
+                tracing::warn!("CELADON_CASSETTE 只能是 record 或 replay，已忽略: {other}");
+                return None;
+            }
+        };
+        let path = std::env::var("CELADON_CASSETTE_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| crate::utils::storage_dir().join("cassettes/default.json"));
+        Some(Self::open(mode, path))
+    }
+
+    /// 录制时从空白开始；回放时读取文件，读取失败按空文件处理（回放时报错）
+    fn open(mode: CassetteMode, path: PathBuf) -> Self {
+        let mut tape = match mode {
+            CassetteMode::Record => Tape::default(),
+            CassetteMode::Replay => match fs::read_to_string(&path) {
+                Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                    tracing::warn!("录制文件 {} 格式无效: {e}", path.display());
+                    Tape::default()
+                }),
+                Err(e) => {
+                    tracing::warn!("读取录制文件 {} 失败: {e}", path.display());
+                    Tape::default()
+                }
+            },
+        };
+        tape.used = vec![false; tape.interactions.len()];
+        Self { mode, path, tape: Mutex::new(tape) }
+    }
+
+    pub fn mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// 回放一次调用的回复
+    pub fn replay_reply(&self, kind: CallKind, request: &Value) -> AppResult<RecordedReply> {
+        Ok(serde_json::from_value(self.take(kind, request)?)?)
+    }
+
+    /// 回放一次开发运行的全部事件
+    pub fn replay_events(&self, request: &Value) -> AppResult<Vec<Value>> {
+        Ok(serde_json::from_value(self.take(CallKind::Dev, request)?)?)
+    }
+
+    pub fn record_reply(&self, kind: CallKind, request: Value, reply: &RecordedReply) {
+        self.record(kind, request, serde_json::to_value(reply).unwrap_or_default());
+    }
+
+    pub fn record_events(&self, request: Value, events: Vec<Value>) {
+        self.record(CallKind::Dev, request, Value::Array(events));
+    }
+
+    fn take(&self, kind: CallKind, request: &Value) -> AppResult<Value> {
+        let mut tape = self.tape.lock().unwrap_or_else(|e| e.into_inner());
+        let unused: Vec<usize> = (0..tape.interactions.len())
+            .filter(|i| !tape.used[*i] && tape.interactions[*i].kind == kind)
+            .collect();
+        if unused.is_empty() {
+            return Err(Message::new("cassette_exhausted")
+                .arg("kind", kind.as_str())
+                .arg("path", self.path.display())
+                .into());
+        }
+        let index = unused
+            .iter()
+            .copied()
+            .find(|i| tape.interactions[*i].request == *request)
+            .ok_or_else(|| {
+                Message::new("cassette_mismatch")
+                    .arg("kind", kind.as_str())
+                    .arg("path", self.path.display())
+                    .arg("remaining", unused.len())
+            })?;
+        tape.used[index] = true;
+        Ok(tape.interactions[index].response.clone())
+    }
+
+    /// 追加一条记录并立即写回文件，进程中途退出也不丢失已录内容
+    fn record(&self, kind: CallKind, request: Value, response: Value) {
+        if self.mode != CassetteMode::Record {
+            return;
+        }
+        let mut tape = self.tape.lock().unwrap_or_else(|e| e.into_inner());
+        tape.interactions.push(Interaction { kind, request, response });
+        if let Err(e) = save(&tape, &self.path) {
+            tracing::error!("写入录制文件 {} 失败: {e}", self.path.display());
+        }
+    }
+}
+
+fn save(tape: &Tape, path: &Path) -> AppResult<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(tape)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
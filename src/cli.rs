@@ -1,4 +1,6 @@
 use clap::{Parser, Subcommand};
+use crate::models::ProjectStatus;
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(
@@ -32,6 +34,11 @@ pub enum Commands {
         #[command(subcommand)]
         command: DevCommand,
     },
+    /// 进入测试阶段并提交测试结论；部署前本轮测试须已通过
+    Test {
+        #[command(subcommand)]
+        command: TestCommand,
+    },
     Deploy {
         #[arg(long)]
         session_id: String,
@@ -42,12 +49,81 @@ pub enum Commands {
         #[arg(long)]
         session_id: String,
     },
+    Project {
+        #[command(subcommand)]
+        command: ProjectCommand,
+    },
+    /// 在对话、PRD 与 workspace 文件中全文检索
+    Search {
+        query: String,
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// 把项目导出为 .tar.gz 包
+    Export {
+        #[arg(long)]
+        project: String,
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// 从 .tar.gz 包导入项目（重新分配所有 ID）
+    Import {
+        bundle: PathBuf,
+    },
     Serve {
         #[arg(long, default_value_t = 3000)]
         port: u16,
     },
 }
 
+#[derive(Subcommand)]
+pub enum ProjectCommand {
+    List {
+        /// active | archived | deleted
+        #[arg(long, value_parser = parse_project_status)]
+        status: Option<ProjectStatus>,
+    },
+    Archive {
+        #[arg(long)]
+        project: String,
+    },
+    Unarchive {
+        #[arg(long)]
+        project: String,
+    },
+    /// 软删除，恢复期内可用 restore 还原；--hard 彻底删除
+    Delete {
+        #[arg(long)]
+        project: String,
+        #[arg(long, default_value_t = false)]
+        hard: bool,
+    },
+    Restore {
+        #[arg(long)]
+        project: String,
+    },
+    /// 彻底删除超过恢复期的软删除项目
+    PurgeExpired,
+    /// 查看项目设置；带参数时修改
+    Settings {
+        #[arg(long)]
+        project: String,
+        #[arg(long)]
+        auto_generate_prd: Option<bool>,
+        /// 每月 token 上限，0 表示不限
+        #[arg(long)]
+        max_tokens: Option<u64>,
+        /// 每月费用上限（美元），0 表示不限
+        #[arg(long)]
+        max_cost: Option<f64>,
+    },
+}
+
+fn parse_project_status(value: &str) -> Result<ProjectStatus, String> {
+    serde_json::from_value(serde_json::Value::String(value.to_uppercase()))
+        .map_err(|_| format!("unknown project status: {value}"))
+}
+
 #[derive(Subcommand)]
 pub enum PrdCommand {
     Generate {
@@ -67,3 +143,20 @@ pub enum DevCommand {
         dry_run: bool,
     },
 }
+
+#[derive(Subcommand)]
+pub enum TestCommand {
+    Start {
+        #[arg(long)]
+        session_id: String,
+    },
+    /// 未通过时回到开发阶段
+    Result {
+        #[arg(long)]
+        session_id: String,
+        #[arg(long, action = clap::ArgAction::Set)]
+        passed: bool,
+        #[arg(long, default_value = "")]
+        notes: String,
+    },
+}
@@ -1,9 +1,25 @@
+use crate::cassette::{self, CallKind, CassetteMode, RecordedReply};
 use crate::common::AppResult;
-use llm_connector::types::{ChatRequest, Message};
+use crate::context;
+use crate::crypto::{self, SecretKey};
+use crate::i18n;
+use crate::mock::{self, MockLlm};
+use crate::models::{RequirementsSheet, UsageRole};
+use crate::usage::{self, CallUsage, PriceTable};
+use llm_connector::error::LlmConnectorError;
+use llm_connector::types::{ChatRequest, Message, ResponseFormat, Usage};
 use llm_connector::LlmClient;
 use serde_json::{Value, json};
-use std::path::PathBuf;
-use std::sync::Arc;
+use futures::{Stream, StreamExt};
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 use zene::config::AgentConfig;
 use zene::engine::session::store::FileSessionStore;
@@ -11,20 +27,99 @@ use zene::RunRequest;
 use zene::ZeneEngine;
 use zene::AgentEvent;
 use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+/// 开发事件：Zene 引擎的事件，或按 Zene 事件形状构造的 JSON（模拟模型、录制回放）
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum DevEvent {
+    Agent(AgentEvent),
+    Json(Value),
+}
+
+impl DevEvent {
+    /// 按 Zene 的 AgentEvent 解析；模拟模型与录制回放的事件形状与之相同
+    pub fn agent(&self) -> Option<Cow<'_, AgentEvent>> {
+        match self {
+            DevEvent::Agent(event) => Some(Cow::Borrowed(event)),
+            DevEvent::Json(value) => AgentEvent::deserialize(value).ok().map(Cow::Owned),
+        }
+    }
+
+    /// Zene 开始新一步（规划、执行某个任务、反思）时发出的事件，该步的模型调用随后开始
+    pub fn starts_step(&self) -> bool {
+        self.agent().is_some_and(|event| {
+            matches!(
+                *event,
+                AgentEvent::PlanningStarted | AgentEvent::TaskStarted { .. } | AgentEvent::ReflectionStarted
+            )
+        })
+    }
+}
+
+/// 一次进行中的开发运行：事件通道与产生事件的任务
+pub struct DevRun {
+    pub events: mpsc::UnboundedReceiver<DevEvent>,
+    task: AbortHandle,
+}
+
+impl DevRun {
+    pub fn new(events: mpsc::UnboundedReceiver<DevEvent>, task: AbortHandle) -> Self {
+        Self { events, task }
+    }
+
+    /// 中止运行：Zene 任务在下一个等待点结束，进行中的模型请求随之取消
+    pub fn stop(&mut self) {
+        self.task.abort();
+        self.events.close();
+    }
+}
 
 pub struct ZeneClient {
     engine: Arc<tokio::sync::Mutex<Option<ZeneEngine>>>,
+    /// planner 为 mock 时不启动引擎，开发任务由 mock::run_dev 模拟
+    mock: bool,
 }
 
 impl ZeneClient {
     pub fn new() -> Self {
-        Self { engine: Arc::new(tokio::sync::Mutex::new(None)) }
+        Self { engine: Arc::new(tokio::sync::Mutex::new(None)), mock: false }
     }
 
-    pub async fn init(&mut self, config: AgentConfig) -> AppResult<()> {
+    /// Zene 会话文件目录（~/.zene/sessions）
+    pub fn session_dir() -> PathBuf {
         let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        let storage_dir = PathBuf::from(&home).join(".zene/sessions");
-        let store = Arc::new(FileSessionStore::new(storage_dir)?);
+        PathBuf::from(&home).join(".zene/sessions")
+    }
+
+    /// 删除某个会话在 Zene 中留下的文件（文件名以 session_id 开头）
+    pub fn remove_session_files(session_id: &str) -> AppResult<()> {
+        let entries = match std::fs::read_dir(Self::session_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            if !entry.file_name().to_string_lossy().starts_with(session_id) {
+                continue;
+            }
+            let path = entry.path();
+            if path.is_dir() {
+                std::fs::remove_dir_all(path)?;
+            } else {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn init(&mut self, config: AgentConfig) -> AppResult<()> {
+        self.mock = config.planner.provider == mock::MOCK_MODEL;
+        if self.mock {
+            *self.engine.lock().await = None;
+            return Ok(());
+        }
+        let store = Arc::new(FileSessionStore::new(Self::session_dir())?);
         let engine = ZeneEngine::new(config, store).await?;
         *self.engine.lock().await = Some(engine);
         Ok(())
@@ -43,33 +138,101 @@ impl ZeneClient {
         })
     }
 
+    /// 启动一次开发运行。回放模式下直接发出录制的事件；录制模式下运行结束（或被中止）
+    /// 时把收到的事件写入录制文件
     pub async fn run_agent_stream(
         &self,
         session_id: &str,
         instruction: &str,
+        workspace: &Path,
+        config_override: Option<AgentConfig>,
+    ) -> AppResult<DevRun> {
+        // 指令中的 workspace 绝对路径随目录与项目 ID 变化，匹配时替换为占位符
+        let key = json!({
+            "instruction": instruction.replace(&workspace.display().to_string(), "<workspace>")
+        });
+        let Some(cassette) = cassette::active() else {
+            return self.start_run(session_id, instruction, workspace, config_override).await;
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        if cassette.mode() == CassetteMode::Replay {
+            let events = cassette.replay_events(&key)?;
+            let task = tokio::spawn(async move {
+                for event in events {
+                    if sender.send(DevEvent::Json(event)).is_err() {
+                        break;
+                    }
+                    tokio::task::yield_now().await;
+                }
+            });
+            return Ok(DevRun::new(receiver, task.abort_handle()));
+        }
+        let mut run = self.start_run(session_id, instruction, workspace, config_override).await?;
+        let task = run.task.clone();
+        tokio::spawn(async move {
+            let mut recorded = Vec::new();
+            while let Some(event) = run.events.recv().await {
+                recorded.push(serde_json::to_value(&event).unwrap_or_default());
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+            cassette.record_events(key, recorded);
+        });
+        Ok(DevRun::new(receiver, task))
+    }
+
+    async fn start_run(
+        &self,
+        session_id: &str,
+        instruction: &str,
+        workspace: &Path,
         config_override: Option<AgentConfig>,
-    ) -> AppResult<mpsc::UnboundedReceiver<AgentEvent>> {
+    ) -> AppResult<DevRun> {
+        if self.mock && config_override.is_none() {
+            return Ok(mock::run_dev(instruction, workspace));
+        }
         let req = RunRequest {
             prompt: instruction.to_string(),
             session_id: session_id.to_string(),
             env_vars: None,
         };
 
-        let mut engine_guard = self.engine.lock().await;
-
-        if let (Some(engine), None) = (&*engine_guard, config_override.as_ref()) {
-            Ok(engine.run_stream(req).await?)
-        } else {
-            // Fallback or override, we update the cached engine
-            let config = config_override.unwrap_or_else(|| AgentConfig::from_env().unwrap());
-            let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-            let storage_dir = PathBuf::from(&home).join(".zene/sessions");
-            let store = Arc::new(FileSessionStore::new(storage_dir)?);
-            let new_engine = ZeneEngine::new(config, store).await?;
-            let stream = new_engine.run_stream(req).await?;
-            *engine_guard = Some(new_engine);
-            Ok(stream)
-        }
+        let engine = {
+            let mut engine_guard = self.engine.lock().await;
+            match (&*engine_guard, config_override) {
+                (Some(engine), None) => engine.clone(),
+                (_, config_override) => {
+                    // Fallback or override, we update the cached engine
+                    let config = config_override.unwrap_or_else(|| AgentConfig::from_env().unwrap());
+                    let store = Arc::new(FileSessionStore::new(Self::session_dir())?);
+                    let new_engine = ZeneEngine::new(config, store).await?;
+                    *engine_guard = Some(new_engine.clone());
+                    new_engine
+                }
+            }
+        };
+        // 与 ZeneEngine::run_stream 相同，但自己持有任务句柄，预算超限时可以中止
+        let (agent_sender, mut agent_events) = mpsc::unbounded_channel();
+        let agent_task = tokio::spawn(async move {
+            let last = match engine.run_with_events(req, Some(agent_sender.clone())).await {
+                Ok(result) => AgentEvent::Finished(result.output),
+                Err(e) => AgentEvent::Error {
+                    code: "RUN_FAILED".to_string(),
+                    message: e.to_string(),
+                },
+            };
+            let _ = agent_sender.send(last);
+        });
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(event) = agent_events.recv().await {
+                if sender.send(DevEvent::Agent(event)).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(DevRun::new(receiver, agent_task.abort_handle()))
     }
 }
 
@@ -79,6 +242,12 @@ pub(crate) const CLARIFY_SYSTEM: &str = r#"你是 Celadon 的需求澄清助手
 3. 保持简洁、专业，每次回复聚焦 1-2 个问题或要点
 4. 若用户表示已准备好或信息足够，回复「需求已澄清，可以生成 PRD 了」"#;
 
+pub(crate) const CLARIFY_SYSTEM_EN: &str = r#"You are Celadon's requirements clarification assistant. The user describes a project idea; your job is to:
+1. Clarify the requirements over several rounds: scope (MVP or full version), constraints (tech stack, budget, launch date) and success criteria (acceptance conditions)
+2. Once enough is known, briefly summarize the agreed requirements and suggest moving on to PRD generation
+3. Stay concise and professional, focusing each reply on one or two questions or points
+4. If the user says they are ready or the information is sufficient, reply "Requirements are clear, ready to generate the PRD""#;
+
 pub(crate) const PRD_GEN_SYSTEM: &str = r#"根据对话内容，提炼并生成一份结构化的 PRD（产品需求文档），包含：
 1. 背景与目标
 2. 用户故事与使用流程
@@ -88,9 +257,384 @@ pub(crate) const PRD_GEN_SYSTEM: &str = r#"根据对话内容，提炼并生成
 
 使用 Markdown 格式，简洁清晰。"#;
 
-pub struct LlmGateway {
-    // Legacy support for basic LLM features in Celadon (like clarify)
+pub(crate) const REQUIREMENTS_SYSTEM: &str = r#"你是需求整理助手。根据需求澄清对话和当前的需求清单，输出更新后的完整需求清单。
+只输出一个 JSON 对象，字段如下：
+{
+  "scope": "MVP" | "FULL" | null,
+  "tech_stack": ["..."],
+  "budget": "..." | null,
+  "deadline": "..." | null,
+  "success_criteria": ["..."],
+  "open_questions": ["..."]
+}
+规则：
+1. 只记录对话中明确提到或用户确认过的信息，不要臆测
+2. 当前清单中已有的内容除非对话中被推翻，否则保留
+3. open_questions 列出还需要向用户确认的关键问题，已回答的问题要移除"#;
+
+pub(crate) const SUMMARY_SYSTEM: &str = r#"你负责压缩一段需求澄清对话。根据已有摘要和新增的对话，输出更新后的摘要：
+1. 保留所有已确认的需求、约束、决定和用户偏好，以及仍未解决的问题
+2. 去掉寒暄和重复内容，不要编造对话中没有的信息
+3. 使用简洁的要点列表，不超过 500 字"#;
+
+pub(crate) const PRD_GEN_SYSTEM_EN: &str = r#"From the conversation, distill a structured PRD (product requirements document) containing:
+1. Background and goals
+2. User stories and flows
+3. Feature list (Must/Should/Could)
+4. Non-functional requirements (performance, security, reliability)
+5. Acceptance criteria and milestones
+
+Use Markdown and keep it concise and clear."#;
+
+pub(crate) const REQUIREMENTS_SYSTEM_EN: &str = r#"You organize requirements. Given a clarification conversation and the current requirements sheet, output the complete updated sheet.
+Output a single JSON object with these fields:
+{
+  "scope": "MVP" | "FULL" | null,
+  "tech_stack": ["..."],
+  "budget": "..." | null,
+  "deadline": "..." | null,
+  "success_criteria": ["..."],
+  "open_questions": ["..."]
+}
+Rules:
+1. Only record information that was stated or confirmed in the conversation; do not guess
+2. Keep existing entries unless the conversation overrides them
+3. open_questions lists key questions still to confirm with the user; remove the ones already answered"#;
+
+pub(crate) const SUMMARY_SYSTEM_EN: &str = r#"You compress a requirements clarification conversation. Given the existing summary and the new turns, output the updated summary:
+1. Keep every confirmed requirement, constraint, decision and user preference, plus unresolved questions
+2. Drop small talk and repetition, and never invent anything not in the conversation
+3. Use a concise bullet list of at most 500 words"#;
+
+/// 模型调用失败的分类。服务层原样向上返回，由接口层映射为 HTTP 状态码，
+/// 失败信息不会作为助手回复写入对话。
+#[derive(Debug)]
+pub enum LlmError {
+    /// 未配置 API Key
+    NotConfigured,
+    Auth(String),
+    /// 余额不足或额度耗尽
+    Quota(String),
+    RateLimited(String),
+    Timeout(String),
+    ContextTooLong(String),
+    Provider(String),
+}
+
+impl LlmError {
+    /// 稳定的错误代码，供前端区分处理（如 SSE error 事件）
+    pub fn code(&self) -> &'static str {
+        match self {
+            LlmError::NotConfigured => "llm_not_configured",
+            LlmError::Auth(_) => "llm_auth",
+            LlmError::Quota(_) => "llm_quota",
+            LlmError::RateLimited(_) => "llm_rate_limited",
+            LlmError::Timeout(_) => "llm_timeout",
+            LlmError::ContextTooLong(_) => "llm_context_too_long",
+            LlmError::Provider(_) => "llm_failed",
+        }
+    }
+
+    /// 服务商返回的原始信息
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            LlmError::NotConfigured => None,
+            LlmError::Auth(d)
+            | LlmError::Quota(d)
+            | LlmError::RateLimited(d)
+            | LlmError::Timeout(d)
+            | LlmError::ContextTooLong(d)
+            | LlmError::Provider(d) => Some(d),
+        }
+    }
+}
+
+impl From<LlmConnectorError> for LlmError {
+    fn from(e: LlmConnectorError) -> Self {
+        match e {
+            LlmConnectorError::AuthenticationError(d) => LlmError::Auth(d),
+            LlmConnectorError::TimeoutError(d) => LlmError::Timeout(d),
+            LlmConnectorError::ContextLengthExceeded(d) => LlmError::ContextTooLong(d),
+            // 余额不足没有单独的错误类型，服务商多以 402 或 429 加说明返回
+            LlmConnectorError::RateLimitError(d) | LlmConnectorError::ApiError(d) if is_quota(&d) => {
+                LlmError::Quota(d)
+            }
+            LlmConnectorError::RateLimitError(d) => LlmError::RateLimited(d),
+            other => LlmError::Provider(other.to_string()),
+        }
+    }
+}
+
+fn is_quota(detail: &str) -> bool {
+    let lower = detail.to_lowercase();
+    ["insufficient balance", "insufficient_quota", "quota", "recharge", "402"]
+        .iter()
+        .any(|marker| lower.contains(marker))
+}
+
+impl fmt::Display for LlmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            LlmError::Provider(detail) => i18n::Message::new("llm_failed").arg("error", detail),
+            other => i18n::Message::new(other.code()),
+        };
+        write!(f, "{message}")
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+/// 模型输出的增量文本流
+pub type DeltaStream = Pin<Box<dyn Stream<Item = AppResult<String>> + Send>>;
+
+/// 可调用的一个模型：服务商、模型名与对应的客户端
+#[derive(Clone)]
+struct LlmTarget {
+    provider: String,
+    model: String,
     client: Arc<LlmClient>,
+}
+
+/// CELADON_LLM_FALLBACKS 中的一项
+#[derive(Debug, Deserialize)]
+struct FallbackConfig {
+    provider: String,
+    model: String,
+    api_key: String,
+}
+
+/// 单次调用的超时与重试策略
+#[derive(Debug, Clone, Copy)]
+struct RetryPolicy {
+    timeout: Duration,
+    /// 同一模型上的最大重试次数（不含首次）
+    max_retries: u32,
+    /// 首次重试前的等待，之后每次翻倍
+    backoff: Duration,
+}
+
+/// 一次模型调用尝试，供排查服务商故障
+#[derive(Debug, Clone, Serialize)]
+pub struct LlmAttempt {
+    pub at: String,
+    pub provider: String,
+    pub model: String,
+    /// 在该模型上的第几次尝试，从 1 开始
+    pub attempt: u32,
+    pub duration_ms: u64,
+    pub ok: bool,
+    pub error_code: Option<&'static str>,
+    pub error: Option<String>,
+}
+
+/// 进程内保留的最近尝试条数
+const MAX_ATTEMPTS_KEPT: usize = 200;
+
+static ATTEMPTS: Mutex<VecDeque<LlmAttempt>> = Mutex::new(VecDeque::new());
+
+/// 每次尝试只进内存记录，不单独打日志；最终失败由调用方按错误类型记录一次
+fn record_attempt(attempt: LlmAttempt) {
+    let mut attempts = ATTEMPTS.lock().unwrap_or_else(|e| e.into_inner());
+    if attempts.len() >= MAX_ATTEMPTS_KEPT {
+        attempts.pop_front();
+    }
+    attempts.push_back(attempt);
+}
+
+/// 调用用量缓冲；服务商未返回用量时按请求与回复文本估算
+#[derive(Clone, Default)]
+struct UsageSink(Arc<Mutex<Vec<CallUsage>>>);
+
+impl UsageSink {
+    /// reported 为服务商返回的 (prompt_tokens, completion_tokens)
+    fn push(&self, request: &ChatRequest, model: String, reported: Option<(u64, u64)>, content: &str) {
+        let usage = match reported {
+            Some((prompt_tokens, completion_tokens)) => CallUsage {
+                role: UsageRole::Planner,
+                model,
+                prompt_tokens,
+                completion_tokens,
+                estimated: false,
+            },
+            None => CallUsage {
+                role: UsageRole::Planner,
+                prompt_tokens: request
+                    .messages
+                    .iter()
+                    .map(|m| context::message_tokens(&model, &m.content_as_text()))
+                    .sum::<usize>() as u64,
+                completion_tokens: context::count_tokens(&model, content) as u64,
+                model,
+                estimated: true,
+            },
+        };
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).push(usage);
+    }
+}
+
+/// 流式输出的用量：累计收到的文本与服务商在末尾返回的用量，丢弃时写入缓冲
+struct StreamMeter {
+    sink: UsageSink,
+    request: ChatRequest,
+    model: String,
+    content: String,
+    usage: Option<(u64, u64)>,
+}
+
+impl Drop for StreamMeter {
+    fn drop(&mut self) {
+        let model = std::mem::take(&mut self.model);
+        self.sink.push(&self.request, model, self.usage, &self.content);
+    }
+}
+
+fn token_counts(usage: &Usage) -> (u64, u64) {
+    (usage.prompt_tokens as u64, usage.completion_tokens as u64)
+}
+
+/// 录制文件中用于匹配的请求内容；不含实际调用的模型，主备模型切换不影响回放
+fn request_key(request: &ChatRequest) -> Value {
+    json!({
+        "messages": request.messages,
+        "json": request.response_format.is_some()
+    })
+}
+
+/// RequirementsSheet 的 JSON Schema（不含服务层维护的 updated_at）；
+/// 严格模式要求列出全部字段，未知的值用 null 或空数组表示
+fn requirements_schema() -> Value {
+    let nullable_string = json!({ "type": ["string", "null"] });
+    let strings = json!({ "type": "array", "items": { "type": "string" } });
+    json!({
+        "type": "object",
+        "properties": {
+            "scope": { "type": ["string", "null"], "enum": ["MVP", "FULL", null] },
+            "tech_stack": strings,
+            "budget": nullable_string,
+            "deadline": nullable_string,
+            "success_criteria": strings,
+            "open_questions": strings
+        },
+        "required": ["scope", "tech_stack", "budget", "deadline", "success_criteria", "open_questions"],
+        "additionalProperties": false
+    })
+}
+
+/// 把完整回复按字符分段输出，模拟增量返回
+fn chunked(text: String) -> DeltaStream {
+    let chunks: Vec<AppResult<String>> = text
+        .chars()
+        .collect::<Vec<_>>()
+        .chunks(8)
+        .map(|chunk| Ok(chunk.iter().collect()))
+        .collect();
+    Box::pin(futures::stream::iter(chunks))
+}
+
+/// 最近的调用尝试，最新的在前
+pub fn recent_attempts(limit: usize) -> Vec<LlmAttempt> {
+    let attempts = ATTEMPTS.lock().unwrap_or_else(|e| e.into_inner());
+    attempts.iter().rev().take(limit).cloned().collect()
+}
+
+impl LlmError {
+    /// 同一模型上值得重试的错误；认证、额度与上下文过长换模型才可能成功
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            LlmError::RateLimited(_) | LlmError::Timeout(_) | LlmError::Provider(_)
+        )
+    }
+}
+
+fn build_client(provider: &str, key: &str) -> LlmClient {
+    let providers_data = llm_providers::get_providers_data();
+    if let Some(p) = providers_data.get(provider) {
+        LlmClient::openai_with_base_url(key, &p.base_url)
+            .unwrap_or_else(|_| LlmClient::deepseek(key).unwrap()) // Fallback
+    } else {
+        match provider {
+            "zhipu" => LlmClient::zhipu(key).unwrap_or_else(|_| LlmClient::deepseek(key).unwrap()),
+            "moonshot" => LlmClient::moonshot(key).unwrap_or_else(|_| LlmClient::deepseek(key).unwrap()),
+            "aliyun" => LlmClient::aliyun(key).unwrap_or_else(|_| LlmClient::deepseek(key).unwrap()),
+            "openai" => LlmClient::openai(key).unwrap_or_else(|_| LlmClient::deepseek(key).unwrap()),
+            "anthropic" => LlmClient::anthropic(key).unwrap_or_else(|_| LlmClient::deepseek(key).unwrap()),
+            "google" => LlmClient::google(key).unwrap_or_else(|_| LlmClient::deepseek(key).unwrap()),
+            _ => LlmClient::deepseek(key).unwrap_or_else(|_| LlmClient::deepseek("dummy").unwrap()),
+        }
+    }
+}
+
+/// 对话类调用依次尝试的模型：planner 在前，其后是 CELADON_LLM_FALLBACKS。
+/// 用户自带 planner Key 时不追加全局备用模型：备用模型用的是运营方的 Key，
+/// 用户的 Key 失败后不应改由运营方付费
+fn chat_targets(planner: Option<LlmTarget>, fallbacks: &str, user_planner_key: bool) -> Vec<LlmTarget> {
+    let mut targets: Vec<LlmTarget> = planner.into_iter().collect();
+    if user_planner_key || fallbacks.trim().is_empty() {
+        return targets;
+    }
+    match serde_json::from_str::<Vec<FallbackConfig>>(fallbacks) {
+        Ok(list) => targets.extend(
+            list.into_iter()
+                .filter(|f| !f.api_key.trim().is_empty())
+                .map(|f| LlmTarget {
+                    client: Arc::new(build_client(&f.provider, &f.api_key)),
+                    provider: f.provider,
+                    model: f.model,
+                }),
+        ),
+        Err(e) => tracing::warn!("CELADON_LLM_FALLBACKS 格式无效，已忽略: {e}"),
+    }
+    targets
+}
+
+/// 用户自带的 Key 解密后按角色展开为 ZENE_* 设置；未配置主密钥或解密失败的忽略，回退到全局设置
+async fn user_settings(pool: &crate::db::Pool, user_id: Uuid) -> Vec<(String, String)> {
+    let keys = match crate::db::list_user_llm_keys(pool, user_id).await {
+        Ok(keys) if keys.is_empty() => return Vec::new(),
+        Ok(keys) => keys,
+        Err(e) => {
+            tracing::warn!("读取用户模型 Key 失败（用户 {user_id}）: {e}");
+            return Vec::new();
+        }
+    };
+    let master = match SecretKey::from_env(crypto::MASTER_KEY_VAR) {
+        Ok(master) => master,
+        Err(e) => {
+            tracing::warn!("无法使用用户模型 Key，已回退到全局设置（用户 {user_id}）: {e}");
+            return Vec::new();
+        }
+    };
+    keys.into_iter()
+        .filter_map(|key| {
+            let api_key = master
+                .decrypt(&key.api_key)
+                .map_err(|e| tracing::warn!("解密用户模型 Key 失败，已忽略（用户 {user_id}，{:?}）: {e}", key.role))
+                .ok()?;
+            let prefix = match key.role {
+                UsageRole::Planner => "ZENE_PLANNER",
+                UsageRole::Executor => "ZENE_EXECUTOR",
+                UsageRole::Reflector => "ZENE_REFLECTOR",
+            };
+            Some([
+                (format!("{prefix}_PROVIDER"), key.provider),
+                (format!("{prefix}_MODEL"), key.model),
+                (format!("{prefix}_API_KEY"), api_key),
+            ])
+        })
+        .flatten()
+        .collect()
+}
+
+pub struct LlmGateway {
+    // Basic LLM features in Celadon (clarify, PRD): planner first, then CELADON_LLM_FALLBACKS in order
+    targets: Vec<LlmTarget>,
+    policy: RetryPolicy,
+    /// 尚未归属到项目的调用用量，由服务层取走后记入状态
+    usage: UsageSink,
+    pub prices: PriceTable,
+    /// ZENE_PLANNER_PROVIDER=mock 时的离线模拟模型，优先于 targets
+    mock: Option<MockLlm>,
     
     // Zene-aligned role configs
     pub planner_provider: String,
@@ -110,8 +654,10 @@ pub struct LlmGateway {
 }
 
 impl LlmGateway {
-    pub async fn load(pool: Option<&crate::db::Pool>) -> Result<Self, String> {
+    /// 读取模型配置：用户自带的 Key（user_id 不为空时）优先，其次系统设置，最后环境变量
+    pub async fn load(pool: Option<&crate::db::Pool>, user_id: Option<Uuid>) -> Result<Self, String> {
         let mut settings = std::collections::HashMap::new();
+        let mut user_planner_key = false;
 
         if let Some(p) = pool {
             let keys = vec![
@@ -119,6 +665,8 @@ impl LlmGateway {
                 "ZENE_EXECUTOR_PROVIDER", "ZENE_EXECUTOR_API_KEY", "ZENE_EXECUTOR_MODEL",
                 "ZENE_REFLECTOR_PROVIDER", "ZENE_REFLECTOR_API_KEY", "ZENE_REFLECTOR_MODEL",
                 "ZENE_USE_SEMANTIC_MEMORY", "CELADON_LLM_MODEL",
+                "CELADON_LLM_FALLBACKS", "CELADON_LLM_TIMEOUT_SECS",
+                "CELADON_LLM_MAX_RETRIES", "CELADON_LLM_BACKOFF_MS", "CELADON_LLM_PRICES",
                 "DEEPSEEK_API_KEY", "OPENAI_API_KEY"
             ];
             for k in keys {
@@ -127,6 +675,11 @@ impl LlmGateway {
                         settings.insert(k.to_string(), val);
                     }
             }
+            if let Some(user_id) = user_id {
+                let user = user_settings(p, user_id).await;
+                user_planner_key = user.iter().any(|(k, _)| k == "ZENE_PLANNER_API_KEY");
+                settings.extend(user);
+            }
         }
 
         let get_setting = |key: &str, default: &str| -> String {
@@ -149,31 +702,34 @@ impl LlmGateway {
 
         // Basic client for non-zene features (e.g. clarify, generate_prd)
         // Use the planner provider & key if available, otherwise read DEEPSEEK_API_KEY, otherwise "dummy".
-        let basic_key = if !planner_key.is_empty() { planner_key.clone() } else { get_setting("DEEPSEEK_API_KEY", "dummy") };
+        let basic_key = if planner_provider == mock::MOCK_MODEL {
+            "dummy".to_string()
+        } else if !planner_key.is_empty() { planner_key.clone() } else { get_setting("DEEPSEEK_API_KEY", "dummy") };
         
-        let is_dummy = basic_key == "dummy" || basic_key.trim().is_empty();
-        let client = if is_dummy {
-            LlmClient::openai("dummy").unwrap() // Fallback mock - won't be called due to is_dummy check
-        } else {
-            let providers_data = llm_providers::get_providers_data();
-            if let Some(p) = providers_data.get(&planner_provider) {
-                LlmClient::openai_with_base_url(&basic_key, &p.base_url)
-                    .unwrap_or_else(|_| LlmClient::deepseek(&basic_key).unwrap()) // Fallback
-            } else {
-                match planner_provider.as_str() {
-                    "zhipu" => LlmClient::zhipu(&basic_key).unwrap_or_else(|_| LlmClient::deepseek(&basic_key).unwrap()),
-                    "moonshot" => LlmClient::moonshot(&basic_key).unwrap_or_else(|_| LlmClient::deepseek(&basic_key).unwrap()),
-                    "aliyun" => LlmClient::aliyun(&basic_key).unwrap_or_else(|_| LlmClient::deepseek(&basic_key).unwrap()),
-                    "openai" => LlmClient::openai(&basic_key).unwrap_or_else(|_| LlmClient::deepseek(&basic_key).unwrap()),
-                    "anthropic" => LlmClient::anthropic(&basic_key).unwrap_or_else(|_| LlmClient::deepseek(&basic_key).unwrap()),
-                    "google" => LlmClient::google(&basic_key).unwrap_or_else(|_| LlmClient::deepseek(&basic_key).unwrap()),
-                    _ => LlmClient::deepseek(&basic_key).unwrap_or_else(|_| LlmClient::deepseek("dummy").unwrap()),
-                }
-            }
+        let planner = (basic_key != "dummy" && !basic_key.trim().is_empty()).then(|| LlmTarget {
+            client: Arc::new(build_client(&planner_provider, &basic_key)),
+            provider: planner_provider.clone(),
+            model: planner_model.clone(),
+        });
+        let targets = chat_targets(planner, &get_setting("CELADON_LLM_FALLBACKS", ""), user_planner_key);
+        let mock = (planner_provider == mock::MOCK_MODEL).then(MockLlm::load);
+        let is_dummy = targets.is_empty() && mock.is_none();
+
+        let number = |key: &str, default: u64| -> u64 {
+            get_setting(key, "").trim().parse().unwrap_or(default)
+        };
+        let policy = RetryPolicy {
+            timeout: Duration::from_secs(number("CELADON_LLM_TIMEOUT_SECS", 60).max(1)),
+            max_retries: number("CELADON_LLM_MAX_RETRIES", 2) as u32,
+            backoff: Duration::from_millis(number("CELADON_LLM_BACKOFF_MS", 500)),
         };
 
         Ok(Self {
-            client: Arc::new(client),
+            targets,
+            policy,
+            usage: UsageSink::default(),
+            prices: usage::parse_prices(&get_setting("CELADON_LLM_PRICES", "")),
+            mock,
             planner_provider, planner_key, planner_model,
             executor_provider, executor_key, executor_model,
             reflector_provider, reflector_key, reflector_model,
@@ -203,12 +759,7 @@ impl LlmGateway {
         config
     }
 
-    pub async fn clarify_round(
-        &self,
-        system_prompt: &str,
-        history: &[(String, String)],
-        user_input: &str,
-    ) -> AppResult<String> {
+    fn chat_request(&self, system_prompt: &str, history: &[(String, String)], user_input: &str) -> ChatRequest {
         let mut messages: Vec<(String, String)> = vec![("system".to_string(), system_prompt.to_string())];
         messages.extend(history.iter().cloned());
         messages.push(("user".to_string(), user_input.to_string()));
@@ -224,31 +775,272 @@ impl LlmGateway {
                 }
             })
             .collect();
-        let request = ChatRequest::new(&self.planner_model)
+        ChatRequest::new(&self.planner_model)
             .with_messages(msgs)
-            .with_max_tokens(2048);
-        if self.is_dummy {
-            return Ok("请先在管理员设置中配置 LLM API Key 以启用需求澄清功能。".to_string());
+            .with_max_tokens(2048)
+    }
+
+    /// 按顺序尝试各模型：每次调用受超时限制，可重试的错误指数退避后在同一模型上重试，
+    /// 仍失败或不可重试时换下一个模型；全部失败时返回最后一个错误
+    async fn call<T, F, Fut>(&self, request: &ChatRequest, op: F) -> Result<(T, String), LlmError>
+    where
+        F: Fn(Arc<LlmClient>, ChatRequest) -> Fut,
+        Fut: Future<Output = Result<T, LlmConnectorError>>,
+    {
+        let mut last_error = LlmError::NotConfigured;
+        for target in &self.targets {
+            let mut request = request.clone();
+            request.model = target.model.clone();
+            for attempt in 0..=self.policy.max_retries {
+                if attempt > 0 {
+                    tokio::time::sleep(self.policy.backoff * 2u32.pow(attempt - 1)).await;
+                }
+                let started = Instant::now();
+                let result = match tokio::time::timeout(
+                    self.policy.timeout,
+                    op(target.client.clone(), request.clone()),
+                )
+                .await
+                {
+                    Ok(result) => result.map_err(LlmError::from),
+                    Err(_) => Err(LlmError::Timeout(format!(
+                        "no response within {}s",
+                        self.policy.timeout.as_secs()
+                    ))),
+                };
+                record_attempt(LlmAttempt {
+                    at: crate::utils::now_timestamp(),
+                    provider: target.provider.clone(),
+                    model: target.model.clone(),
+                    attempt: attempt + 1,
+                    duration_ms: started.elapsed().as_millis() as u64,
+                    ok: result.is_ok(),
+                    error_code: result.as_ref().err().map(LlmError::code),
+                    error: result.as_ref().err().and_then(|e| e.detail()).map(str::to_string),
+                });
+                match result {
+                    Ok(value) => return Ok((value, target.model.clone())),
+                    Err(e) => {
+                        let retryable = e.is_retryable();
+                        last_error = e;
+                        if !retryable {
+                            break;
+                        }
+                    }
+                }
+            }
         }
+        Err(last_error)
+    }
+
+    /// 非流式调用的统一入口：回放模式取录制的回复，否则调用模拟模型或真实模型，
+    /// 记下用量，录制模式下写入录制文件
+    async fn chat(&self, request: &ChatRequest, mock_reply: impl FnOnce(&MockLlm) -> String) -> AppResult<String> {
+        let cassette = cassette::active();
+        if let Some(c) = cassette.filter(|c| c.mode() == CassetteMode::Replay) {
+            let reply = c.replay_reply(CallKind::Chat, &request_key(request))?;
+            self.usage.push(request, reply.model, reply.usage, &reply.content);
+            return Ok(reply.content);
+        }
+        let reply = match &self.mock {
+            Some(mock) => RecordedReply {
+                content: mock_reply(mock),
+                model: mock::MOCK_MODEL.to_string(),
+                usage: None,
+            },
+            None if self.is_dummy => return Err(LlmError::NotConfigured.into()),
+            None => {
+                let (response, model) = self
+                    .call(request, |client, request| async move { client.chat(&request).await })
+                    .await?;
+                RecordedReply {
+                    usage: response.usage.as_ref().map(token_counts),
+                    content: response.content,
+                    model,
+                }
+            }
+        };
+        if let Some(c) = cassette {
+            c.record_reply(CallKind::Chat, request_key(request), &reply);
+        }
+        self.usage.push(request, reply.model, reply.usage, &reply.content);
+        Ok(reply.content)
+    }
+
+    /// 未配置模型且不在回放时，摘要与需求整理直接跳过
+    fn can_skip(&self) -> bool {
+        self.is_dummy && !cassette::replaying()
+    }
+
+    pub async fn clarify_round(
+        &self,
+        system_prompt: &str,
+        history: &[(String, String)],
+        user_input: &str,
+    ) -> AppResult<String> {
+        let request = self.chat_request(system_prompt, history, user_input);
+        self.chat(&request, |mock| mock.clarify(history, user_input)).await
+    }
 
-        match self.client.chat(&request).await {
-            Ok(response) => Ok(response.content),
-            Err(e) => {
-                let err_msg = e.to_string();
-                let lower = err_msg.to_lowercase();
-                if lower.contains("insufficient balance") || lower.contains("recharge") || lower.contains("quota") || lower.contains("balance") || lower.contains("credit") {
-                    Ok("LLM 余额不足或额度耗尽，请检查您的 API 账户余额及配额限制。".to_string())
-                } else if lower.contains("authentication failed") || lower.contains("invalid api key") || lower.contains("api_key_invalid") {
-                    Ok("LLM API Key 无效或认证失败，请在管理员设置中检查您的配置。".to_string())
-                } else if lower.contains("rate limit") || lower.contains("too many requests") {
-                    Ok("LLM 调用频率过快，请稍后再试。".to_string())
-                } else {
-                    Err(format!("LLM 调用失败: {err_msg}").into())
+    /// 流式版本的 clarify_round：逐段返回模型输出的增量文本
+    pub async fn clarify_stream(
+        &self,
+        system_prompt: &str,
+        history: &[(String, String)],
+        user_input: &str,
+    ) -> AppResult<DeltaStream> {
+        let request = self.chat_request(system_prompt, history, user_input);
+        let cassette = cassette::active();
+        if let Some(c) = cassette.filter(|c| c.mode() == CassetteMode::Replay) {
+            let reply = c.replay_reply(CallKind::Stream, &request_key(&request))?;
+            self.usage.push(&request, reply.model, reply.usage, &reply.content);
+            return Ok(chunked(reply.content));
+        }
+        if let Some(mock) = &self.mock {
+            let reply = RecordedReply {
+                content: mock.clarify(history, user_input),
+                model: mock::MOCK_MODEL.to_string(),
+                usage: None,
+            };
+            if let Some(c) = cassette {
+                c.record_reply(CallKind::Stream, request_key(&request), &reply);
+            }
+            self.usage.push(&request, reply.model, None, &reply.content);
+            return Ok(chunked(reply.content));
+        }
+        if self.is_dummy {
+            return Err(LlmError::NotConfigured.into());
+        }
+        // 重试与切换模型只覆盖建立连接，输出开始后中断由调用方保存已收到的部分
+        let (mut stream, model) = self
+            .call(&request, |client, request| async move { client.chat_stream(&request).await })
+            .await?;
+        // 流被丢弃（正常结束、出错或客户端断开）时记下用量
+        let mut meter = StreamMeter {
+            sink: self.usage.clone(),
+            request,
+            model,
+            content: String::new(),
+            usage: None,
+        };
+        Ok(Box::pin(async_stream::stream! {
+            let mut complete = true;
+            while let Some(chunk) = stream.next().await {
+                match chunk {
+                    Ok(chunk) => {
+                        if let Some(usage) = &chunk.usage {
+                            meter.usage = Some(token_counts(usage));
+                        }
+                        if let Some(delta) = chunk.get_content().filter(|delta| !delta.is_empty()) {
+                            meter.content.push_str(delta);
+                            yield Ok(delta.to_string());
+                        }
+                    }
+                    Err(e) => {
+                        complete = false;
+                        yield Err(LlmError::from(e).into());
+                        break;
+                    }
                 }
             }
+            // 只录制完整的回复
+            if complete && let Some(c) = cassette {
+                let reply = RecordedReply {
+                    content: meter.content.clone(),
+                    model: meter.model.clone(),
+                    usage: meter.usage,
+                };
+                c.record_reply(CallKind::Stream, request_key(&meter.request), &reply);
+            }
+        }))
+    }
+
+    /// 根据对话生成 PRD 正文
+    pub async fn generate_prd(&self, system_prompt: &str, input: &str) -> AppResult<String> {
+        let request = self.chat_request(system_prompt, &[], input);
+        self.chat(&request, |mock| mock.prd(input)).await
+    }
+
+    /// 取走已记录的调用用量
+    pub fn take_usage(&self) -> Vec<CallUsage> {
+        std::mem::take(&mut *self.usage.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Zene 角色对应的模型，估算开发用量时使用；模拟模式下开发运行同样是模拟的
+    pub fn role_model(&self, role: UsageRole) -> String {
+        if self.mock.is_some() {
+            return mock::MOCK_MODEL.to_string();
+        }
+        match role {
+            UsageRole::Planner => self.planner_model.clone(),
+            UsageRole::Executor => self.executor_model.clone(),
+            UsageRole::Reflector => self.reflector_model.clone(),
         }
     }
 
+    /// 把新增轮次并入已有摘要；未配置模型时返回 None
+    pub async fn summarize(
+        &self,
+        system_prompt: &str,
+        previous: &str,
+        turns: &str,
+    ) -> AppResult<Option<String>> {
+        if self.can_skip() {
+            return Ok(None);
+        }
+        let input = format!(
+            "{}:\n{previous}\n\n{}:\n{turns}",
+            i18n::t("summary_input_previous"),
+            i18n::t("summary_input_turns")
+        );
+        let request = self.chat_request(system_prompt, &[], &input);
+        let content = self.chat(&request, |mock| mock.summarize(previous, turns)).await?;
+        let summary = content.trim();
+        Ok((!summary.is_empty()).then(|| summary.to_string()))
+    }
+
+    /// 按 RequirementsSheet 的 JSON Schema 整理需求清单；未配置模型时返回 None，保留原清单
+    pub async fn extract_requirements(
+        &self,
+        system_prompt: &str,
+        conversation: &str,
+        current: &RequirementsSheet,
+    ) -> AppResult<Option<RequirementsSheet>> {
+        if self.can_skip() {
+            return Ok(None);
+        }
+        // 更新时间由服务层维护，不交给模型，也让同样的对话得到相同的请求
+        let sheet = RequirementsSheet {
+            updated_at: None,
+            ..current.clone()
+        };
+        let input = format!(
+            "{}:\n{}\n\n{}:\n{conversation}",
+            i18n::t("requirements_input_current"),
+            serde_json::to_string_pretty(&sheet)?,
+            i18n::t("prd_input_conversation")
+        );
+        let mut request = self.chat_request(system_prompt, &[], &input);
+        request.response_format = Some(ResponseFormat::json_schema(
+            "requirements_sheet",
+            requirements_schema(),
+        ));
+        let content = self
+            .chat(&request, |mock| {
+                serde_json::to_string(&mock.requirements(conversation, current)).unwrap_or_default()
+            })
+            .await?;
+        let content = content.trim();
+        // 个别模型仍会包一层 ```json 代码块
+        let json_text = match (content.find('{'), content.rfind('}')) {
+            (Some(start), Some(end)) if start < end => &content[start..=end],
+            _ => content,
+        };
+        let sheet = serde_json::from_str(json_text)
+            .map_err(|e| i18n::Message::new("requirements_invalid_json").arg("error", e))?;
+        Ok(Some(sheet))
+    }
+
     pub fn invoke_payload(&self, model: &str, prompt: &str, context: &Value) -> Value {
         json!({
             "method": "provider.invoke",
@@ -260,3 +1052,80 @@ impl LlmGateway {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(model: &str, question: &str) -> ChatRequest {
+        ChatRequest::new(model).with_messages(vec![
+            Message::system("你是需求澄清助手"),
+            Message::user(question),
+        ])
+    }
+
+    #[test]
+    fn request_key_ignores_the_model() {
+        // 主模型失败后切到备用模型，回放时仍应命中同一条录制
+        assert_eq!(
+            request_key(&request("gpt-4o", "做一个记账工具")),
+            request_key(&request("deepseek-chat", "做一个记账工具"))
+        );
+    }
+
+    #[test]
+    fn request_key_distinguishes_messages_and_json_mode() {
+        let plain = request("gpt-4o", "做一个记账工具");
+        assert_ne!(request_key(&plain), request_key(&request("gpt-4o", "做一个笔记应用")));
+
+        let mut structured = plain.clone();
+        structured.response_format =
+            Some(ResponseFormat::json_schema("requirements_sheet", requirements_schema()));
+        assert_ne!(request_key(&plain), request_key(&structured));
+    }
+
+    #[test]
+    fn user_planner_key_skips_global_fallbacks() {
+        let planner = || {
+            Some(LlmTarget {
+                client: Arc::new(build_client("openai", "sk-user")),
+                provider: "openai".to_string(),
+                model: "gpt-4o".to_string(),
+            })
+        };
+        let fallbacks = r#"[{"provider": "deepseek", "model": "deepseek-chat", "api_key": "sk-operator"}]"#;
+        let providers = |targets: Vec<LlmTarget>| -> Vec<String> { targets.into_iter().map(|t| t.provider).collect() };
+
+        assert_eq!(providers(chat_targets(planner(), fallbacks, false)), ["openai", "deepseek"]);
+        // 用户自带的 Key 失败后不能换到运营方付费的备用模型
+        assert_eq!(providers(chat_targets(planner(), fallbacks, true)), ["openai"]);
+    }
+
+    #[test]
+    fn requirements_schema_matches_the_sheet() {
+        let sheet = RequirementsSheet {
+            scope: Some(crate::models::ProjectScope::Mvp),
+            ..RequirementsSheet::default()
+        };
+        let Value::Object(mut fields) = serde_json::to_value(&sheet).unwrap() else {
+            panic!("sheet should serialize to an object");
+        };
+        fields.remove("updated_at");
+        let schema = requirements_schema();
+        let mut required: Vec<&str> = schema["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect();
+        let mut keys: Vec<&str> = fields.keys().map(String::as_str).collect();
+        required.sort_unstable();
+        keys.sort_unstable();
+        assert_eq!(required, keys);
+        assert!(schema["properties"]["scope"]["enum"].as_array().unwrap().contains(&fields["scope"]));
+
+        // 按 schema 输出、没有 updated_at 的回复仍能解析为清单
+        let reply = serde_json::to_string(&fields).unwrap();
+        assert_eq!(serde_json::from_str::<RequirementsSheet>(&reply).unwrap(), sheet);
+    }
+}
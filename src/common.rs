@@ -1 +1,22 @@
+use crate::i18n::Message;
+use std::fmt;
+
 pub type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// 乐观并发控制冲突：提交时存储中的 revision 已被其他请求推进
+#[derive(Debug)]
+pub struct StateConflict {
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for StateConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = Message::new("state_conflict")
+            .arg("expected", self.expected)
+            .arg("actual", self.actual);
+        write!(f, "{message}")
+    }
+}
+
+impl std::error::Error for StateConflict {}
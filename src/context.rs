@@ -0,0 +1,101 @@
+//! 对话上下文预算：按模型估算 token，超出预算时把较早的轮次折叠进会话的滚动摘要，
+//! 只把摘要和最近的轮次发给模型。澄清、需求整理与 PRD 生成共用同一预算。
+
+use std::ops::Range;
+
+/// 默认留给历史对话的 token 预算，可用 CELADON_CONTEXT_BUDGET 覆盖
+const DEFAULT_BUDGET: usize = 6000;
+
+/// 每条消息的角色与分隔开销
+const MESSAGE_OVERHEAD: usize = 4;
+
+pub fn budget() -> usize {
+    std::env::var("CELADON_CONTEXT_BUDGET")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_BUDGET)
+}
+
+/// 各模型分词器的粗略比例：拉丁字符每 token 的字符数、每个 CJK 字符的 token 数
+fn token_ratio(model: &str) -> (f32, f32) {
+    let model = model.to_lowercase();
+    if model.starts_with("deepseek") || model.starts_with("qwen") || model.starts_with("glm") {
+        // 中文词表较大的模型
+        (3.8, 0.6)
+    } else if model.starts_with("gpt-4o") || model.starts_with("o1") || model.starts_with("o3") {
+        (4.0, 0.8)
+    } else if model.starts_with("claude") {
+        (3.5, 1.2)
+    } else {
+        (3.5, 1.0)
+    }
+}
+
+/// 估算文本在该模型下的 token 数，宁多勿少
+pub fn count_tokens(model: &str, text: &str) -> usize {
+    let (chars_per_token, cjk_per_char) = token_ratio(model);
+    let (mut latin, mut cjk) = (0usize, 0usize);
+    for c in text.chars() {
+        if is_cjk(c) {
+            cjk += 1;
+        } else {
+            latin += 1;
+        }
+    }
+    (latin as f32 / chars_per_token + cjk as f32 * cjk_per_char).ceil() as usize
+}
+
+pub fn message_tokens(model: &str, content: &str) -> usize {
+    count_tokens(model, content) + MESSAGE_OVERHEAD
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3000..=0x303F | 0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xAC00..=0xD7AF | 0xFF00..=0xFFEF)
+}
+
+/// 本次需要折叠进摘要的轮次，以及原样保留的起点
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContextPlan {
+    pub fold: Range<usize>,
+    pub keep_from: usize,
+}
+
+/// turns 为会话全部轮次，covered 为摘要已覆盖的轮数，available 为可用的 token。
+/// 超出预算时折叠到最近轮次只占一半预算，避免之后每轮都要重新摘要。
+pub fn plan(
+    model: &str,
+    turns: &[(String, String)],
+    covered: usize,
+    summary_tokens: usize,
+    available: usize,
+) -> ContextPlan {
+    let covered = covered.min(turns.len());
+    let pending: usize = turns[covered..]
+        .iter()
+        .map(|(_, content)| message_tokens(model, content))
+        .sum();
+    if summary_tokens + pending <= available {
+        return ContextPlan {
+            fold: covered..covered,
+            keep_from: covered,
+        };
+    }
+
+    let target = available / 2;
+    let mut kept = 0;
+    let mut keep_from = turns.len();
+    while keep_from > covered {
+        let cost = message_tokens(model, &turns[keep_from - 1].1);
+        if kept + cost > target {
+            break;
+        }
+        kept += cost;
+        keep_from -= 1;
+    }
+    ContextPlan {
+        fold: covered..keep_from,
+        keep_from,
+    }
+}
@@ -0,0 +1,142 @@
+//! 服务端的密钥加密：AES-256-GCM，密钥为环境变量中 base64 编码的 32 字节。
+//!
+//! 密文格式为 `v1:` 加 base64(12 字节随机 nonce + 密文)，便于以后更换算法。
+//! 系统设置中的密钥值使用信封加密：每个值有自己的数据密钥，数据密钥再由
+//! CELADON_SETTINGS_KEK 加密保存；更换 KEK 时只需重新加密数据密钥，值本身不变。
+
+use crate::common::AppResult;
+use crate::i18n::Message;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+
+/// 加密用户自带模型 Key 的主密钥
+pub const MASTER_KEY_VAR: &str = "CELADON_MASTER_KEY";
+
+/// 加密系统设置中数据密钥的 KEK，及轮换期间仍可解密的上一个 KEK
+pub const SETTINGS_KEK_VAR: &str = "CELADON_SETTINGS_KEK";
+pub const SETTINGS_KEK_PREVIOUS_VAR: &str = "CELADON_SETTINGS_KEK_PREVIOUS";
+
+const VERSION_PREFIX: &str = "v1:";
+const NONCE_LEN: usize = 12;
+
+pub struct SecretKey(Aes256Gcm);
+
+impl SecretKey {
+    /// 从环境变量读取密钥；未设置或格式不对时返回错误
+    pub fn from_env(var: &'static str) -> AppResult<Self> {
+        let text = std::env::var(var).unwrap_or_default();
+        if text.trim().is_empty() {
+            return Err(Message::new("secret_key_missing").arg("var", var).into());
+        }
+        Self::from_base64(&text).ok_or_else(|| Message::new("secret_key_invalid").arg("var", var).into())
+    }
+
+    fn from_base64(text: &str) -> Option<Self> {
+        let bytes = STANDARD.decode(text.trim()).ok()?;
+        Aes256Gcm::new_from_slice(&bytes).ok().map(Self)
+    }
+
+    /// 随机生成一个密钥，同时返回其 base64 文本
+    fn generate() -> (Self, String) {
+        let key = Aes256Gcm::generate_key(&mut OsRng);
+        (Self(Aes256Gcm::new(&key)), STANDARD.encode(key))
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> AppResult<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, plaintext.as_bytes())
+            .map_err(|_| Message::new("encrypt_failed"))?;
+        let mut payload = nonce.to_vec();
+        payload.extend(ciphertext);
+        Ok(format!("{VERSION_PREFIX}{}", STANDARD.encode(payload)))
+    }
+
+    /// 密钥不对或密文被改动时返回错误
+    pub fn decrypt(&self, encoded: &str) -> AppResult<String> {
+        let payload = encoded
+            .strip_prefix(VERSION_PREFIX)
+            .and_then(|text| STANDARD.decode(text).ok())
+            .filter(|payload| payload.len() > NONCE_LEN)
+            .ok_or_else(|| Message::new("decrypt_failed"))?;
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Message::new("decrypt_failed"))?;
+        Ok(String::from_utf8(plaintext).map_err(|_| Message::new("decrypt_failed"))?)
+    }
+}
+
+/// 信封加密后的设置值与其数据密钥
+pub struct Sealed {
+    pub value: String,
+    pub data_key: String,
+}
+
+/// 系统设置的 KEK；设置了 CELADON_SETTINGS_KEK_PREVIOUS 时，旧 KEK 加密的数据密钥仍可解开
+pub struct Keyring {
+    current: SecretKey,
+    previous: Option<SecretKey>,
+}
+
+impl Keyring {
+    pub fn from_env() -> AppResult<Self> {
+        let current = SecretKey::from_env(SETTINGS_KEK_VAR)?;
+        let previous = match std::env::var(SETTINGS_KEK_PREVIOUS_VAR) {
+            Ok(text) if !text.trim().is_empty() => Some(SecretKey::from_env(SETTINGS_KEK_PREVIOUS_VAR)?),
+            _ => None,
+        };
+        Ok(Self { current, previous })
+    }
+
+    pub fn seal(&self, plaintext: &str) -> AppResult<Sealed> {
+        let (data_key, encoded) = SecretKey::generate();
+        Ok(Sealed {
+            value: data_key.encrypt(plaintext)?,
+            data_key: self.current.encrypt(&encoded)?,
+        })
+    }
+
+    pub fn open(&self, value: &str, data_key: &str) -> AppResult<String> {
+        let (encoded, _) = self.unwrap_data_key(data_key)?;
+        let data_key = SecretKey::from_base64(&encoded).ok_or_else(|| Message::new("decrypt_failed"))?;
+        data_key.decrypt(value)
+    }
+
+    /// 数据密钥由旧 KEK 加密时，返回用当前 KEK 重新加密的结果；已是当前 KEK 时返回 None
+    pub fn rewrap(&self, data_key: &str) -> AppResult<Option<String>> {
+        match self.unwrap_data_key(data_key)? {
+            (_, true) => Ok(None),
+            (encoded, false) => Ok(Some(self.current.encrypt(&encoded)?)),
+        }
+    }
+
+    /// 解开数据密钥，并返回是否由当前 KEK 加密
+    fn unwrap_data_key(&self, data_key: &str) -> AppResult<(String, bool)> {
+        match self.current.decrypt(data_key) {
+            Ok(encoded) => Ok((encoded, true)),
+            Err(e) => match &self.previous {
+                Some(previous) => Ok((previous.decrypt(data_key)?, false)),
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// 只露出前缀与末 4 位的掩码，如 `sk-…abcd`；过短的值全部隐藏
+pub fn mask(secret: &str) -> String {
+    let chars: Vec<char> = secret.trim().chars().collect();
+    if chars.len() <= 8 {
+        return "…".to_string();
+    }
+    let prefix: String = match chars.iter().take(4).position(|c| *c == '-') {
+        Some(dash) => chars[..=dash].iter().collect(),
+        None => chars[..2].iter().collect(),
+    };
+    let suffix: String = chars[chars.len() - 4..].iter().collect();
+    format!("{prefix}…{suffix}")
+}
@@ -1,12 +1,22 @@
 //! PostgreSQL 连接、迁移与按用户的状态存储
 
-use crate::common::AppResult;
-use crate::models::StateStore;
+use crate::common::{AppResult, StateConflict};
+use crate::crypto::{self, Keyring};
+use crate::models::{
+    Budget, ConversationTurn, DeploymentRun, IdeaEvent, Iteration, PrdVersion, Project, ProjectEvent,
+    Session, StateChange, StateStore, TaskRun, UsageRecord, UsageRole,
+};
+use crate::schema;
+use crate::usage::{Spend, UsageRow};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
 use serde_json::{Value, json};
-use sqlx::postgres::PgPoolOptions;
+use sqlx::postgres::{PgConnection, PgPoolOptions, PgRow};
 use sqlx::types::Json;
 use sqlx::PgPool;
 use sqlx::Row;
+use std::collections::HashSet;
 use std::time::Duration;
 use uuid::Uuid;
 
@@ -26,79 +36,1044 @@ pub async fn init_pool(database_url: &str) -> Result<Pool, String> {
         .await
         .map_err(|e| format!("执行迁移失败: {e}"))?;
 
+    // 未配置 KEK 时密钥类设置保持原样，不影响启动
+    match seal_secret_settings(&pool).await {
+        Ok((0, 0)) => {}
+        Ok((sealed, rewrapped)) => {
+            tracing::info!("系统设置密钥：新加密 {sealed} 项，改用新 KEK {rewrapped} 项")
+        }
+        Err(e) => tracing::warn!("未加密系统设置中的密钥: {e}"),
+    }
+
     Ok(pool)
 }
 
-/// 读取对应用户的 state（无则返回默认）
-pub async fn load_user_state(pool: &Pool, user_id: Uuid) -> AppResult<StateStore> {
-    let row = sqlx::query("SELECT state_json FROM user_state WHERE user_id = $1")
+/// 用户状态的 schema 版本落后时执行 schema 迁移。逐行存储下字段与表结构的变化由 SQL 迁移完成，
+/// 这里把现有各行拼成该旧版本的状态文档交给 schema::upgrade，只写入迁移新增的记录
+/// （不删除、不整体替换已有行），并在同一事务中更新 schema_version
+pub async fn upgrade_user_state(pool: &Pool, user_id: Uuid) -> AppResult<()> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("开启事务失败: {e}"))?;
+    let version = sqlx::query_scalar::<_, i32>(
+        "SELECT schema_version FROM user_state WHERE user_id = $1 FOR UPDATE",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|e| format!("读取状态 schema 版本失败: {e}"))?;
+    let version = match version {
+        Some(v) if (v as u32) < schema::CURRENT_SCHEMA_VERSION => v,
+        _ => return Ok(()),
+    };
+    let mut state = StateStore::default();
+    read_index(&mut tx, user_id, &mut state).await?;
+    let project_ids: Vec<String> = state.projects.keys().cloned().collect();
+    for project_id in project_ids {
+        read_project_rows(&mut tx, user_id, &project_id, &mut state).await?;
+    }
+    let mut doc = serde_json::to_value(&state)?;
+    doc["schema_version"] = json!(version);
+    let upgraded = schema::upgrade(doc)?;
+
+    let existing: HashSet<String> = StateChange::snapshot(&state)
+        .iter()
+        .filter_map(|c| serde_json::to_string(c).ok())
+        .collect();
+    let added: Vec<StateChange> = StateChange::snapshot(&upgraded)
+        .into_iter()
+        .filter(|c| serde_json::to_string(c).is_ok_and(|text| !existing.contains(&text)))
+        .collect();
+    if !added.is_empty() {
+        write_changes(&mut tx, user_id, &added).await?;
+        bump_revision(&mut tx, user_id).await?;
+    }
+    sqlx::query("UPDATE user_state SET schema_version = $2 WHERE user_id = $1")
         .bind(user_id)
-        .fetch_optional(pool)
+        .bind(schema::CURRENT_SCHEMA_VERSION as i32)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| format!("读取用户状态失败: {e}"))?;
-    let state: StateStore = match row {
-        Some(r) => {
-            let json: Json<Value> = r.get("state_json");
-            serde_json::from_value(json.0).map_err(|e| format!("反序列化状态失败: {e}"))?
-        }
-        None => StateStore::default(),
+        .map_err(|e| format!("更新状态 schema 版本失败: {e}"))?;
+    tx.commit().await.map_err(|e| format!("提交事务失败: {e}"))?;
+    Ok(())
+}
+
+/// 读取对应用户的项目与会话索引（不含对话、PRD 等明细，按需再用 load_project_rows 补齐）
+pub async fn load_user_index(pool: &Pool, user_id: Uuid) -> AppResult<StateStore> {
+    let mut conn = pool
+        .acquire()
+        .await
+        .map_err(|e| format!("获取数据库连接失败: {e}"))?;
+    let mut state = StateStore {
+        revision: read_revision(&mut conn, user_id, false).await?,
+        ..Default::default()
     };
+    read_index(&mut conn, user_id, &mut state).await?;
     Ok(state)
 }
 
-/// 写入对应用户的 state
-pub async fn save_user_state(pool: &Pool, user_id: Uuid, state: &StateStore) -> AppResult<()> {
-    let json = serde_json::to_value(state).map_err(|e| format!("序列化状态失败: {e}"))?;
-    sqlx::query(
-        "INSERT INTO user_state (user_id, state_json, updated_at) VALUES ($1, $2, now())
-         ON CONFLICT (user_id) DO UPDATE SET state_json = $2, updated_at = now()",
+/// 读取单个项目的对话、想法、PRD、任务与部署记录
+pub async fn load_project_rows(pool: &Pool, user_id: Uuid, project_id: &str) -> AppResult<StateStore> {
+    let mut conn = pool
+        .acquire()
+        .await
+        .map_err(|e| format!("获取数据库连接失败: {e}"))?;
+    let mut state = StateStore::default();
+    read_project_rows(&mut conn, user_id, project_id, &mut state).await?;
+    Ok(state)
+}
+
+/// 按事件逐行写入对应用户的状态并追加事件日志；
+/// user_state.revision 不等于 expected 时拒绝写入
+pub async fn apply_state_changes(
+    pool: &Pool,
+    user_id: Uuid,
+    expected: u64,
+    events: &[ProjectEvent],
+) -> AppResult<u64> {
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("开启事务失败: {e}"))?;
+    let actual = read_revision(&mut tx, user_id, true).await?;
+    if actual != expected {
+        return Err(StateConflict { expected, actual }.into());
+    }
+    let changes: Vec<StateChange> = events.iter().map(|e| e.change.clone()).collect();
+    write_changes(&mut tx, user_id, &changes).await?;
+    insert_events(&mut tx, user_id, events).await?;
+    let revision = bump_revision(&mut tx, user_id).await?;
+    tx.commit().await.map_err(|e| format!("提交事务失败: {e}"))?;
+    Ok(revision)
+}
+
+/// 删除项目的全部事件日志（彻底删除项目时调用）
+pub async fn delete_project_events(pool: &Pool, user_id: Uuid, project_id: &str) -> AppResult<()> {
+    sqlx::query("DELETE FROM project_events WHERE user_id = $1 AND project_id = $2")
+        .bind(user_id)
+        .bind(project_id)
+        .execute(pool)
+        .await
+        .map_err(|e| format!("删除事件日志失败: {e}"))?;
+    Ok(())
+}
+
+/// 按 seq 升序读取项目事件日志
+pub async fn load_project_events(pool: &Pool, user_id: Uuid, project_id: &str) -> AppResult<Vec<ProjectEvent>> {
+    let rows = sqlx::query(
+        "SELECT seq, project_id, payload, created_at FROM project_events
+         WHERE user_id = $1 AND project_id = $2
+         ORDER BY seq",
     )
     .bind(user_id)
-    .bind(json)
-    .execute(pool)
+    .bind(project_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取事件日志失败: {e}"))?;
+    let mut events = Vec::new();
+    for r in rows {
+        let payload: Json<Value> = r.get("payload");
+        events.push(ProjectEvent {
+            seq: r.get::<i64, _>("seq") as u64,
+            project_id: r.get("project_id"),
+            change: serde_json::from_value(payload.0).map_err(|e| format!("反序列化事件失败: {e}"))?,
+            created_at: timestamp(&r, "created_at"),
+        });
+    }
+    Ok(events)
+}
+
+/// 有软删除项目的用户，供定时清理过期项目
+pub async fn users_with_deleted_projects(pool: &Pool) -> AppResult<Vec<Uuid>> {
+    let users = sqlx::query_scalar::<_, Uuid>(
+        "SELECT DISTINCT user_id FROM projects WHERE status = 'DELETED'",
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取软删除项目失败: {e}"))?;
+    Ok(users)
+}
+
+/// 读取 user_state.revision；lock 为 true 时行锁住直到事务结束
+async fn read_revision(conn: &mut PgConnection, user_id: Uuid, lock: bool) -> AppResult<u64> {
+    if lock {
+        // 新用户没有需要迁移的旧数据，直接记为当前 schema 版本
+        sqlx::query(
+            "INSERT INTO user_state (user_id, schema_version) VALUES ($1, $2)
+             ON CONFLICT (user_id) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(schema::CURRENT_SCHEMA_VERSION as i32)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| format!("写入用户状态失败: {e}"))?;
+    }
+    let sql = if lock {
+        "SELECT revision FROM user_state WHERE user_id = $1 FOR UPDATE"
+    } else {
+        "SELECT revision FROM user_state WHERE user_id = $1"
+    };
+    let revision = sqlx::query_scalar::<_, i64>(sql)
+        .bind(user_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| format!("读取状态版本失败: {e}"))?
+        .unwrap_or(0);
+    Ok(revision as u64)
+}
+
+async fn bump_revision(conn: &mut PgConnection, user_id: Uuid) -> AppResult<u64> {
+    let revision = sqlx::query_scalar::<_, i64>(
+        "UPDATE user_state SET revision = revision + 1, updated_at = now()
+         WHERE user_id = $1 RETURNING revision",
+    )
+    .bind(user_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| format!("更新状态版本失败: {e}"))?;
+    Ok(revision as u64)
+}
+
+async fn read_index(conn: &mut PgConnection, user_id: Uuid, state: &mut StateStore) -> AppResult<()> {
+    let rows = sqlx::query(
+        "SELECT id, name, status, created_at, updated_at, deleted_at, settings FROM projects WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| format!("读取项目失败: {e}"))?;
+    for r in rows {
+        let project = Project {
+            id: r.get("id"),
+            name: r.get("name"),
+            status: from_text(r.get("status")),
+            created_at: timestamp(&r, "created_at"),
+            updated_at: timestamp(&r, "updated_at"),
+            deleted_at: r
+                .get::<Option<DateTime<Utc>>, _>("deleted_at")
+                .map(|t| t.to_rfc3339()),
+            settings: serde_json::from_value(r.get::<Json<Value>, _>("settings").0)
+                .unwrap_or_default(),
+        };
+        state.projects.insert(project.id.clone(), project);
+    }
+
+    let rows = sqlx::query(
+        "SELECT s.session_id, s.project_id, s.stage, s.context_snapshot, s.stage_history, s.requirements,
+                s.summary
+         FROM sessions s JOIN projects p ON p.id = s.project_id
+         WHERE p.user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| format!("读取会话失败: {e}"))?;
+    for r in rows {
+        let session = Session {
+            session_id: r.get("session_id"),
+            project_id: r.get("project_id"),
+            stage: from_text(r.get("stage")),
+            context_snapshot: r.get("context_snapshot"),
+            stage_history: serde_json::from_value(r.get::<Json<Value>, _>("stage_history").0)
+                .unwrap_or_default(),
+            requirements: serde_json::from_value(r.get::<Json<Value>, _>("requirements").0)
+                .unwrap_or_default(),
+            summary: serde_json::from_value(r.get::<Json<Value>, _>("summary").0)
+                .unwrap_or_default(),
+        };
+        state.sessions.insert(session.session_id.clone(), session);
+    }
+    Ok(())
+}
+
+async fn read_project_rows(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    project_id: &str,
+    state: &mut StateStore,
+) -> AppResult<()> {
+    let rows = sqlx::query(
+        "SELECT t.session_id, t.role, t.content, t.created_at, t.partial
+         FROM conversation_turns t
+         JOIN sessions s ON s.session_id = t.session_id
+         JOIN projects p ON p.id = s.project_id
+         WHERE p.user_id = $1 AND p.id = $2
+         ORDER BY t.id",
+    )
+    .bind(user_id)
+    .bind(project_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| format!("读取对话失败: {e}"))?;
+    state.conversation_turns.extend(rows.iter().map(|r| ConversationTurn {
+        session_id: r.get("session_id"),
+        role: r.get("role"),
+        content: r.get("content"),
+        created_at: timestamp(r, "created_at"),
+        partial: r.get("partial"),
+    }));
+
+    let rows = sqlx::query(
+        "SELECT e.event_id, e.session_id, e.user_input, e.created_at
+         FROM idea_events e
+         JOIN sessions s ON s.session_id = e.session_id
+         JOIN projects p ON p.id = s.project_id
+         WHERE p.user_id = $1 AND p.id = $2
+         ORDER BY e.seq",
+    )
+    .bind(user_id)
+    .bind(project_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| format!("读取想法记录失败: {e}"))?;
+    state.idea_events.extend(rows.iter().map(|r| IdeaEvent {
+        event_id: r.get("event_id"),
+        session_id: r.get("session_id"),
+        user_input: r.get("user_input"),
+        created_at: timestamp(r, "created_at"),
+    }));
+
+    let rows = sqlx::query(
+        "SELECT v.prd_id, v.project_id, v.version, v.content, v.diff_from_prev
+         FROM prd_versions v JOIN projects p ON p.id = v.project_id
+         WHERE p.user_id = $1 AND p.id = $2
+         ORDER BY v.version",
+    )
+    .bind(user_id)
+    .bind(project_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| format!("读取 PRD 失败: {e}"))?;
+    state.prd_versions.extend(rows.iter().map(|r| PrdVersion {
+        prd_id: r.get("prd_id"),
+        project_id: r.get("project_id"),
+        version: r.get::<i32, _>("version") as u32,
+        content: r.get("content"),
+        diff_from_prev: r.get("diff_from_prev"),
+    }));
+
+    let rows = sqlx::query(
+        "SELECT t.task_id, t.project_id, t.plan_json, t.run_status, t.logs
+         FROM task_runs t JOIN projects p ON p.id = t.project_id
+         WHERE p.user_id = $1 AND p.id = $2
+         ORDER BY t.seq",
+    )
+    .bind(user_id)
+    .bind(project_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| format!("读取任务记录失败: {e}"))?;
+    state.task_runs.extend(rows.iter().map(|r| TaskRun {
+        task_id: r.get("task_id"),
+        project_id: r.get("project_id"),
+        plan_json: r.get("plan_json"),
+        run_status: r.get("run_status"),
+        logs: r.get("logs"),
+    }));
+
+    let rows = sqlx::query(
+        "SELECT d.deploy_id, d.project_id, d.env, d.version, d.result, d.rollback_hint
+         FROM deployment_runs d JOIN projects p ON p.id = d.project_id
+         WHERE p.user_id = $1 AND p.id = $2
+         ORDER BY d.seq",
+    )
+    .bind(user_id)
+    .bind(project_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| format!("读取部署记录失败: {e}"))?;
+    state.deployment_runs.extend(rows.iter().map(|r| DeploymentRun {
+        deploy_id: r.get("deploy_id"),
+        project_id: r.get("project_id"),
+        env: r.get("env"),
+        version: r.get("version"),
+        result: r.get("result"),
+        rollback_hint: r.get("rollback_hint"),
+    }));
+
+    let rows = sqlx::query(
+        "SELECT i.iteration_id, i.project_id, i.session_id, i.number, i.idea_event_ids, i.prd_version,
+                i.task_ids, i.test_result, i.deploy_id, i.status, i.opened_at, i.delivered_at
+         FROM iterations i JOIN projects p ON p.id = i.project_id
+         WHERE p.user_id = $1 AND p.id = $2
+         ORDER BY i.number",
+    )
+    .bind(user_id)
+    .bind(project_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| format!("读取迭代记录失败: {e}"))?;
+    state.iterations.extend(rows.iter().map(|r| Iteration {
+        iteration_id: r.get("iteration_id"),
+        project_id: r.get("project_id"),
+        session_id: r.get("session_id"),
+        number: r.get::<i32, _>("number") as u32,
+        idea_event_ids: serde_json::from_value(r.get::<Json<Value>, _>("idea_event_ids").0)
+            .unwrap_or_default(),
+        prd_version: r.get::<Option<i32>, _>("prd_version").map(|v| v as u32),
+        task_ids: serde_json::from_value(r.get::<Json<Value>, _>("task_ids").0).unwrap_or_default(),
+        test_result: r
+            .get::<Option<Json<Value>>, _>("test_result")
+            .and_then(|v| serde_json::from_value(v.0).ok()),
+        deploy_id: r.get("deploy_id"),
+        status: from_text(r.get("status")),
+        opened_at: timestamp(r, "opened_at"),
+        delivered_at: r
+            .get::<Option<DateTime<Utc>>, _>("delivered_at")
+            .map(|t| t.to_rfc3339()),
+    }));
+
+    let rows = sqlx::query(
+        "SELECT usage_id, project_id, session_id, role, model, prompt_tokens, completion_tokens,
+                cost, estimated, created_at
+         FROM llm_usage
+         WHERE user_id = $1 AND project_id = $2
+         ORDER BY created_at",
+    )
+    .bind(user_id)
+    .bind(project_id)
+    .fetch_all(&mut *conn)
     .await
-    .map_err(|e| format!("写入用户状态失败: {e}"))?;
+    .map_err(|e| format!("读取用量记录失败: {e}"))?;
+    state.usage.extend(rows.iter().map(|r| UsageRecord {
+        usage_id: r.get("usage_id"),
+        project_id: r.get("project_id"),
+        session_id: r.get("session_id"),
+        role: from_text(r.get("role")),
+        model: r.get("model"),
+        prompt_tokens: r.get::<i64, _>("prompt_tokens") as u64,
+        completion_tokens: r.get::<i64, _>("completion_tokens") as u64,
+        cost: r.get("cost"),
+        estimated: r.get("estimated"),
+        created_at: timestamp(r, "created_at"),
+    }));
+    Ok(())
+}
+
+async fn insert_events(conn: &mut PgConnection, user_id: Uuid, events: &[ProjectEvent]) -> AppResult<()> {
+    for event in events {
+        let payload = serde_json::to_value(&event.change).map_err(|e| format!("序列化事件失败: {e}"))?;
+        let event_type = payload["type"].as_str().unwrap_or_default().to_string();
+        sqlx::query(
+            "INSERT INTO project_events (user_id, project_id, event_type, payload, created_at)
+             VALUES ($1, $2, $3, $4, $5::timestamptz)",
+        )
+        .bind(user_id)
+        .bind(&event.project_id)
+        .bind(event_type)
+        .bind(payload)
+        .bind(&event.created_at)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| format!("写入事件日志失败: {e}"))?;
+    }
+    Ok(())
+}
+
+async fn write_changes(conn: &mut PgConnection, user_id: Uuid, changes: &[StateChange]) -> AppResult<()> {
+    for change in changes {
+        match change {
+            StateChange::ProjectCreated(p) => {
+                sqlx::query(
+                    "INSERT INTO projects (id, user_id, name, status, created_at, updated_at, deleted_at, settings)
+                     VALUES ($1, $2, $3, $4, $5::timestamptz, $6::timestamptz, $7::timestamptz, $8)
+                     ON CONFLICT (id) DO NOTHING",
+                )
+                .bind(&p.id)
+                .bind(user_id)
+                .bind(&p.name)
+                .bind(to_text(&p.status))
+                .bind(&p.created_at)
+                .bind(&p.updated_at)
+                .bind(&p.deleted_at)
+                .bind(Json(&p.settings))
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入项目失败: {e}"))?;
+            }
+            StateChange::SessionCreated(s) => {
+                sqlx::query(
+                    "INSERT INTO sessions (session_id, project_id, stage, context_snapshot, stage_history, requirements, summary)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7)
+                     ON CONFLICT (session_id) DO NOTHING",
+                )
+                .bind(&s.session_id)
+                .bind(&s.project_id)
+                .bind(to_text(&s.stage))
+                .bind(&s.context_snapshot)
+                .bind(Json(&s.stage_history))
+                .bind(Json(&s.requirements))
+                .bind(Json(&s.summary))
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入会话失败: {e}"))?;
+            }
+            StateChange::TurnAppended(t) => {
+                sqlx::query(
+                    "INSERT INTO conversation_turns (session_id, role, content, created_at, partial)
+                     VALUES ($1, $2, $3, $4::timestamptz, $5)",
+                )
+                .bind(&t.session_id)
+                .bind(&t.role)
+                .bind(&t.content)
+                .bind(&t.created_at)
+                .bind(t.partial)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入对话失败: {e}"))?;
+            }
+            StateChange::IdeaAppended(e) => {
+                sqlx::query(
+                    "INSERT INTO idea_events (event_id, session_id, user_input, created_at)
+                     VALUES ($1, $2, $3, $4::timestamptz)
+                     ON CONFLICT (event_id) DO NOTHING",
+                )
+                .bind(&e.event_id)
+                .bind(&e.session_id)
+                .bind(&e.user_input)
+                .bind(&e.created_at)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入想法记录失败: {e}"))?;
+            }
+            StateChange::PrdGenerated(v) => {
+                sqlx::query(
+                    "INSERT INTO prd_versions (prd_id, project_id, version, content, diff_from_prev)
+                     VALUES ($1, $2, $3, $4, $5)
+                     ON CONFLICT (prd_id) DO NOTHING",
+                )
+                .bind(&v.prd_id)
+                .bind(&v.project_id)
+                .bind(v.version as i32)
+                .bind(&v.content)
+                .bind(&v.diff_from_prev)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入 PRD 失败: {e}"))?;
+            }
+            StateChange::TaskRecorded(t) => {
+                sqlx::query(
+                    "INSERT INTO task_runs (task_id, project_id, plan_json, run_status, logs)
+                     VALUES ($1, $2, $3, $4, $5)
+                     ON CONFLICT (task_id) DO UPDATE SET run_status = $4, logs = $5",
+                )
+                .bind(&t.task_id)
+                .bind(&t.project_id)
+                .bind(&t.plan_json)
+                .bind(&t.run_status)
+                .bind(&t.logs)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入任务记录失败: {e}"))?;
+            }
+            StateChange::DeploymentRecorded(d) => {
+                sqlx::query(
+                    "INSERT INTO deployment_runs (deploy_id, project_id, env, version, result, rollback_hint)
+                     VALUES ($1, $2, $3, $4, $5, $6)
+                     ON CONFLICT (deploy_id) DO NOTHING",
+                )
+                .bind(&d.deploy_id)
+                .bind(&d.project_id)
+                .bind(&d.env)
+                .bind(&d.version)
+                .bind(&d.result)
+                .bind(&d.rollback_hint)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入部署记录失败: {e}"))?;
+            }
+            StateChange::IterationOpened(i) | StateChange::IterationUpdated(i) => {
+                sqlx::query(
+                    "INSERT INTO iterations (iteration_id, project_id, session_id, number, idea_event_ids,
+                         prd_version, task_ids, deploy_id, status, opened_at, delivered_at, test_result)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10::timestamptz, $11::timestamptz, $12)
+                     ON CONFLICT (iteration_id) DO UPDATE SET
+                         idea_event_ids = $5, prd_version = $6, task_ids = $7, deploy_id = $8,
+                         status = $9, delivered_at = $11::timestamptz, test_result = $12",
+                )
+                .bind(&i.iteration_id)
+                .bind(&i.project_id)
+                .bind(&i.session_id)
+                .bind(i.number as i32)
+                .bind(Json(&i.idea_event_ids))
+                .bind(i.prd_version.map(|v| v as i32))
+                .bind(Json(&i.task_ids))
+                .bind(&i.deploy_id)
+                .bind(to_text(&i.status))
+                .bind(&i.opened_at)
+                .bind(&i.delivered_at)
+                .bind(i.test_result.as_ref().map(Json))
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入迭代记录失败: {e}"))?;
+            }
+            StateChange::UsageRecorded(u) => {
+                sqlx::query(
+                    "INSERT INTO llm_usage (usage_id, user_id, project_id, session_id, role, model,
+                         prompt_tokens, completion_tokens, cost, estimated, created_at)
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11::timestamptz)
+                     ON CONFLICT (usage_id) DO NOTHING",
+                )
+                .bind(&u.usage_id)
+                .bind(user_id)
+                .bind(&u.project_id)
+                .bind(&u.session_id)
+                .bind(to_text(&u.role))
+                .bind(&u.model)
+                .bind(u.prompt_tokens as i64)
+                .bind(u.completion_tokens as i64)
+                .bind(u.cost)
+                .bind(u.estimated)
+                .bind(&u.created_at)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("写入用量记录失败: {e}"))?;
+            }
+            StateChange::StageChanged {
+                session_id,
+                stage,
+                context_snapshot,
+                changed_at,
+            } => {
+                // SET 中的 stage 取的是更新前的值，与 StateChange::apply 的历史规则一致
+                sqlx::query(
+                    "UPDATE sessions SET stage = $2, context_snapshot = $3,
+                         stage_history = CASE WHEN stage = $2 THEN stage_history
+                             ELSE stage_history || jsonb_build_array(jsonb_build_object(
+                                 'from', stage, 'to', $2::text, 'at', $5::text, 'reason', $3::text))
+                         END
+                     WHERE session_id = $1
+                       AND project_id IN (SELECT id FROM projects WHERE user_id = $4)",
+                )
+                .bind(session_id)
+                .bind(to_text(stage))
+                .bind(context_snapshot)
+                .bind(user_id)
+                .bind(changed_at)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("更新会话阶段失败: {e}"))?;
+            }
+            StateChange::RequirementsUpdated {
+                session_id,
+                requirements,
+            } => {
+                sqlx::query(
+                    "UPDATE sessions SET requirements = $2
+                     WHERE session_id = $1
+                       AND project_id IN (SELECT id FROM projects WHERE user_id = $3)",
+                )
+                .bind(session_id)
+                .bind(Json(requirements))
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("更新需求清单失败: {e}"))?;
+            }
+            StateChange::SummaryUpdated {
+                session_id,
+                summary,
+            } => {
+                sqlx::query(
+                    "UPDATE sessions SET summary = $2
+                     WHERE session_id = $1
+                       AND project_id IN (SELECT id FROM projects WHERE user_id = $3)",
+                )
+                .bind(session_id)
+                .bind(Json(summary))
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("更新对话摘要失败: {e}"))?;
+            }
+            StateChange::ProjectTouched {
+                project_id,
+                updated_at,
+            } => {
+                sqlx::query(
+                    "UPDATE projects SET updated_at = $2::timestamptz WHERE id = $1 AND user_id = $3",
+                )
+                .bind(project_id)
+                .bind(updated_at)
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("更新项目时间失败: {e}"))?;
+            }
+            StateChange::ProjectStatusChanged {
+                project_id,
+                status,
+                deleted_at,
+                updated_at,
+            } => {
+                sqlx::query(
+                    "UPDATE projects SET status = $2, deleted_at = $3::timestamptz, updated_at = $4::timestamptz
+                     WHERE id = $1 AND user_id = $5",
+                )
+                .bind(project_id)
+                .bind(to_text(status))
+                .bind(deleted_at)
+                .bind(updated_at)
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("更新项目状态失败: {e}"))?;
+            }
+            StateChange::ProjectSettingsChanged {
+                project_id,
+                settings,
+                updated_at,
+            } => {
+                sqlx::query(
+                    "UPDATE projects SET settings = $2, updated_at = $3::timestamptz
+                     WHERE id = $1 AND user_id = $4",
+                )
+                .bind(project_id)
+                .bind(Json(settings))
+                .bind(updated_at)
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| format!("更新项目设置失败: {e}"))?;
+            }
+            StateChange::ProjectPurged { project_id } => {
+                // 会话、对话、PRD、任务与部署记录通过外键级联删除
+                sqlx::query("DELETE FROM projects WHERE id = $1 AND user_id = $2")
+                    .bind(project_id)
+                    .bind(user_id)
+                    .execute(&mut *conn)
+                    .await
+                    .map_err(|e| format!("删除项目失败: {e}"))?;
+            }
+            // 快照与各行的现有内容一致，只需记入事件日志
+            StateChange::ProjectSnapshot { .. } => {}
+        }
+    }
     Ok(())
 }
 
-/// 获取全局系统设置
+fn timestamp(row: &PgRow, column: &str) -> String {
+    row.get::<DateTime<Utc>, _>(column).to_rfc3339()
+}
+
+/// 枚举以其 serde 名称（如 "CLARIFYING"）存为 TEXT
+fn to_text<T: Serialize>(value: &T) -> String {
+    serde_json::to_value(value)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+fn from_text<T: DeserializeOwned + Default>(text: String) -> T {
+    serde_json::from_value(Value::String(text)).unwrap_or_default()
+}
+
+/// 新建设置时按名称判断是否为密钥：各类 API Key 与包含 Key 的备用模型列表
+pub fn is_secret_setting(key: &str) -> bool {
+    key.ends_with("API_KEY") || key == "CELADON_LLM_FALLBACKS"
+}
+
+/// 获取全局系统设置；密钥类设置解密后返回
 pub async fn get_system_setting(pool: &Pool, key: &str) -> AppResult<Option<String>> {
-    let row = sqlx::query("SELECT value FROM system_settings WHERE key = $1")
+    let row = sqlx::query("SELECT value, data_key FROM system_settings WHERE key = $1")
         .bind(key)
         .fetch_optional(pool)
         .await
         .map_err(|e| format!("读取系统设置失败: {e}"))?;
-    Ok(row.map(|r| r.get("value")))
+    let Some(row) = row else { return Ok(None) };
+    let value: String = row.get("value");
+    match row.get::<Option<String>, _>("data_key") {
+        Some(data_key) => Ok(Some(Keyring::from_env()?.open(&value, &data_key)?)),
+        None => Ok(Some(value)),
+    }
 }
 
-/// 更新或创建全局系统设置
-pub async fn set_system_setting(pool: &Pool, key: &str, value: &str) -> AppResult<()> {
+/// 更新或创建全局系统设置。secret 为 None 时沿用已有标记，新设置按名称判断；
+/// 密钥类设置需要配置 CELADON_SETTINGS_KEK，值加密后保存
+pub async fn set_system_setting(
+    pool: &Pool,
+    key: &str,
+    value: &str,
+    secret: Option<bool>,
+) -> AppResult<()> {
+    let secret = match secret {
+        Some(secret) => secret,
+        None => sqlx::query_scalar::<_, bool>("SELECT secret FROM system_settings WHERE key = $1")
+            .bind(key)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("读取系统设置失败: {e}"))?
+            .unwrap_or_else(|| is_secret_setting(key)),
+    };
+    let (value, data_key) = if secret && !value.is_empty() {
+        let sealed = Keyring::from_env()?.seal(value)?;
+        (sealed.value, Some(sealed.data_key))
+    } else {
+        (value.to_string(), None)
+    };
     sqlx::query(
-        "INSERT INTO system_settings (key, value, updated_at) VALUES ($1, $2, now())
-         ON CONFLICT (key) DO UPDATE SET value = $2, updated_at = now()",
+        "INSERT INTO system_settings (key, value, secret, data_key, updated_at) VALUES ($1, $2, $3, $4, now())
+         ON CONFLICT (key) DO UPDATE SET value = $2, secret = $3, data_key = $4, updated_at = now()",
     )
     .bind(key)
     .bind(value)
+    .bind(secret)
+    .bind(data_key)
     .execute(pool)
     .await
     .map_err(|e| format!("执行系统设置更新失败: {e}"))?;
     Ok(())
 }
 
-/// 获取所有系统设置
+/// 获取所有系统设置；密钥类设置只返回掩码，无法解密时 readable 为 false
 pub async fn list_system_settings(pool: &Pool) -> AppResult<Value> {
-    let rows = sqlx::query("SELECT key, value, description FROM system_settings")
+    let rows = sqlx::query("SELECT key, value, description, secret, data_key FROM system_settings ORDER BY key")
         .fetch_all(pool)
         .await
         .map_err(|e| format!("读取所有系统设置失败: {e}"))?;
+    let keyring = Keyring::from_env().ok();
 
     let mut result = Vec::new();
     for row in rows {
+        let value: String = row.get("value");
+        let secret: bool = row.get("secret");
+        let plain = match row.get::<Option<String>, _>("data_key") {
+            Some(data_key) => keyring.as_ref().and_then(|k| k.open(&value, &data_key).ok()),
+            None => Some(value),
+        };
+        let value = match (&plain, secret) {
+            (Some(v), true) if v.is_empty() => String::new(),
+            (Some(v), true) => crypto::mask(v),
+            (Some(v), false) => v.clone(),
+            (None, _) => "…".to_string(),
+        };
         result.push(json!({
             "key": row.get::<String, _>("key"),
-            "value": row.get::<String, _>("value"),
+            "value": value,
+            "secret": secret,
+            "readable": plain.is_some(),
             "description": row.get::<Option<String>, _>("description"),
         }));
     }
     Ok(Value::Array(result))
 }
+
+/// 加密仍为明文的密钥类设置，并把旧 KEK 加密的数据密钥改用当前 KEK；
+/// 返回加密与重新加密的条数。解不开的行跳过并记日志，不影响其他行
+pub async fn seal_secret_settings(pool: &Pool) -> AppResult<(u64, u64)> {
+    let keyring = Keyring::from_env()?;
+    let rows = sqlx::query("SELECT key, value, data_key FROM system_settings WHERE secret AND value <> ''")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| format!("读取系统设置失败: {e}"))?;
+    let (mut sealed, mut rewrapped) = (0, 0);
+    for row in rows {
+        let key: String = row.get("key");
+        let value: String = row.get("value");
+        let update = match row.get::<Option<String>, _>("data_key") {
+            None => keyring.seal(&value).map(|s| Some((s.value, s.data_key))),
+            Some(data_key) => keyring.rewrap(&data_key).map(|d| d.map(|d| (value, d))),
+        };
+        let (value, data_key) = match update {
+            Ok(Some(update)) => update,
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::warn!("系统设置 {key} 无法加密或解密，已跳过: {e}");
+                continue;
+            }
+        };
+        let was_plain = row.get::<Option<String>, _>("data_key").is_none();
+        sqlx::query("UPDATE system_settings SET value = $2, data_key = $3 WHERE key = $1")
+            .bind(&key)
+            .bind(value)
+            .bind(data_key)
+            .execute(pool)
+            .await
+            .map_err(|e| format!("加密系统设置 {key} 失败: {e}"))?;
+        if was_plain {
+            sealed += 1;
+        } else {
+            rewrapped += 1;
+        }
+    }
+    Ok((sealed, rewrapped))
+}
+
+/// 按项目、角色与模型汇总用量；user_id 为 None 时汇总所有用户并带上用户邮箱
+pub async fn usage_rows(
+    pool: &Pool,
+    user_id: Option<Uuid>,
+    project_id: Option<&str>,
+) -> AppResult<Vec<UsageRow>> {
+    let rows = sqlx::query(
+        "SELECT u.email, l.project_id, l.role, l.model, COUNT(*) AS calls,
+                SUM(l.prompt_tokens)::BIGINT AS prompt_tokens,
+                SUM(l.completion_tokens)::BIGINT AS completion_tokens,
+                SUM(l.cost) AS cost
+         FROM llm_usage l JOIN users u ON u.id = l.user_id
+         WHERE ($1::uuid IS NULL OR l.user_id = $1)
+           AND ($2::text IS NULL OR l.project_id = $2)
+         GROUP BY u.email, l.project_id, l.role, l.model
+         ORDER BY u.email, l.project_id, l.role, l.model",
+    )
+    .bind(user_id)
+    .bind(project_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取用量失败: {e}"))?;
+    Ok(rows
+        .into_iter()
+        .map(|r| UsageRow {
+            user: user_id.is_none().then(|| r.get("email")),
+            project_id: r.get("project_id"),
+            role: from_text(r.get("role")),
+            model: r.get("model"),
+            calls: r.get::<i64, _>("calls") as u64,
+            prompt_tokens: r.get::<i64, _>("prompt_tokens") as u64,
+            completion_tokens: r.get::<i64, _>("completion_tokens") as u64,
+            cost: r.get("cost"),
+        })
+        .collect())
+}
+
+/// 用户自 since 起的用量合计
+pub async fn user_spend(pool: &Pool, user_id: Uuid, since: DateTime<Utc>) -> AppResult<Spend> {
+    let row = sqlx::query(
+        "SELECT COALESCE(SUM(prompt_tokens + completion_tokens), 0)::BIGINT AS tokens,
+                COALESCE(SUM(cost), 0) AS cost
+         FROM llm_usage WHERE user_id = $1 AND created_at >= $2",
+    )
+    .bind(user_id)
+    .bind(since)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| format!("读取用量失败: {e}"))?;
+    Ok(Spend {
+        tokens: row.get::<i64, _>("tokens") as u64,
+        cost: row.get("cost"),
+    })
+}
+
+pub async fn get_user_budget(pool: &Pool, user_id: Uuid) -> AppResult<Budget> {
+    let row = sqlx::query("SELECT max_tokens, max_cost FROM user_budgets WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| format!("读取用户预算失败: {e}"))?;
+    Ok(row.map(|r| budget_from_row(&r)).unwrap_or_default())
+}
+
+pub async fn set_user_budget(pool: &Pool, user_id: Uuid, budget: Budget) -> AppResult<()> {
+    sqlx::query(
+        "INSERT INTO user_budgets (user_id, max_tokens, max_cost, updated_at) VALUES ($1, $2, $3, now())
+         ON CONFLICT (user_id) DO UPDATE SET max_tokens = $2, max_cost = $3, updated_at = now()",
+    )
+    .bind(user_id)
+    .bind(budget.max_tokens.map(|v| v as i64))
+    .bind(budget.max_cost)
+    .execute(pool)
+    .await
+    .map_err(|e| format!("保存用户预算失败: {e}"))?;
+    Ok(())
+}
+
+/// 所有用户的预算与自 since 起的用量
+pub async fn list_user_budgets(pool: &Pool, since: DateTime<Utc>) -> AppResult<Value> {
+    let rows = sqlx::query(
+        "SELECT u.id, u.email, b.max_tokens, b.max_cost,
+                COALESCE(SUM(l.prompt_tokens + l.completion_tokens), 0)::BIGINT AS tokens,
+                COALESCE(SUM(l.cost), 0) AS cost
+         FROM users u
+         LEFT JOIN user_budgets b ON b.user_id = u.id
+         LEFT JOIN llm_usage l ON l.user_id = u.id AND l.created_at >= $1
+         GROUP BY u.id, u.email, b.max_tokens, b.max_cost
+         ORDER BY u.email",
+    )
+    .bind(since)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取用户预算失败: {e}"))?;
+    Ok(Value::Array(
+        rows.iter()
+            .map(|r| {
+                json!({
+                    "user_id": r.get::<Uuid, _>("id").to_string(),
+                    "email": r.get::<String, _>("email"),
+                    "budget": budget_from_row(r),
+                    "spent": Spend {
+                        tokens: r.get::<i64, _>("tokens") as u64,
+                        cost: r.get("cost"),
+                    }
+                })
+            })
+            .collect(),
+    ))
+}
+
+fn budget_from_row(row: &PgRow) -> Budget {
+    Budget {
+        max_tokens: row.get::<Option<i64>, _>("max_tokens").map(|v| v as u64),
+        max_cost: row.get("max_cost"),
+    }
+}
+
+/// 用户自带的一组模型配置，api_key 为密文
+#[derive(Debug, Clone)]
+pub struct UserLlmKey {
+    pub role: UsageRole,
+    pub provider: String,
+    pub model: String,
+    pub api_key: String,
+    pub updated_at: String,
+}
+
+pub async fn list_user_llm_keys(pool: &Pool, user_id: Uuid) -> AppResult<Vec<UserLlmKey>> {
+    let rows = sqlx::query(
+        "SELECT role, provider, model, api_key, updated_at FROM user_llm_keys
+         WHERE user_id = $1 ORDER BY role",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("读取用户模型 Key 失败: {e}"))?;
+    Ok(rows
+        .iter()
+        .map(|r| UserLlmKey {
+            role: from_text(r.get("role")),
+            provider: r.get("provider"),
+            model: r.get("model"),
+            api_key: r.get("api_key"),
+            updated_at: timestamp(r, "updated_at"),
+        })
+        .collect())
+}
+
+pub async fn set_user_llm_key(pool: &Pool, user_id: Uuid, key: &UserLlmKey) -> AppResult<()> {
+    sqlx::query(
+        "INSERT INTO user_llm_keys (user_id, role, provider, model, api_key, updated_at)
+         VALUES ($1, $2, $3, $4, $5, now())
+         ON CONFLICT (user_id, role) DO UPDATE
+         SET provider = $3, model = $4, api_key = $5, updated_at = now()",
+    )
+    .bind(user_id)
+    .bind(to_text(&key.role))
+    .bind(&key.provider)
+    .bind(&key.model)
+    .bind(&key.api_key)
+    .execute(pool)
+    .await
+    .map_err(|e| format!("保存用户模型 Key 失败: {e}"))?;
+    Ok(())
+}
+
+/// 返回是否删除了记录
+pub async fn delete_user_llm_key(pool: &Pool, user_id: Uuid, role: UsageRole) -> AppResult<bool> {
+    let result = sqlx::query("DELETE FROM user_llm_keys WHERE user_id = $1 AND role = $2")
+        .bind(user_id)
+        .bind(to_text(&role))
+        .execute(pool)
+        .await
+        .map_err(|e| format!("删除用户模型 Key 失败: {e}"))?;
+    Ok(result.rows_affected() > 0)
+}
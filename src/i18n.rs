@@ -0,0 +1,310 @@
+//! 多语言：界面/错误文案目录与提示词语言。
+//!
+//! 每个 HTTP 请求的语言由中间件确定（用户设置 → Accept-Language → CELADON_LOCALE），
+//! 保存在任务局部变量中；`Message` 在格式化时按当前语言取文案，因此服务层照常返回
+//! 错误，ApiError 输出时即为请求方的语言。命令行与后台任务使用 CELADON_LOCALE。
+
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fmt;
+use std::future::Future;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    Zh,
+    En,
+}
+
+impl Locale {
+    pub fn code(self) -> &'static str {
+        match self {
+            Locale::Zh => "zh",
+            Locale::En => "en",
+        }
+    }
+
+    /// 接受 zh、zh-CN、en-US 等语言标签
+    pub fn parse(tag: &str) -> Option<Self> {
+        let primary = tag.trim().split(['-', '_']).next()?.to_lowercase();
+        match primary.as_str() {
+            "zh" => Some(Locale::Zh),
+            "en" => Some(Locale::En),
+            _ => None,
+        }
+    }
+
+    /// 按 q 值从 Accept-Language 中选出第一个支持的语言
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut candidates: Vec<(f32, Locale)> = header
+            .split(',')
+            .filter_map(|part| {
+                let mut pieces = part.split(';');
+                let locale = Locale::parse(pieces.next()?)?;
+                let quality = pieces
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                Some((quality, locale))
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0));
+        candidates.first().map(|(_, locale)| *locale)
+    }
+}
+
+/// 未指定时的默认语言：CELADON_LOCALE，缺省为中文
+pub fn default_locale() -> Locale {
+    std::env::var("CELADON_LOCALE")
+        .ok()
+        .and_then(|v| Locale::parse(&v))
+        .unwrap_or_default()
+}
+
+tokio::task_local! {
+    static CURRENT: Cell<Locale>;
+}
+
+pub fn current() -> Locale {
+    CURRENT.try_with(Cell::get).unwrap_or_else(|_| default_locale())
+}
+
+/// 在 future 内把当前语言设为 locale
+pub async fn scope<F: Future>(locale: Locale, future: F) -> F::Output {
+    CURRENT.scope(Cell::new(locale), future).await
+}
+
+/// 在已有作用域内改写当前语言（如登录用户的语言设置）
+pub fn set_current(locale: Locale) {
+    let _ = CURRENT.try_with(|cell| cell.set(locale));
+}
+
+/// 文案目录：(key, 中文, English)，`{name}` 为参数占位
+const CATALOGUE: &[(&str, &str, &str)] = &[
+    ("session_not_found", "会话不存在: {id}", "Session not found: {id}"),
+    ("project_not_found", "项目不存在: {id}", "Project not found: {id}"),
+    ("project_already_in_status", "项目已处于 {status} 状态", "Project is already {status}"),
+    ("restore_before_archive", "已删除的项目需先恢复再归档", "Restore a deleted project before archiving it"),
+    (
+        "restore_window_expired",
+        "项目已超过 {days} 天恢复期，无法恢复",
+        "The {days}-day restore window has passed; the project can no longer be restored",
+    ),
+    ("project_not_active", "项目处于 {status} 状态，请先恢复", "Project is {status}; restore it first"),
+    ("empty_search_query", "搜索关键词不能为空", "Search query must not be empty"),
+    ("bundle_unknown_session", "项目包中引用了不存在的会话: {id}", "Bundle references an unknown session: {id}"),
+    ("bundle_too_many_entries", "导入包条目超过 {max} 个", "The bundle has more than {max} entries"),
+    ("bundle_too_large", "导入包解压后超过 {max} 字节", "The bundle unpacks to more than {max} bytes"),
+    ("bundle_unsafe_path", "导入包中包含非法路径: {path}", "The bundle contains an unsafe path: {path}"),
+    ("bundle_invalid_manifest", "manifest.json 无效: {error}", "Invalid manifest.json: {error}"),
+    ("bundle_missing_manifest", "导入包缺少 manifest.json", "The bundle has no manifest.json"),
+    ("bundle_wrong_format", "不是 Celadon 项目包: {format}", "Not a Celadon project bundle: {format}"),
+    (
+        "bundle_version_unsupported",
+        "项目包版本 {version} 高于当前支持的 {supported}",
+        "Bundle version {version} is newer than the supported {supported}",
+    ),
+    ("empty_prompt", "提示词内容不能为空", "Prompt content must not be empty"),
+    ("unknown_prompt", "未知提示词: {name}", "Unknown prompt: {name}"),
+    ("database_disabled", "数据库未启用", "The database is not enabled"),
+    ("login_required", "需要登录", "Login required"),
+    ("forbidden", "权限不足", "Permission denied"),
+    ("users_disabled", "未启用用户系统", "User accounts are not enabled"),
+    ("admin_not_configured", "未配置管理员邮箱", "No admin email is configured"),
+    ("invalid_token", "token 格式无效", "Invalid token format"),
+    ("token_expired", "无效或过期的登录状态，请重新登录", "Invalid or expired session, please log in again"),
+    ("no_active_stream", "会话 {id} 没有进行中的输出流", "No active stream for session {id}"),
+    ("invalid_credentials", "邮箱或密码错误", "Incorrect email or password"),
+    ("email_taken", "邮箱已被注册", "This email is already registered"),
+    ("user_not_found", "用户不存在", "User not found"),
+    ("password_hash_failed", "密码哈希失败: {error}", "Failed to hash the password: {error}"),
+    ("register_failed", "注册失败: {error}", "Registration failed: {error}"),
+    ("login_query_failed", "登录查询失败: {error}", "Login lookup failed: {error}"),
+    ("user_query_failed", "查询用户失败: {error}", "Failed to look up the user: {error}"),
+    ("token_write_failed", "写入 token 失败: {error}", "Failed to store the token: {error}"),
+    ("token_check_failed", "token 校验失败: {error}", "Failed to verify the token: {error}"),
+    ("logout_failed", "登出失败: {error}", "Logout failed: {error}"),
+    ("locale_update_failed", "更新用户语言失败: {error}", "Failed to update the user's locale: {error}"),
+    ("unsupported_locale", "不支持的语言: {locale}", "Unsupported locale: {locale}"),
+    (
+        "state_backend_needs_database",
+        "postgres 状态后端需要 DATABASE_URL 与登录用户",
+        "The postgres state backend needs DATABASE_URL and a logged-in user",
+    ),
+    ("state_backend_unknown", "未知的状态后端: {backend}", "Unknown state backend: {backend}"),
+    (
+        "state_file_unrecoverable",
+        "state.json 已损坏且没有可用备份: {error}",
+        "state.json is corrupt and no usable backup exists: {error}",
+    ),
+    ("state_encode_failed", "序列化状态失败: {error}", "Failed to serialize the state: {error}"),
+    ("state_decode_failed", "反序列化状态失败: {error}", "Failed to deserialize the state: {error}"),
+    ("event_encode_failed", "序列化事件失败: {error}", "Failed to serialize an event: {error}"),
+    ("event_decode_failed", "反序列化事件失败: {error}", "Failed to deserialize an event: {error}"),
+    ("sqlite_open_failed", "打开 SQLite 失败: {error}", "Failed to open SQLite: {error}"),
+    ("sqlite_init_failed", "初始化 SQLite 失败: {error}", "Failed to initialize SQLite: {error}"),
+    ("sqlite_read_state_failed", "读取 SQLite 状态失败: {error}", "Failed to read the state from SQLite: {error}"),
+    ("sqlite_write_state_failed", "写入 SQLite 状态失败: {error}", "Failed to write the state to SQLite: {error}"),
+    ("sqlite_read_events_failed", "读取 SQLite 事件失败: {error}", "Failed to read events from SQLite: {error}"),
+    ("sqlite_write_events_failed", "写入 SQLite 事件失败: {error}", "Failed to write events to SQLite: {error}"),
+    ("sqlite_delete_events_failed", "删除 SQLite 事件失败: {error}", "Failed to delete events from SQLite: {error}"),
+    ("sqlite_begin_failed", "开启 SQLite 事务失败: {error}", "Failed to begin a SQLite transaction: {error}"),
+    ("sqlite_lock_failed", "锁定 SQLite 状态失败: {error}", "Failed to lock the SQLite state: {error}"),
+    ("sqlite_commit_failed", "提交 SQLite 事务失败: {error}", "Failed to commit the SQLite transaction: {error}"),
+    (
+        "state_conflict",
+        "状态已被其他请求修改（期望 revision {expected}，实际 {actual}），请刷新后重试",
+        "State was modified by another request (expected revision {expected}, found {actual}); reload and retry",
+    ),
+    (
+        "stage_illegal",
+        "当前阶段 {from} 不能执行「{action}」（无法进入 {to}）",
+        "Cannot {action} in stage {from} (cannot move to {to})",
+    ),
+    ("stage_missing", "执行「{action}」前需要先{missing}", "You need to {missing} before you can {action}"),
+    ("action_append_idea", "追加想法", "add an idea"),
+    ("action_generate_prd", "生成 PRD", "generate the PRD"),
+    ("action_run_dev", "开发", "run development"),
+    ("action_start_tests", "开始测试", "start testing"),
+    ("action_record_test_result", "提交测试结果", "record a test result"),
+    ("action_deploy", "部署", "deploy"),
+    ("prereq_clarify", "完成至少一轮需求澄清", "complete at least one clarification round"),
+    ("prereq_prd", "生成 PRD", "generate a PRD"),
+    ("prereq_dev", "完成一次开发", "run development at least once"),
+    ("prereq_test_pass", "通过本轮测试", "pass this iteration's tests"),
+    (
+        "llm_not_configured",
+        "请先在管理员设置中配置 LLM API Key 以启用需求澄清功能。",
+        "Configure an LLM API key in the admin settings to enable requirement clarification.",
+    ),
+    (
+        "llm_quota",
+        "LLM 余额不足或额度耗尽，请检查您的 API 账户余额及配额限制。",
+        "The LLM account is out of balance or quota; check your API billing and limits.",
+    ),
+    (
+        "llm_auth",
+        "LLM API Key 无效或认证失败，请在管理员设置中检查您的配置。",
+        "The LLM API key is invalid or authentication failed; check the admin settings.",
+    ),
+    ("llm_rate_limited", "LLM 调用频率过快，请稍后再试。", "Too many LLM requests; please try again later."),
+    ("llm_timeout", "LLM 响应超时，请稍后重试。", "The LLM did not respond in time; please try again."),
+    (
+        "llm_context_too_long",
+        "对话内容超出模型上下文长度，请精简输入或调小上下文预算。",
+        "The conversation exceeds the model's context length; shorten the input or lower the context budget.",
+    ),
+    ("llm_failed", "LLM 调用失败: {error}", "LLM call failed: {error}"),
+    (
+        "budget_exceeded_user",
+        "本月模型用量已达账户上限（{limit}），请联系管理员调整。",
+        "Your account has reached its monthly LLM limit ({limit}); ask an admin to raise it.",
+    ),
+    (
+        "budget_exceeded_project",
+        "该项目本月模型用量已达上限（{limit}），可在项目设置中调整。",
+        "This project has reached its monthly LLM limit ({limit}); adjust it in the project settings.",
+    ),
+    ("secret_key_missing", "未配置密钥 {var}", "The secret key {var} is not configured"),
+    (
+        "secret_key_invalid",
+        "密钥 {var} 必须是 base64 编码的 32 字节",
+        "The secret key {var} must be 32 bytes encoded as base64",
+    ),
+    ("encrypt_failed", "加密失败", "Encryption failed"),
+    ("decrypt_failed", "解密失败，密钥不匹配或数据已损坏", "Decryption failed: wrong key or corrupted data"),
+    ("llm_key_incomplete", "服务商、模型与 Key 都不能为空", "Provider, model and key are all required"),
+    ("llm_key_not_found", "未设置该角色的模型 Key", "No LLM key is set for this role"),
+    ("unknown_llm_role", "未知角色: {role}（可选 planner、executor、reflector）", "Unknown role: {role} (use planner, executor or reflector)"),
+    (
+        "cassette_exhausted",
+        "录制文件 {path} 中没有可回放的 {kind} 调用",
+        "No recorded {kind} call left to replay in {path}",
+    ),
+    (
+        "cassette_mismatch",
+        "录制文件 {path} 中没有与本次 {kind} 请求一致的记录（还有 {remaining} 条未回放），提示词或流程改动后请重新录制",
+        "No recorded {kind} call in {path} matches this request ({remaining} left unplayed); re-record after changing prompts or the flow",
+    ),
+    ("summary_prefix", "此前对话摘要：", "Summary of the earlier conversation:"),
+    ("prd_input_project", "项目名", "Project"),
+    ("prd_input_requirements", "需求清单", "Requirements"),
+    ("prd_input_conversation", "对话记录", "Conversation"),
+    (
+        "prd_fallback",
+        "# PRD · {name}\n\n## 背景与目标\n\n根据澄清对话整理如下。若此处为摘要而非完整 PRD，请检查后端 LLM 配置（如 DEEPSEEK_API_KEY）或重试生成。\n\n## 对话摘要\n\n{conversation}\n\n## 功能与验收\n\n待根据上述对话由 AI 补充功能清单与验收标准。",
+        "# PRD · {name}\n\n## Background and goals\n\nCompiled from the clarification conversation. If this is a summary rather than a full PRD, check the backend LLM configuration (e.g. DEEPSEEK_API_KEY) or regenerate.\n\n## Conversation summary\n\n{conversation}\n\n## Features and acceptance\n\nFeature list and acceptance criteria to be completed from the conversation above.",
+    ),
+    ("summary_input_previous", "已有摘要", "Existing summary"),
+    ("summary_input_turns", "新增对话", "New turns"),
+    ("requirements_input_current", "当前需求清单", "Current requirements sheet"),
+    (
+        "requirements_invalid_json",
+        "需求清单不是有效的 JSON: {error}",
+        "The requirements sheet is not valid JSON: {error}",
+    ),
+    ("req_unspecified", "未明确", "Not specified"),
+    ("req_scope", "范围（MVP/完整版）", "Scope (MVP/full)"),
+    ("req_scope_mvp", "MVP", "MVP"),
+    ("req_scope_full", "完整版", "Full"),
+    ("req_tech_stack", "技术栈", "Tech stack"),
+    ("req_budget", "预算", "Budget"),
+    ("req_deadline", "上线时间", "Deadline"),
+    ("req_success_criteria", "成功标准", "Success criteria"),
+    ("req_open_questions", "待确认问题", "Open questions"),
+];
+
+/// 按语言取文案；目录中没有的 key 原样返回
+pub fn text(locale: Locale, key: &str) -> &str {
+    CATALOGUE
+        .iter()
+        .find(|(k, _, _)| *k == key)
+        .map(|(_, zh, en)| match locale {
+            Locale::Zh => *zh,
+            Locale::En => *en,
+        })
+        .unwrap_or(key)
+}
+
+/// 当前语言下的文案
+pub fn t(key: &str) -> String {
+    text(current(), key).to_string()
+}
+
+/// 可本地化的消息，也用作错误：Display 时按当前语言渲染
+#[derive(Debug, Clone)]
+pub struct Message {
+    key: &'static str,
+    args: Vec<(&'static str, String)>,
+}
+
+impl Message {
+    pub fn new(key: &'static str) -> Self {
+        Self {
+            key,
+            args: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, name: &'static str, value: impl fmt::Display) -> Self {
+        self.args.push((name, value.to_string()));
+        self
+    }
+
+    pub fn render(&self, locale: Locale) -> String {
+        self.args
+            .iter()
+            .fold(text(locale, self.key).to_string(), |out, (name, value)| {
+                out.replace(&format!("{{{name}}}"), value)
+            })
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(current()))
+    }
+}
+
+impl std::error::Error for Message {}
@@ -1,15 +1,28 @@
 mod api;
 mod auth;
+mod bundle;
+mod cassette;
 mod cli;
 mod clients;
 mod common;
+mod context;
+mod crypto;
 mod db;
+mod i18n;
+mod mock;
 mod models;
+mod prompts;
+mod schema;
+mod search;
 mod service;
+mod store;
+mod usage;
 mod utils;
+mod workflow;
 
 use clap::Parser;
-use cli::{Cli, Commands, DevCommand, PrdCommand};
+use cli::{Cli, Commands, DevCommand, PrdCommand, ProjectCommand, TestCommand};
+use models::{BudgetPatch, ProjectSettingsPatch, ProjectStatus};
 use common::AppResult;
 use service::CeladonService;
 use utils::{print_json, storage_dir};
@@ -17,6 +30,7 @@ use utils::{print_json, storage_dir};
 #[tokio::main]
 async fn main() -> AppResult<()> {
     let _ = dotenvy::dotenv();
+    init_logging();
     let cli = Cli::parse();
     let storage = storage_dir();
 
@@ -50,12 +64,76 @@ async fn main() -> AppResult<()> {
                         session_id,
                         instruction,
                         dry_run,
-                    } => service.run_dev(&session_id, instruction, dry_run).await?.0,
+                    } => {
+                        let (output, events) =
+                            service.run_dev_stream(session_id, instruction, dry_run).await?;
+                        // 命令行不订阅事件，等开发运行结束（用量已记入、预算已检查）后再退出
+                        if let Some(mut events) = events {
+                            while events.recv().await.is_some() {}
+                        }
+                        output
+                    }
+                },
+                Commands::Test { command } => match command {
+                    TestCommand::Start { session_id } => service.start_tests(&session_id).await?,
+                    TestCommand::Result {
+                        session_id,
+                        passed,
+                        notes,
+                    } => service.record_test_result(&session_id, passed, notes).await?,
                 },
                 Commands::Deploy { session_id, env } => {
                     service.run_deploy(&session_id, env).await?
                 }
-                Commands::Status { session_id } => service.status(&session_id)?,
+                Commands::Status { session_id } => service.status(&session_id).await?,
+                Commands::Project { command } => match command {
+                    ProjectCommand::List { status } => service.list_projects(status)?,
+                    ProjectCommand::Archive { project } => {
+                        service.set_project_status(&project, ProjectStatus::Archived).await?
+                    }
+                    ProjectCommand::Unarchive { project } | ProjectCommand::Restore { project } => {
+                        service.set_project_status(&project, ProjectStatus::Active).await?
+                    }
+                    ProjectCommand::Delete { project, hard: true } => {
+                        service.purge_project(&project).await?
+                    }
+                    ProjectCommand::Delete { project, hard: false } => {
+                        service.set_project_status(&project, ProjectStatus::Deleted).await?
+                    }
+                    ProjectCommand::PurgeExpired => service.purge_expired().await?,
+                    ProjectCommand::Settings {
+                        project,
+                        auto_generate_prd,
+                        max_tokens,
+                        max_cost,
+                    } if auto_generate_prd.is_some() || max_tokens.is_some() || max_cost.is_some() => {
+                        let patch = ProjectSettingsPatch {
+                            auto_generate_prd,
+                            budget: Some(BudgetPatch {
+                                max_tokens: max_tokens.map(|max| (max > 0).then_some(max)),
+                                max_cost: max_cost.map(|max| (max > 0.0).then_some(max)),
+                            }),
+                        };
+                        service.update_project_settings(&project, patch).await?
+                    }
+                    ProjectCommand::Settings { project, .. } => service.project_settings(&project)?,
+                },
+                Commands::Search { query, limit } => service.search(&query, limit).await?,
+                Commands::Export { project, output } => {
+                    let (file_name, bytes) = service.export_project(&project).await?;
+                    let path = output.unwrap_or_else(|| file_name.into());
+                    std::fs::write(&path, &bytes)?;
+                    serde_json::json!({
+                        "message": "project exported",
+                        "project_id": project,
+                        "path": path,
+                        "bytes": bytes.len()
+                    })
+                }
+                Commands::Import { bundle } => {
+                    let bytes = std::fs::read(&bundle)?;
+                    service.import_project(&bytes).await?
+                }
                 Commands::Serve { .. } => unreachable!(),
             }
         }
@@ -64,3 +142,13 @@ async fn main() -> AppResult<()> {
     print_json(&output)?;
     Ok(())
 }
+
+/// 日志输出到 stderr，stdout 只留给命令的 JSON 结果
+fn init_logging() {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("warn,celadon=info"));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
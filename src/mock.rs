@@ -0,0 +1,326 @@
+//! 离线的模拟模型：`ZENE_PLANNER_PROVIDER=mock` 时代替真实服务商，不访问网络。
+//!
+//! 澄清按固定顺序逐项提问（范围、技术栈、预算、上线时间、成功标准），用户的下一条回复
+//! 即为该项的答案，问完后给出「需求已澄清」；需求清单从问答中整理，PRD 由项目名、
+//! 需求清单与对话拼成。开发阶段按 Zene AgentEvent 的序列化形状输出计划、思考、write_file/run_command
+//! 工具调用与结果、反思和结束事件，并真实写入 workspace。
+//!
+//! CELADON_MOCK_SCRIPT 可指向一个 JSON 文件覆盖默认行为：
+//! `{"clarify": ["第 1 轮回复", "..."], "prd": "# PRD ..."}`，未覆盖的轮次仍按规则回复。
+
+use crate::clients::{DevEvent, DevRun};
+use crate::i18n::{self, Locale};
+use crate::models::{ProjectScope, RequirementsSheet};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::fs;
+use std::path::Path;
+use tokio::sync::mpsc;
+
+/// 模拟模型的模型名，用于用量记录
+pub const MOCK_MODEL: &str = "mock";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+struct MockScript {
+    clarify: Vec<String>,
+    prd: Option<String>,
+}
+
+/// 澄清时依次询问的需求项
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Topic {
+    Scope,
+    TechStack,
+    Budget,
+    Deadline,
+    SuccessCriteria,
+}
+
+const TOPICS: [Topic; 5] = [
+    Topic::Scope,
+    Topic::TechStack,
+    Topic::Budget,
+    Topic::Deadline,
+    Topic::SuccessCriteria,
+];
+
+impl Topic {
+    fn question(self, locale: Locale) -> &'static str {
+        match (self, locale) {
+            (Topic::Scope, Locale::Zh) => "先确认范围：这次做 MVP 还是完整版？",
+            (Topic::Scope, Locale::En) => "First, the scope: should this be an MVP or the full version?",
+            (Topic::TechStack, Locale::Zh) => "希望使用什么技术栈？",
+            (Topic::TechStack, Locale::En) => "Which tech stack would you like to use?",
+            (Topic::Budget, Locale::Zh) => "预算大概是多少？",
+            (Topic::Budget, Locale::En) => "What is the budget?",
+            (Topic::Deadline, Locale::Zh) => "计划什么时候上线？",
+            (Topic::Deadline, Locale::En) => "When should it launch?",
+            (Topic::SuccessCriteria, Locale::Zh) => "怎样算成功？请列出验收标准。",
+            (Topic::SuccessCriteria, Locale::En) => "What does success look like? Please list the acceptance criteria.",
+        }
+    }
+
+    /// 根据助手的提问认出对应的需求项（两种语言都认）
+    fn asked_in(reply: &str) -> Option<Self> {
+        TOPICS
+            .into_iter()
+            .find(|t| [Locale::Zh, Locale::En].iter().any(|l| reply.contains(t.question(*l))))
+    }
+}
+
+pub struct MockLlm {
+    script: MockScript,
+}
+
+impl MockLlm {
+    pub fn load() -> Self {
+        let script = std::env::var("CELADON_MOCK_SCRIPT")
+            .ok()
+            .and_then(|path| match fs::read_to_string(&path) {
+                Ok(text) => serde_json::from_str(&text)
+                    .map_err(|e| tracing::warn!("CELADON_MOCK_SCRIPT 格式无效，已忽略: {e}"))
+                    .ok(),
+                Err(e) => {
+                    tracing::warn!("读取 CELADON_MOCK_SCRIPT 失败（{path}）: {e}");
+                    None
+                }
+            })
+            .unwrap_or_default();
+        Self { script }
+    }
+
+    /// 第 n 轮澄清回复：依次询问尚未问过的需求项，都问过后给出澄清完成的总结
+    pub fn clarify(&self, history: &[(String, String)], user_input: &str) -> String {
+        let round = history.iter().filter(|(role, _)| role == "user").count();
+        if let Some(reply) = self.script.clarify.get(round) {
+            return reply.clone();
+        }
+        let locale = i18n::current();
+        let asked: Vec<Topic> = history
+            .iter()
+            .filter(|(role, _)| role == "assistant")
+            .filter_map(|(_, content)| Topic::asked_in(content))
+            .collect();
+        let wants_prd = ["可以了", "就这样", "生成 PRD", "ready", "go ahead"]
+            .iter()
+            .any(|w| user_input.to_lowercase().contains(&w.to_lowercase()));
+        match TOPICS.into_iter().find(|t| !asked.contains(t)) {
+            Some(topic) if !wants_prd => topic.question(locale).to_string(),
+            _ => match locale {
+                Locale::Zh => "需求已澄清，可以生成 PRD 了。".to_string(),
+                Locale::En => "Requirements are clear, ready to generate the PRD.".to_string(),
+            },
+        }
+    }
+
+    /// 从「助手提问 → 用户回答」中整理需求清单；conversation 为 `[role] content` 格式
+    pub fn requirements(&self, conversation: &str, current: &RequirementsSheet) -> RequirementsSheet {
+        let mut sheet = current.clone();
+        let turns = parse_conversation(conversation);
+        let mut asked = Vec::new();
+        for pair in turns.windows(2) {
+            let ((role, question), (next_role, answer)) = (&pair[0], &pair[1]);
+            if role != "assistant" || next_role != "user" {
+                continue;
+            }
+            let Some(topic) = Topic::asked_in(question) else {
+                continue;
+            };
+            asked.push(topic);
+            let answer = answer.trim();
+            match topic {
+                Topic::Scope => {
+                    let full = ["完整", "full"].iter().any(|w| answer.to_lowercase().contains(w));
+                    sheet.scope = Some(if full { ProjectScope::Full } else { ProjectScope::Mvp });
+                }
+                Topic::TechStack => sheet.tech_stack = split_list(answer, &[',', '，', '、', '/']),
+                Topic::Budget => sheet.budget = Some(answer.to_string()),
+                Topic::Deadline => sheet.deadline = Some(answer.to_string()),
+                Topic::SuccessCriteria => {
+                    sheet.success_criteria = split_list(answer, &[';', '；', '。', '\n'])
+                }
+            }
+        }
+        let locale = i18n::current();
+        sheet.open_questions = TOPICS
+            .into_iter()
+            .filter(|t| !asked.contains(t))
+            .map(|t| t.question(locale).to_string())
+            .collect();
+        sheet
+    }
+
+    /// 把用户的发言逐条并入已有摘要
+    pub fn summarize(&self, previous: &str, turns: &str) -> String {
+        let mut lines: Vec<String> = previous.lines().map(str::to_string).collect();
+        lines.extend(
+            parse_conversation(turns)
+                .into_iter()
+                .filter(|(role, _)| role == "user")
+                .map(|(_, content)| format!("- {}", truncate(content.trim(), 80))),
+        );
+        lines.join("\n")
+    }
+
+    /// PRD 输入由项目名、需求清单与对话组成，原样整理进固定的章节
+    pub fn prd(&self, input: &str) -> String {
+        if let Some(prd) = &self.script.prd {
+            return prd.clone();
+        }
+        let project = input
+            .lines()
+            .next()
+            .and_then(|line| line.split_once(": "))
+            .map_or("project", |(_, name)| name.trim());
+        let ideas: Vec<String> = input
+            .lines()
+            .filter_map(|line| line.strip_prefix("[user] "))
+            .map(|idea| format!("- {}", truncate(idea.trim(), 120)))
+            .collect();
+        let ideas = if ideas.is_empty() { "- -".to_string() } else { ideas.join("\n") };
+        match i18n::current() {
+            Locale::Zh => format!(
+                "# PRD · {project}\n\n## 背景与目标\n\n根据与用户的澄清对话，交付「{project}」的首个可用版本。\n\n\
+                 ## 用户需求\n\n{ideas}\n\n## 功能清单\n\n- Must：覆盖上述需求的核心流程\n- Should：基础的错误提示与日志\n\
+                 - Could：后续迭代再补充的增强功能\n\n## 非功能需求\n\n- 页面加载在 2 秒内\n- 不保存明文密码等敏感信息\n\n\
+                 ## 验收标准与里程碑\n\n- 核心流程可在本地端到端运行\n- 需求清单中的成功标准全部满足\n"
+            ),
+            Locale::En => format!(
+                "# PRD · {project}\n\n## Background and goals\n\nDeliver the first usable version of \"{project}\" \
+                 based on the clarification conversation.\n\n## User needs\n\n{ideas}\n\n## Features\n\n\
+                 - Must: the core flow covering the needs above\n- Should: basic error messages and logging\n\
+                 - Could: enhancements for later iterations\n\n## Non-functional requirements\n\n\
+                 - Pages load within 2 seconds\n- No sensitive data such as plaintext passwords is stored\n\n\
+                 ## Acceptance and milestones\n\n- The core flow runs end to end locally\n\
+                 - Every success criterion in the requirements sheet is met\n"
+            ),
+        }
+    }
+}
+
+/// 模拟一次 Zene 开发：规划 → 逐个任务写文件 → 运行检查 → 反思 → 结束。
+/// 事件形状与 Zene 序列化后的 AgentEvent 一致（`{"type": ..., "data": ...}`）；
+/// 每一步之间让出执行权，运行可以在步与步之间被中止。文件真实写入 workspace
+pub fn run_dev(instruction: &str, workspace: &Path) -> DevRun {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let title = instruction
+        .lines()
+        .find(|line| !line.trim().is_empty())
+        .map_or("Celadon project", str::trim)
+        .to_string();
+    let files = [
+        ("README.md", format!("# {title}\n\nGenerated by the Celadon mock provider.\n")),
+        (
+            "index.html",
+            "<!doctype html>\n<html>\n<head><meta charset=\"utf-8\"><title>Celadon</title></head>\n\
+             <body>\n<h1 id=\"title\"></h1>\n<script src=\"app.js\"></script>\n</body>\n</html>\n"
+                .to_string(),
+        ),
+        (
+            "app.js",
+            format!("document.getElementById(\"title\").textContent = {};\n", json!(title)),
+        ),
+    ];
+    let workspace = workspace.to_path_buf();
+
+    let task = tokio::spawn(async move {
+        let send = |events: Vec<Value>| events.into_iter().all(|e| sender.send(DevEvent::Json(e)).is_ok());
+        let tasks: Vec<Value> = files
+            .iter()
+            .enumerate()
+            .map(|(id, (name, _))| json!({ "id": id, "description": format!("write {name}"), "status": "Pending", "result": null }))
+            .collect();
+        if !send(vec![
+            event("PlanningStarted", Value::Null),
+            event("PlanGenerated", json!({ "goal": title, "tasks": tasks, "current_task_index": null })),
+        ]) {
+            return;
+        }
+
+        for (id, (name, content)) in files.iter().enumerate() {
+            tokio::task::yield_now().await;
+            let description = format!("write {name}");
+            if !send(vec![
+                event("TaskStarted", json!({ "id": id, "description": description })),
+                event("ThoughtDelta", json!(format!("Writing {name} for {title}."))),
+            ]) {
+                return;
+            }
+            tokio::task::yield_now().await;
+            let path = workspace.join(name);
+            let arguments = json!({ "path": path, "content": content });
+            let result = match fs::write(&path, content) {
+                Ok(()) => format!("wrote {} bytes to {}", content.len(), path.display()),
+                Err(e) => format!("error: {e}"),
+            };
+            if !send(vec![
+                event("ToolCall", json!({ "name": "write_file", "arguments": arguments })),
+                event("ToolResult", json!({ "name": "write_file", "result": result })),
+            ]) {
+                return;
+            }
+        }
+
+        tokio::task::yield_now().await;
+        let listing: Vec<&str> = files.iter().map(|(name, _)| *name).collect();
+        let command = format!("cd {} && ls", workspace.display());
+        if !send(vec![
+            event("ToolCall", json!({ "name": "run_command", "arguments": { "command": command } })),
+            event("ToolResult", json!({ "name": "run_command", "result": listing.join("\n") })),
+            event("ReflectionStarted", Value::Null),
+        ]) {
+            return;
+        }
+        tokio::task::yield_now().await;
+        send(vec![
+            event("ReflectionResult", json!({ "passed": true, "reason": "all planned files were written" })),
+            event("Finished", json!(format!("{} files written to {}", files.len(), workspace.display()))),
+        ]);
+    });
+    DevRun::new(receiver, task.abort_handle())
+}
+
+/// Zene AgentEvent 的序列化形状；没有数据的事件只有 type
+fn event(kind: &str, data: Value) -> Value {
+    if data.is_null() {
+        json!({ "type": kind })
+    } else {
+        json!({ "type": kind, "data": data })
+    }
+}
+
+/// 解析 `[role] content` 格式的对话文本，content 可跨行
+fn parse_conversation(text: &str) -> Vec<(String, String)> {
+    let mut turns: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        let tagged = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("] "))
+            .filter(|(role, _)| matches!(*role, "user" | "assistant" | "system"));
+        match (tagged, turns.last_mut()) {
+            (Some((role, content)), _) => turns.push((role.to_string(), content.to_string())),
+            (None, Some((_, content))) => {
+                content.push('\n');
+                content.push_str(line);
+            }
+            (None, None) => {}
+        }
+    }
+    turns
+}
+
+fn split_list(text: &str, separators: &[char]) -> Vec<String> {
+    text.split(separators)
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text.to_string(),
+    }
+}
@@ -1,7 +1,9 @@
+use crate::i18n::t;
+use crate::schema::CURRENT_SCHEMA_VERSION;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum Stage {
     #[default]
@@ -14,13 +16,96 @@ pub enum Stage {
     Delivered,
 }
 
+/// 项目生命周期：归档后只读；删除后在恢复期内可还原，过期或彻底删除时清除全部数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ProjectStatus {
+    #[default]
+    Active,
+    Archived,
+    Deleted,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Project {
     pub id: String,
     pub name: String,
-    pub status: String,
+    pub status: ProjectStatus,
     pub created_at: String,
     pub updated_at: String,
+    /// 软删除时间，用于计算恢复期限
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    #[serde(default)]
+    pub settings: ProjectSettings,
+}
+
+/// 项目级开关
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProjectSettings {
+    /// 澄清完成（ready_for_prd）后自动生成 PRD
+    #[serde(default)]
+    pub auto_generate_prd: bool,
+    /// 项目每月的用量上限
+    #[serde(default)]
+    pub budget: Budget,
+}
+
+/// 每个自然月（UTC）的用量上限；token 为输入与输出之和，费用为美元，均为空表示不限
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Budget {
+    pub max_tokens: Option<u64>,
+    pub max_cost: Option<f64>,
+}
+
+impl Budget {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_tokens.is_none() && self.max_cost.is_none()
+    }
+}
+
+/// 项目设置的部分修改：只改请求中出现的字段，其余保持原值
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProjectSettingsPatch {
+    #[serde(default)]
+    pub auto_generate_prd: Option<bool>,
+    #[serde(default)]
+    pub budget: Option<BudgetPatch>,
+}
+
+/// 预算的部分修改；字段为 null 表示取消该项上限，缺省表示不改
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BudgetPatch {
+    #[serde(default, deserialize_with = "present")]
+    pub max_tokens: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub max_cost: Option<Option<f64>>,
+}
+
+/// 出现的字段（包括 null）记为 Some，与缺省区分
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl ProjectSettingsPatch {
+    pub fn apply(&self, settings: &mut ProjectSettings) {
+        if let Some(auto_generate_prd) = self.auto_generate_prd {
+            settings.auto_generate_prd = auto_generate_prd;
+        }
+        if let Some(budget) = &self.budget {
+            if let Some(max_tokens) = budget.max_tokens {
+                settings.budget.max_tokens = max_tokens;
+            }
+            if let Some(max_cost) = budget.max_cost {
+                settings.budget.max_cost = max_cost;
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,6 +114,101 @@ pub struct Session {
     pub project_id: String,
     pub stage: Stage,
     pub context_snapshot: String,
+    /// 阶段变更记录，只在阶段实际改变时追加
+    #[serde(default)]
+    pub stage_history: Vec<StageTransition>,
+    /// 每轮澄清后由模型整理的结构化需求，可通过 API 手动修改
+    #[serde(default)]
+    pub requirements: RequirementsSheet,
+    /// 较早轮次的滚动摘要，见 context.rs
+    #[serde(default)]
+    pub summary: ConversationSummary,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConversationSummary {
+    pub content: String,
+    /// 摘要覆盖了该会话的前多少轮对话
+    pub covered_turns: usize,
+    pub updated_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ProjectScope {
+    Mvp,
+    Full,
+}
+
+/// 需求清单：对应 CLARIFY_SYSTEM 要澄清的范围、约束与成功标准
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RequirementsSheet {
+    #[serde(default)]
+    pub scope: Option<ProjectScope>,
+    #[serde(default)]
+    pub tech_stack: Vec<String>,
+    #[serde(default)]
+    pub budget: Option<String>,
+    #[serde(default)]
+    pub deadline: Option<String>,
+    #[serde(default)]
+    pub success_criteria: Vec<String>,
+    #[serde(default)]
+    pub open_questions: Vec<String>,
+    /// 最后更新时间；为空表示尚未整理过
+    #[serde(default)]
+    pub updated_at: Option<String>,
+}
+
+impl RequirementsSheet {
+    pub fn is_empty(&self) -> bool {
+        self.scope.is_none()
+            && self.tech_stack.is_empty()
+            && self.budget.is_none()
+            && self.deadline.is_none()
+            && self.success_criteria.is_empty()
+            && self.open_questions.is_empty()
+    }
+
+    /// 渲染为 Markdown（当前语言），作为生成 PRD 的输入
+    pub fn to_markdown(&self) -> String {
+        let none = || t("req_unspecified");
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                format!("- {}\n", none())
+            } else {
+                items.iter().map(|item| format!("- {item}\n")).collect()
+            }
+        };
+        let scope = match self.scope {
+            Some(ProjectScope::Mvp) => t("req_scope_mvp"),
+            Some(ProjectScope::Full) => t("req_scope_full"),
+            None => none(),
+        };
+        format!(
+            "- {}: {scope}\n- {}: {}\n- {}: {}\n- {}: {}\n\n{}:\n{}\n{}:\n{}",
+            t("req_scope"),
+            t("req_tech_stack"),
+            if self.tech_stack.is_empty() { none() } else { self.tech_stack.join(", ") },
+            t("req_budget"),
+            self.budget.clone().unwrap_or_else(none),
+            t("req_deadline"),
+            self.deadline.clone().unwrap_or_else(none),
+            t("req_success_criteria"),
+            list(&self.success_criteria),
+            t("req_open_questions"),
+            list(&self.open_questions),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StageTransition {
+    pub from: Stage,
+    pub to: Stage,
+    pub at: String,
+    pub reason: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,6 +225,9 @@ pub struct ConversationTurn {
     pub role: String, // "user" | "assistant"
     pub content: String,
     pub created_at: String,
+    /// 流式输出被中断时保存的不完整回复
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -75,9 +258,79 @@ pub struct DeploymentRun {
     pub rollback_hint: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum IterationStatus {
+    #[default]
+    Open,
+    Delivered,
+}
+
+/// 产生模型调用的角色：澄清与 PRD 走 planner，开发由 Zene 的三个角色协作
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageRole {
+    #[default]
+    Planner,
+    Executor,
+    Reflector,
+}
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+/// 一次模型调用的 token 用量与按价格表折算的费用（美元）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub usage_id: String,
+    pub project_id: String,
+    pub session_id: Option<String>,
+    pub role: UsageRole,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+    /// 服务商未返回用量时按文本长度估算
+    #[serde(default)]
+    pub estimated: bool,
+    pub created_at: String,
+}
+
+/// TESTING 阶段提交的测试结论，部署前必须通过
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TestResult {
+    pub passed: bool,
+    #[serde(default)]
+    pub notes: String,
+    pub tested_at: String,
+}
+
+/// 一轮迭代：由一批想法触发，产出一个 PRD 版本、若干开发任务和一次部署。
+/// 交付后再有新输入即开启下一轮（docs/development-process.md 的增量闭环）
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Iteration {
+    pub iteration_id: String,
+    pub project_id: String,
+    pub session_id: String,
+    /// 项目内从 1 开始递增
+    pub number: u32,
+    pub idea_event_ids: Vec<String>,
+    pub prd_version: Option<u32>,
+    pub task_ids: Vec<String>,
+    /// 当前开发结果的测试结论；重新开发或重新开始测试时清空
+    #[serde(default)]
+    pub test_result: Option<TestResult>,
+    pub deploy_id: Option<String>,
+    pub status: IterationStatus,
+    pub opened_at: String,
+    pub delivered_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StateStore {
+    /// 文档格式版本，加载时由 schema::upgrade 迁移到当前版本
+    #[serde(default)]
+    pub schema_version: u32,
+    /// 每次成功写入加一，用于乐观并发控制
+    #[serde(default)]
+    pub revision: u64,
     pub projects: HashMap<String, Project>,
     pub sessions: HashMap<String, Session>,
     pub idea_events: Vec<IdeaEvent>,
@@ -86,4 +339,405 @@ pub struct StateStore {
     pub prd_versions: Vec<PrdVersion>,
     pub task_runs: Vec<TaskRun>,
     pub deployment_runs: Vec<DeploymentRun>,
+    #[serde(default)]
+    pub iterations: Vec<Iteration>,
+    #[serde(default)]
+    pub usage: Vec<UsageRecord>,
+}
+
+impl Default for StateStore {
+    fn default() -> Self {
+        Self {
+            schema_version: CURRENT_SCHEMA_VERSION,
+            revision: 0,
+            projects: HashMap::new(),
+            sessions: HashMap::new(),
+            idea_events: Vec::new(),
+            conversation_turns: Vec::new(),
+            prd_versions: Vec::new(),
+            task_runs: Vec::new(),
+            deployment_runs: Vec::new(),
+            iterations: Vec::new(),
+            usage: Vec::new(),
+        }
+    }
+}
+
+/// 对 StateStore 的一次增量修改。服务层只产生这些修改，
+/// 由存储后端决定整体写回（文件）还是逐行写入（PostgreSQL）。
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data")]
+pub enum StateChange {
+    ProjectCreated(Project),
+    SessionCreated(Session),
+    TurnAppended(ConversationTurn),
+    IdeaAppended(IdeaEvent),
+    PrdGenerated(PrdVersion),
+    TaskRecorded(TaskRun),
+    DeploymentRecorded(DeploymentRun),
+    StageChanged {
+        session_id: String,
+        stage: Stage,
+        context_snapshot: String,
+        #[serde(default)]
+        changed_at: String,
+    },
+    ProjectTouched {
+        project_id: String,
+        updated_at: String,
+    },
+    RequirementsUpdated {
+        session_id: String,
+        requirements: RequirementsSheet,
+    },
+    SummaryUpdated {
+        session_id: String,
+        summary: ConversationSummary,
+    },
+    ProjectStatusChanged {
+        project_id: String,
+        status: ProjectStatus,
+        deleted_at: Option<String>,
+        updated_at: String,
+    },
+    ProjectSettingsChanged {
+        project_id: String,
+        settings: ProjectSettings,
+        updated_at: String,
+    },
+    IterationOpened(Iteration),
+    /// 整体替换同 iteration_id 的迭代
+    IterationUpdated(Iteration),
+    UsageRecorded(UsageRecord),
+    /// 彻底删除项目及其会话、对话、PRD、任务与部署记录
+    ProjectPurged {
+        project_id: String,
+    },
+    /// 项目某一时刻的完整状态。事件日志启用之前就已存在的项目日志不完整，
+    /// 读取时在内存中以它接在日志之后重放；重放到这里时以它替换该项目此前的全部记录
+    ProjectSnapshot {
+        project_id: String,
+        snapshot: Box<StateStore>,
+    },
+}
+
+impl StateChange {
+    pub fn apply(&self, state: &mut StateStore) {
+        match self {
+            StateChange::ProjectCreated(project) => {
+                state.projects.insert(project.id.clone(), project.clone());
+            }
+            StateChange::SessionCreated(session) => {
+                state.sessions.insert(session.session_id.clone(), session.clone());
+            }
+            StateChange::TurnAppended(turn) => state.conversation_turns.push(turn.clone()),
+            StateChange::IdeaAppended(event) => state.idea_events.push(event.clone()),
+            StateChange::PrdGenerated(prd) => state.prd_versions.push(prd.clone()),
+            // 与数据库的 ON CONFLICT 一致：同一任务再次记录时更新状态
+            StateChange::TaskRecorded(task) => {
+                match state.task_runs.iter_mut().find(|t| t.task_id == task.task_id) {
+                    Some(existing) => *existing = task.clone(),
+                    None => state.task_runs.push(task.clone()),
+                }
+            }
+            StateChange::DeploymentRecorded(deploy) => state.deployment_runs.push(deploy.clone()),
+            StateChange::IterationOpened(iteration) => state.iterations.push(iteration.clone()),
+            StateChange::UsageRecorded(usage) => state.usage.push(usage.clone()),
+            StateChange::IterationUpdated(iteration) => {
+                if let Some(existing) = state
+                    .iterations
+                    .iter_mut()
+                    .find(|i| i.iteration_id == iteration.iteration_id)
+                {
+                    *existing = iteration.clone();
+                }
+            }
+            StateChange::StageChanged {
+                session_id,
+                stage,
+                context_snapshot,
+                changed_at,
+            } => {
+                if let Some(session) = state.sessions.get_mut(session_id) {
+                    if session.stage != *stage {
+                        session.stage_history.push(StageTransition {
+                            from: session.stage,
+                            to: *stage,
+                            at: changed_at.clone(),
+                            reason: context_snapshot.clone(),
+                        });
+                    }
+                    session.stage = *stage;
+                    session.context_snapshot = context_snapshot.clone();
+                }
+            }
+            StateChange::RequirementsUpdated {
+                session_id,
+                requirements,
+            } => {
+                if let Some(session) = state.sessions.get_mut(session_id) {
+                    session.requirements = requirements.clone();
+                }
+            }
+            StateChange::SummaryUpdated {
+                session_id,
+                summary,
+            } => {
+                if let Some(session) = state.sessions.get_mut(session_id) {
+                    session.summary = summary.clone();
+                }
+            }
+            StateChange::ProjectTouched {
+                project_id,
+                updated_at,
+            } => {
+                if let Some(project) = state.projects.get_mut(project_id) {
+                    project.updated_at = updated_at.clone();
+                }
+            }
+            StateChange::ProjectStatusChanged {
+                project_id,
+                status,
+                deleted_at,
+                updated_at,
+            } => {
+                if let Some(project) = state.projects.get_mut(project_id) {
+                    project.status = *status;
+                    project.deleted_at = deleted_at.clone();
+                    project.updated_at = updated_at.clone();
+                }
+            }
+            StateChange::ProjectSettingsChanged {
+                project_id,
+                settings,
+                updated_at,
+            } => {
+                if let Some(project) = state.projects.get_mut(project_id) {
+                    project.settings = settings.clone();
+                    project.updated_at = updated_at.clone();
+                }
+            }
+            StateChange::ProjectPurged { project_id } => {
+                state.projects.remove(project_id);
+                let sessions: Vec<String> = state
+                    .sessions
+                    .values()
+                    .filter(|s| &s.project_id == project_id)
+                    .map(|s| s.session_id.clone())
+                    .collect();
+                state.sessions.retain(|_, s| &s.project_id != project_id);
+                state.conversation_turns.retain(|t| !sessions.contains(&t.session_id));
+                state.idea_events.retain(|e| !sessions.contains(&e.session_id));
+                state.prd_versions.retain(|v| &v.project_id != project_id);
+                state.task_runs.retain(|t| &t.project_id != project_id);
+                state.deployment_runs.retain(|d| &d.project_id != project_id);
+                state.iterations.retain(|i| &i.project_id != project_id);
+                // 用量记录保留，删除项目后费用仍计入用户
+            }
+            StateChange::ProjectSnapshot {
+                project_id,
+                snapshot,
+            } => {
+                StateChange::ProjectPurged {
+                    project_id: project_id.clone(),
+                }
+                .apply(state);
+                state.usage.retain(|u| &u.project_id != project_id);
+                for change in StateChange::snapshot(snapshot) {
+                    change.apply(state);
+                }
+            }
+        }
+    }
+
+    /// 修改所属的项目；会话级修改通过 state 中的会话查找
+    pub fn project_id(&self, state: &StateStore) -> Option<String> {
+        let via_session = |session_id: &str| {
+            state
+                .sessions
+                .get(session_id)
+                .map(|s| s.project_id.clone())
+        };
+        match self {
+            StateChange::ProjectCreated(project) => Some(project.id.clone()),
+            StateChange::SessionCreated(session) => Some(session.project_id.clone()),
+            StateChange::TurnAppended(turn) => via_session(&turn.session_id),
+            StateChange::IdeaAppended(event) => via_session(&event.session_id),
+            StateChange::PrdGenerated(prd) => Some(prd.project_id.clone()),
+            StateChange::TaskRecorded(task) => Some(task.project_id.clone()),
+            StateChange::DeploymentRecorded(deploy) => Some(deploy.project_id.clone()),
+            StateChange::IterationOpened(iteration) | StateChange::IterationUpdated(iteration) => {
+                Some(iteration.project_id.clone())
+            }
+            StateChange::UsageRecorded(usage) => Some(usage.project_id.clone()),
+            StateChange::StageChanged { session_id, .. }
+            | StateChange::RequirementsUpdated { session_id, .. }
+            | StateChange::SummaryUpdated { session_id, .. } => via_session(session_id),
+            StateChange::ProjectTouched { project_id, .. } => Some(project_id.clone()),
+            StateChange::ProjectStatusChanged { project_id, .. }
+            | StateChange::ProjectSettingsChanged { project_id, .. } => Some(project_id.clone()),
+            StateChange::ProjectPurged { project_id }
+            | StateChange::ProjectSnapshot { project_id, .. } => Some(project_id.clone()),
+        }
+    }
+
+    /// 整体替换对象、或依据现有内容推导（如 PRD 版本号）的修改所依赖的对象及其当前值。
+    /// 并发冲突后只有这些值在最新状态中未变，才能直接重放；
+    /// 以新 id 追加的修改与其他写入可交换，返回 None
+    pub fn precondition(&self, state: &StateStore) -> Option<(String, serde_json::Value)> {
+        let session = |session_id: &str| state.sessions.get(session_id);
+        let project = |project_id: &str| state.projects.get(project_id);
+        let value = |v: Option<serde_json::Value>| v.unwrap_or_default();
+        let (key, current) = match self {
+            StateChange::PrdGenerated(prd) => {
+                let versions: Vec<u32> = state
+                    .prd_versions
+                    .iter()
+                    .filter(|v| v.project_id == prd.project_id)
+                    .map(|v| v.version)
+                    .collect();
+                (format!("prd:{}", prd.project_id), serde_json::json!(versions))
+            }
+            StateChange::IterationUpdated(iteration) => (
+                format!("iteration:{}", iteration.iteration_id),
+                value(
+                    state
+                        .iterations
+                        .iter()
+                        .find(|i| i.iteration_id == iteration.iteration_id)
+                        .and_then(|i| serde_json::to_value(i).ok()),
+                ),
+            ),
+            StateChange::StageChanged { session_id, .. } => (
+                format!("stage:{session_id}"),
+                value(session(session_id).and_then(|s| serde_json::to_value(s.stage).ok())),
+            ),
+            StateChange::RequirementsUpdated { session_id, .. } => (
+                format!("requirements:{session_id}"),
+                value(session(session_id).and_then(|s| serde_json::to_value(&s.requirements).ok())),
+            ),
+            StateChange::SummaryUpdated { session_id, .. } => (
+                format!("summary:{session_id}"),
+                value(session(session_id).and_then(|s| serde_json::to_value(&s.summary).ok())),
+            ),
+            StateChange::ProjectStatusChanged { project_id, .. } => (
+                format!("status:{project_id}"),
+                value(project(project_id).map(|p| serde_json::json!([p.status, p.deleted_at]))),
+            ),
+            StateChange::ProjectSettingsChanged { project_id, .. } => (
+                format!("settings:{project_id}"),
+                value(project(project_id).and_then(|p| serde_json::to_value(&p.settings).ok())),
+            ),
+            _ => return None,
+        };
+        Some((key, current))
+    }
+
+    /// 把完整状态展开为一组插入，用于 schema 升级时补写新增记录与重放项目快照
+    pub fn snapshot(state: &StateStore) -> Vec<StateChange> {
+        let mut changes: Vec<StateChange> = Vec::new();
+        changes.extend(state.projects.values().cloned().map(StateChange::ProjectCreated));
+        changes.extend(state.sessions.values().cloned().map(StateChange::SessionCreated));
+        changes.extend(state.conversation_turns.iter().cloned().map(StateChange::TurnAppended));
+        changes.extend(state.idea_events.iter().cloned().map(StateChange::IdeaAppended));
+        changes.extend(state.prd_versions.iter().cloned().map(StateChange::PrdGenerated));
+        changes.extend(state.task_runs.iter().cloned().map(StateChange::TaskRecorded));
+        changes.extend(state.deployment_runs.iter().cloned().map(StateChange::DeploymentRecorded));
+        changes.extend(state.iterations.iter().cloned().map(StateChange::IterationOpened));
+        changes.extend(state.usage.iter().cloned().map(StateChange::UsageRecorded));
+        changes
+    }
+}
+
+impl StateStore {
+    /// 只含单个项目及其会话、对话、PRD、任务、部署、迭代与用量的状态
+    pub fn project_snapshot(&self, project_id: &str) -> StateStore {
+        let sessions: HashMap<String, Session> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.project_id == project_id)
+            .map(|(id, s)| (id.clone(), s.clone()))
+            .collect();
+        StateStore {
+            projects: self
+                .projects
+                .get(project_id)
+                .map(|p| HashMap::from([(p.id.clone(), p.clone())]))
+                .unwrap_or_default(),
+            conversation_turns: self
+                .conversation_turns
+                .iter()
+                .filter(|t| sessions.contains_key(&t.session_id))
+                .cloned()
+                .collect(),
+            idea_events: self
+                .idea_events
+                .iter()
+                .filter(|e| sessions.contains_key(&e.session_id))
+                .cloned()
+                .collect(),
+            prd_versions: self.prd_versions.iter().filter(|v| v.project_id == project_id).cloned().collect(),
+            task_runs: self.task_runs.iter().filter(|t| t.project_id == project_id).cloned().collect(),
+            deployment_runs: self
+                .deployment_runs
+                .iter()
+                .filter(|d| d.project_id == project_id)
+                .cloned()
+                .collect(),
+            iterations: self.iterations.iter().filter(|i| i.project_id == project_id).cloned().collect(),
+            usage: self.usage.iter().filter(|u| u.project_id == project_id).cloned().collect(),
+            sessions,
+            ..Default::default()
+        }
+    }
+}
+
+/// 项目事件日志中的一条记录：只追加、不修改，按 seq 重放即可得到项目状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectEvent {
+    /// 项目内递增的序号，由存储后端在写入时分配
+    #[serde(default)]
+    pub seq: u64,
+    pub project_id: String,
+    pub change: StateChange,
+    pub created_at: String,
+}
+
+impl ProjectEvent {
+    /// 按顺序重放事件，得到这些事件所描述的状态
+    pub fn replay(events: &[ProjectEvent]) -> StateStore {
+        let mut state = StateStore::default();
+        for event in events {
+            event.change.apply(&mut state);
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_patch_keeps_fields_it_does_not_mention() {
+        let mut settings = ProjectSettings {
+            auto_generate_prd: false,
+            budget: Budget {
+                max_tokens: Some(200_000),
+                max_cost: Some(5.0),
+            },
+        };
+        let patch: ProjectSettingsPatch = serde_json::from_str(r#"{"auto_generate_prd": true}"#).unwrap();
+        patch.apply(&mut settings);
+        assert!(settings.auto_generate_prd);
+        assert_eq!(settings.budget.max_tokens, Some(200_000));
+        assert_eq!(settings.budget.max_cost, Some(5.0));
+
+        // 预算中只改出现的字段，null 取消该项上限
+        let patch: ProjectSettingsPatch = serde_json::from_str(r#"{"budget": {"max_tokens": null}}"#).unwrap();
+        patch.apply(&mut settings);
+        assert!(settings.auto_generate_prd);
+        assert_eq!(settings.budget.max_tokens, None);
+        assert_eq!(settings.budget.max_cost, Some(5.0));
+    }
 }
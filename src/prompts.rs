@@ -0,0 +1,325 @@
+//! 提示词模板：管理员可在线修改，保留版本历史，可按项目覆盖。
+//!
+//! 查找顺序：项目覆盖（数据库 → `.celadon/prompts/{project_id}/{name}.md`）→
+//! 全局（数据库 → `.celadon/prompts/{name}.md`）→ 内置默认。每一级先找当前语言的
+//! 模板（文件名为 `{name}.{locale}.md`），再找不限语言的模板。数据库中每次保存或回滚
+//! 都追加一个新版本，最新版本生效。模板中的 `{{project_name}}`、`{{workspace}}`、
+//! `{{locale}}` 在使用时替换，未知变量原样保留。
+
+use crate::clients::{
+    CLARIFY_SYSTEM, CLARIFY_SYSTEM_EN, PRD_GEN_SYSTEM, PRD_GEN_SYSTEM_EN, REQUIREMENTS_SYSTEM,
+    REQUIREMENTS_SYSTEM_EN, SUMMARY_SYSTEM, SUMMARY_SYSTEM_EN,
+};
+use crate::common::AppResult;
+use crate::db;
+use crate::i18n::Locale;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sqlx::Row;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+const DEV_GUARDRAILS: &str = r#"CRITICAL SYSTEM RULES:
+1. Your project workspace is strictly restricted to: `{{workspace}}`
+2. ALL file operations (read_file, write_file, list_files, search_code) MUST use ABSOLUTE paths starting with this workspace.
+3. ALL commands (`run_command`) MUST start with `cd {{workspace}} && ...` to ensure they run in the correct context.
+4. You are FORBIDDEN from accessing any files outside of this workspace.
+5. When using `read_file`, you MUST provide the `path` argument (e.g., `{"path": "{{workspace}}/Cargo.toml"}`).
+6. When using `search_code`, you MUST provide the `pattern` argument.
+DO NOT CALL THESE TOOLS WITHOUT ARGUMENTS."#;
+
+/// 可编辑的提示词
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptName {
+    Clarify,
+    PrdGenerate,
+    DevGuardrails,
+    Requirements,
+    Summary,
+}
+
+impl PromptName {
+    pub const ALL: [PromptName; 5] = [
+        PromptName::Clarify,
+        PromptName::PrdGenerate,
+        PromptName::DevGuardrails,
+        PromptName::Requirements,
+        PromptName::Summary,
+    ];
+
+    pub fn key(self) -> &'static str {
+        match self {
+            PromptName::Clarify => "clarify",
+            PromptName::PrdGenerate => "prd_generate",
+            PromptName::DevGuardrails => "dev_guardrails",
+            PromptName::Requirements => "requirements",
+            PromptName::Summary => "summary",
+        }
+    }
+
+    pub fn parse(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|name| name.key() == key)
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            PromptName::Clarify => "需求澄清对话的系统提示",
+            PromptName::PrdGenerate => "根据对话生成 PRD 的系统提示",
+            PromptName::DevGuardrails => "追加在开发指令后的工作区约束",
+            PromptName::Requirements => "整理结构化需求清单（JSON）的系统提示",
+            PromptName::Summary => "压缩较早对话为滚动摘要的系统提示",
+        }
+    }
+
+    pub fn builtin(self, locale: Locale) -> &'static str {
+        match (self, locale) {
+            (PromptName::Clarify, Locale::Zh) => CLARIFY_SYSTEM,
+            (PromptName::Clarify, Locale::En) => CLARIFY_SYSTEM_EN,
+            (PromptName::PrdGenerate, Locale::Zh) => PRD_GEN_SYSTEM,
+            (PromptName::PrdGenerate, Locale::En) => PRD_GEN_SYSTEM_EN,
+            (PromptName::DevGuardrails, _) => DEV_GUARDRAILS,
+            (PromptName::Requirements, Locale::Zh) => REQUIREMENTS_SYSTEM,
+            (PromptName::Requirements, Locale::En) => REQUIREMENTS_SYSTEM_EN,
+            (PromptName::Summary, Locale::Zh) => SUMMARY_SYSTEM,
+            (PromptName::Summary, Locale::En) => SUMMARY_SYSTEM_EN,
+        }
+    }
+}
+
+pub type PromptVars = BTreeMap<String, String>;
+
+pub fn variables(project_name: &str, workspace: &str, locale: &str) -> PromptVars {
+    BTreeMap::from([
+        ("project_name".to_string(), project_name.to_string()),
+        ("workspace".to_string(), workspace.to_string()),
+        ("locale".to_string(), locale.to_string()),
+    ])
+}
+
+pub fn render(template: &str, vars: &PromptVars) -> String {
+    vars.iter().fold(template.to_string(), |text, (key, value)| {
+        text.replace(&format!("{{{{{key}}}}}"), value)
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptVersion {
+    pub version: i32,
+    pub content: String,
+    pub note: Option<String>,
+    pub created_at: String,
+}
+
+/// 模板来源：数据库版本、文件或内置默认
+#[derive(Debug, Clone, Serialize)]
+pub struct ResolvedPrompt {
+    pub content: String,
+    pub source: &'static str,
+    pub version: Option<i32>,
+    /// 是否来自项目覆盖
+    pub project_override: bool,
+}
+
+pub struct PromptStore {
+    pool: Option<db::Pool>,
+    dir: PathBuf,
+}
+
+impl PromptStore {
+    pub fn open(pool: Option<&db::Pool>) -> Self {
+        Self {
+            pool: pool.cloned(),
+            dir: crate::utils::storage_dir().join("prompts"),
+        }
+    }
+
+    /// 当前生效的模板（尚未替换变量）
+    pub async fn resolve(
+        &self,
+        name: PromptName,
+        project_id: Option<&str>,
+        locale: Locale,
+    ) -> AppResult<ResolvedPrompt> {
+        let scopes = project_id.into_iter().chain(std::iter::once(""));
+        for scope in scopes {
+            for lang in [locale.code(), ""] {
+                if let Some(mut found) = self.lookup(name, scope, lang).await? {
+                    found.project_override = !scope.is_empty();
+                    return Ok(found);
+                }
+            }
+        }
+        Ok(ResolvedPrompt {
+            content: name.builtin(locale).to_string(),
+            source: "builtin",
+            version: None,
+            project_override: false,
+        })
+    }
+
+    /// project_id 为空串时查找全局模板，locale 为空串时查找不限语言的模板
+    async fn lookup(
+        &self,
+        name: PromptName,
+        project_id: &str,
+        locale: &str,
+    ) -> AppResult<Option<ResolvedPrompt>> {
+        if let Some(pool) = &self.pool {
+            let row = sqlx::query(
+                "SELECT version, content FROM prompt_templates
+                 WHERE name = $1 AND project_id = $2 AND locale = $3
+                 ORDER BY version DESC LIMIT 1",
+            )
+            .bind(name.key())
+            .bind(project_id)
+            .bind(locale)
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("读取提示词模板失败: {e}"))?;
+            if let Some(row) = row {
+                return Ok(Some(ResolvedPrompt {
+                    content: row.get("content"),
+                    source: "database",
+                    version: Some(row.get("version")),
+                    project_override: false,
+                }));
+            }
+        }
+        let file_name = match locale {
+            "" => format!("{}.md", name.key()),
+            locale => format!("{}.{locale}.md", name.key()),
+        };
+        let path = if project_id.is_empty() {
+            self.dir.join(file_name)
+        } else {
+            self.dir.join(project_id).join(file_name)
+        };
+        Ok(fs::read_to_string(&path)
+            .ok()
+            .filter(|content| !content.trim().is_empty())
+            .map(|content| ResolvedPrompt {
+                content,
+                source: "file",
+                version: None,
+                project_override: false,
+            }))
+    }
+
+    /// locale 为 None 时是不限语言的模板
+    pub async fn history(
+        &self,
+        name: PromptName,
+        project_id: Option<&str>,
+        locale: Option<Locale>,
+    ) -> AppResult<Vec<PromptVersion>> {
+        let Some(pool) = &self.pool else {
+            return Ok(Vec::new());
+        };
+        let rows = sqlx::query(
+            "SELECT version, content, note, created_at FROM prompt_templates
+             WHERE name = $1 AND project_id = $2 AND locale = $3
+             ORDER BY version DESC",
+        )
+        .bind(name.key())
+        .bind(project_id.unwrap_or(""))
+        .bind(locale.map_or("", Locale::code))
+        .fetch_all(pool)
+        .await
+        .map_err(|e| format!("读取提示词历史失败: {e}"))?;
+        Ok(rows
+            .into_iter()
+            .map(|r| PromptVersion {
+                version: r.get("version"),
+                content: r.get("content"),
+                note: r.get("note"),
+                created_at: r.get::<DateTime<Utc>, _>("created_at").to_rfc3339(),
+            })
+            .collect())
+    }
+
+    /// 保存为新版本并立即生效，返回版本号
+    pub async fn save(
+        &self,
+        name: PromptName,
+        project_id: Option<&str>,
+        locale: Option<Locale>,
+        content: &str,
+        note: Option<&str>,
+        created_by: Option<Uuid>,
+    ) -> AppResult<i32> {
+        let pool = self
+            .pool
+            .as_ref()
+            .ok_or("提示词版本管理需要数据库，未启用时请编辑 .celadon/prompts 下的文件")?;
+        let row = sqlx::query(
+            "INSERT INTO prompt_templates (name, project_id, locale, version, content, note, created_by)
+             SELECT $1, $2, $6, COALESCE(MAX(version), 0) + 1, $3, $4, $5
+             FROM prompt_templates WHERE name = $1 AND project_id = $2 AND locale = $6
+             RETURNING version",
+        )
+        .bind(name.key())
+        .bind(project_id.unwrap_or(""))
+        .bind(content)
+        .bind(note)
+        .bind(created_by)
+        .bind(locale.map_or("", Locale::code))
+        .fetch_one(pool)
+        .await
+        .map_err(|e| format!("保存提示词模板失败: {e}"))?;
+        Ok(row.get("version"))
+    }
+
+    /// 回滚：把历史版本的内容另存为新版本，历史不被改写
+    pub async fn rollback(
+        &self,
+        name: PromptName,
+        project_id: Option<&str>,
+        locale: Option<Locale>,
+        version: i32,
+        created_by: Option<Uuid>,
+    ) -> AppResult<i32> {
+        let target = self
+            .history(name, project_id, locale)
+            .await?
+            .into_iter()
+            .find(|v| v.version == version)
+            .ok_or_else(|| format!("提示词 {} 没有版本 {version}", name.key()))?;
+        let note = format!("rollback to v{version}");
+        self.save(name, project_id, locale, &target.content, Some(&note), created_by)
+            .await
+    }
+
+    /// 所有模板在 locale 下的当前生效内容与内置默认
+    pub async fn list(&self, locale: Locale) -> AppResult<Value> {
+        let mut items = Vec::new();
+        for name in PromptName::ALL {
+            let active = self.resolve(name, None, locale).await?;
+            let overrides: Vec<String> = match &self.pool {
+                Some(pool) => sqlx::query(
+                    "SELECT DISTINCT project_id FROM prompt_templates
+                     WHERE name = $1 AND project_id <> ''",
+                )
+                .bind(name.key())
+                .fetch_all(pool)
+                .await
+                .map_err(|e| format!("读取提示词模板失败: {e}"))?
+                .into_iter()
+                .map(|r| r.get("project_id"))
+                .collect(),
+                None => Vec::new(),
+            };
+            items.push(json!({
+                "name": name,
+                "description": name.description(),
+                "active": active,
+                "builtin": name.builtin(locale),
+                "project_overrides": overrides
+            }));
+        }
+        Ok(Value::Array(items))
+    }
+}
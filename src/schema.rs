@@ -0,0 +1,376 @@
+//! StateStore 的 schema 版本与按序执行的迁移。
+//!
+//! 迁移作用于反序列化之前的 JSON 文档，因此可以补字段、改名或拆分结构；
+//! 新增迁移时在 MIGRATIONS 末尾追加一项，版本号取上一项加一。
+
+use crate::common::AppResult;
+use crate::models::StateStore;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+type Migration = fn(&mut Value) -> AppResult<()>;
+
+/// (目标版本, 说明, 迁移函数)，按版本升序排列
+const MIGRATIONS: &[(u32, &str, Migration)] = &[
+    (1, "补齐缺失的集合字段", fill_missing_collections),
+    (2, "idea_events 转为 conversation_turns", idea_events_to_conversation),
+    (3, "为已有项目补建第一轮迭代", backfill_iterations),
+];
+
+/// 当前代码写出的 schema 版本
+pub const CURRENT_SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].0;
+
+/// 读取文档中的 schema_version（缺省视为 0，即引入版本号之前的格式）
+pub fn version_of(doc: &Value) -> u32 {
+    doc.get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0) as u32
+}
+
+/// 把任意旧版本的状态文档迁移到当前版本并反序列化
+pub fn upgrade(mut doc: Value) -> AppResult<StateStore> {
+    let from = version_of(&doc);
+    if from > CURRENT_SCHEMA_VERSION {
+        return Err(format!(
+            "状态 schema 版本 {from} 高于当前支持的 {CURRENT_SCHEMA_VERSION}，请升级 celadon"
+        )
+        .into());
+    }
+    if !doc.is_object() {
+        return Err("状态文档必须是 JSON 对象".into());
+    }
+    for (version, name, migrate) in MIGRATIONS.iter().filter(|(v, _, _)| *v > from) {
+        migrate(&mut doc).map_err(|e| format!("schema 迁移 v{version}（{name}）失败: {e}"))?;
+        doc["schema_version"] = json!(version);
+    }
+    Ok(serde_json::from_value(doc).map_err(|e| format!("反序列化状态失败: {e}"))?)
+}
+
+/// v1：早期 state.json 可能缺少后加入的集合
+fn fill_missing_collections(doc: &mut Value) -> AppResult<()> {
+    for key in ["projects", "sessions"] {
+        if doc.get(key).is_none_or(Value::is_null) {
+            doc[key] = json!({});
+        }
+    }
+    for key in [
+        "idea_events",
+        "conversation_turns",
+        "prd_versions",
+        "task_runs",
+        "deployment_runs",
+    ] {
+        if doc.get(key).is_none_or(Value::is_null) {
+            doc[key] = json!([]);
+        }
+    }
+    Ok(())
+}
+
+/// v2：对话记录出现之前只保存了用户输入，按原顺序转成 user 轮次
+fn idea_events_to_conversation(doc: &mut Value) -> AppResult<()> {
+    let has_turns = doc["conversation_turns"]
+        .as_array()
+        .is_some_and(|turns| !turns.is_empty());
+    if has_turns {
+        return Ok(());
+    }
+    let turns: Vec<Value> = doc["idea_events"]
+        .as_array()
+        .map(|events| {
+            events
+                .iter()
+                .map(|e| {
+                    json!({
+                        "session_id": e["session_id"],
+                        "role": "user",
+                        "content": e["user_input"],
+                        "created_at": e["created_at"],
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    doc["conversation_turns"] = Value::Array(turns);
+    Ok(())
+}
+
+/// v3：迭代出现之前的项目整体视为第 1 轮，已有部署即视为已交付
+fn backfill_iterations(doc: &mut Value) -> AppResult<()> {
+    if doc.get("iterations").is_none_or(Value::is_null) {
+        doc["iterations"] = json!([]);
+    }
+    let empty = Vec::new();
+    let list = |key: &str| doc[key].as_array().unwrap_or(&empty).clone();
+    let (ideas, prds, tasks, deploys, existing) = (
+        list("idea_events"),
+        list("prd_versions"),
+        list("task_runs"),
+        list("deployment_runs"),
+        list("iterations"),
+    );
+    let sessions: Vec<(String, Value)> = doc["sessions"]
+        .as_object()
+        .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+        .unwrap_or_default();
+    let projects: Vec<(String, Value)> = doc["projects"]
+        .as_object()
+        .map(|m| m.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+        .unwrap_or_default();
+
+    let mut added = Vec::new();
+    for (project_id, project) in projects {
+        if existing.iter().any(|i| i["project_id"] == project_id.as_str()) {
+            continue;
+        }
+        let session_ids: Vec<&String> = sessions
+            .iter()
+            .filter(|(_, s)| s["project_id"] == project_id.as_str())
+            .map(|(id, _)| id)
+            .collect();
+        let Some(session_id) = session_ids.first() else {
+            continue;
+        };
+        let of_project = |items: &[Value]| -> Vec<Value> {
+            items
+                .iter()
+                .filter(|v| v["project_id"] == project_id.as_str())
+                .cloned()
+                .collect()
+        };
+        let idea_ids: Vec<Value> = ideas
+            .iter()
+            .filter(|e| session_ids.iter().any(|id| e["session_id"] == id.as_str()))
+            .map(|e| e["event_id"].clone())
+            .collect();
+        let prd_version = of_project(&prds).iter().filter_map(|v| v["version"].as_u64()).max();
+        let task_ids: Vec<Value> = of_project(&tasks).iter().map(|t| t["task_id"].clone()).collect();
+        let deploy_id = of_project(&deploys).last().map(|d| d["deploy_id"].clone());
+        let delivered = deploy_id.is_some();
+        added.push(json!({
+            "iteration_id": Uuid::new_v4().to_string(),
+            "project_id": project_id,
+            "session_id": session_id,
+            "number": 1,
+            "idea_event_ids": idea_ids,
+            "prd_version": prd_version,
+            "task_ids": task_ids,
+            "deploy_id": deploy_id,
+            "status": if delivered { "DELIVERED" } else { "OPEN" },
+            "opened_at": project["created_at"],
+            "delivered_at": if delivered { project["updated_at"].clone() } else { Value::Null },
+        }));
+    }
+    if let Some(iterations) = doc["iterations"].as_array_mut() {
+        iterations.extend(added);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn project(id: &str) -> Value {
+        json!({
+            "id": id,
+            "name": "demo",
+            "status": "ACTIVE",
+            "created_at": "2024-01-01T00:00:00Z",
+            "updated_at": "2024-01-02T00:00:00Z",
+        })
+    }
+
+    fn session(id: &str, project_id: &str) -> Value {
+        json!({
+            "session_id": id,
+            "project_id": project_id,
+            "stage": "CLARIFYING",
+            "context_snapshot": "",
+        })
+    }
+
+    fn idea(id: &str, session_id: &str, input: &str) -> Value {
+        json!({
+            "event_id": id,
+            "session_id": session_id,
+            "user_input": input,
+            "created_at": "2024-01-01T00:00:00Z",
+        })
+    }
+
+    /// 引入版本号之前的 state.json：没有 schema_version，只有项目、会话与想法
+    fn v0_fixture() -> Value {
+        json!({
+            "projects": { "p1": project("p1") },
+            "sessions": { "s1": session("s1", "p1") },
+            "idea_events": [idea("e1", "s1", "做一个记账工具"), idea("e2", "s1", "支持导出")],
+            "task_runs": null,
+        })
+    }
+
+    /// v1：集合齐全，但还没有对话记录
+    fn v1_fixture() -> Value {
+        json!({
+            "schema_version": 1,
+            "projects": { "p1": project("p1") },
+            "sessions": { "s1": session("s1", "p1") },
+            "idea_events": [idea("e1", "s1", "做一个记账工具")],
+            "conversation_turns": [],
+            "prd_versions": [
+                { "prd_id": "v1", "project_id": "p1", "version": 1, "content": "# v1", "diff_from_prev": null },
+                { "prd_id": "v2", "project_id": "p1", "version": 2, "content": "# v2", "diff_from_prev": null },
+            ],
+            "task_runs": [
+                { "task_id": "t1", "project_id": "p1", "plan_json": "", "run_status": "DONE", "logs": "" },
+            ],
+            "deployment_runs": [
+                { "deploy_id": "d1", "project_id": "p1", "env": "staging", "version": "v1",
+                  "result": "ok", "rollback_hint": "" },
+            ],
+        })
+    }
+
+    /// v2：已有对话记录，还没有迭代
+    fn v2_fixture() -> Value {
+        json!({
+            "schema_version": 2,
+            "projects": { "p1": project("p1"), "p2": project("p2") },
+            "sessions": { "s1": session("s1", "p1") },
+            "idea_events": [idea("e1", "s1", "做一个记账工具")],
+            "conversation_turns": [
+                { "session_id": "s1", "role": "user", "content": "做一个记账工具",
+                  "created_at": "2024-01-01T00:00:00Z" },
+                { "session_id": "s1", "role": "assistant", "content": "面向谁？",
+                  "created_at": "2024-01-01T00:00:01Z" },
+            ],
+            "prd_versions": [],
+            "task_runs": [],
+            "deployment_runs": [],
+        })
+    }
+
+    #[test]
+    fn migrations_are_contiguous_and_ascending() {
+        let versions: Vec<u32> = MIGRATIONS.iter().map(|(v, _, _)| *v).collect();
+        let expected: Vec<u32> = (1..=versions.len() as u32).collect();
+        assert_eq!(versions, expected);
+        assert_eq!(CURRENT_SCHEMA_VERSION, *versions.last().unwrap());
+    }
+
+    #[test]
+    fn version_defaults_to_zero() {
+        assert_eq!(version_of(&v0_fixture()), 0);
+        assert_eq!(version_of(&v2_fixture()), 2);
+    }
+
+    #[test]
+    fn fill_missing_collections_adds_empty_collections() {
+        let mut doc = v0_fixture();
+        fill_missing_collections(&mut doc).unwrap();
+        assert_eq!(doc["task_runs"], json!([]));
+        assert_eq!(doc["conversation_turns"], json!([]));
+        assert_eq!(doc["prd_versions"], json!([]));
+        assert_eq!(doc["deployment_runs"], json!([]));
+        assert_eq!(doc["idea_events"].as_array().unwrap().len(), 2);
+        assert!(doc["projects"]["p1"].is_object());
+    }
+
+    #[test]
+    fn idea_events_become_user_turns_in_order() {
+        let mut doc = v0_fixture();
+        fill_missing_collections(&mut doc).unwrap();
+        idea_events_to_conversation(&mut doc).unwrap();
+        let turns = doc["conversation_turns"].as_array().unwrap();
+        assert_eq!(turns.len(), 2);
+        assert_eq!(turns[0]["role"], "user");
+        assert_eq!(turns[0]["content"], "做一个记账工具");
+        assert_eq!(turns[1]["content"], "支持导出");
+    }
+
+    #[test]
+    fn existing_turns_are_not_replaced() {
+        let mut doc = v2_fixture();
+        idea_events_to_conversation(&mut doc).unwrap();
+        assert_eq!(doc["conversation_turns"], v2_fixture()["conversation_turns"]);
+    }
+
+    #[test]
+    fn backfill_iterations_covers_projects_with_sessions() {
+        let mut doc = v1_fixture();
+        backfill_iterations(&mut doc).unwrap();
+        let iterations = doc["iterations"].as_array().unwrap();
+        assert_eq!(iterations.len(), 1);
+        let first = &iterations[0];
+        assert_eq!(first["project_id"], "p1");
+        assert_eq!(first["session_id"], "s1");
+        assert_eq!(first["number"], 1);
+        assert_eq!(first["idea_event_ids"], json!(["e1"]));
+        assert_eq!(first["prd_version"], 2);
+        assert_eq!(first["task_ids"], json!(["t1"]));
+        assert_eq!(first["deploy_id"], "d1");
+        assert_eq!(first["status"], "DELIVERED");
+        assert_eq!(first["delivered_at"], "2024-01-02T00:00:00Z");
+
+        // 已有迭代的项目不重复补建
+        backfill_iterations(&mut doc).unwrap();
+        assert_eq!(doc["iterations"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn upgrade_v0_runs_every_migration() {
+        let state = upgrade(v0_fixture()).unwrap();
+        assert_eq!(state.schema_version, CURRENT_SCHEMA_VERSION);
+        assert!(state.task_runs.is_empty());
+        let contents: Vec<&str> = state.conversation_turns.iter().map(|t| t.content.as_str()).collect();
+        assert_eq!(contents, ["做一个记账工具", "支持导出"]);
+        assert_eq!(state.iterations.len(), 1);
+        let iteration = &state.iterations[0];
+        assert_eq!(iteration.idea_event_ids, ["e1", "e2"]);
+        assert_eq!(iteration.prd_version, None);
+        assert_eq!(iteration.status, crate::models::IterationStatus::Open);
+    }
+
+    #[test]
+    fn upgrade_v1_converts_ideas_and_backfills() {
+        let state = upgrade(v1_fixture()).unwrap();
+        assert_eq!(state.schema_version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(state.conversation_turns.len(), 1);
+        assert_eq!(state.conversation_turns[0].role, "user");
+        assert_eq!(state.iterations.len(), 1);
+        assert_eq!(state.iterations[0].prd_version, Some(2));
+        assert_eq!(state.iterations[0].deploy_id.as_deref(), Some("d1"));
+    }
+
+    #[test]
+    fn upgrade_v2_only_runs_later_migrations() {
+        let state = upgrade(v2_fixture()).unwrap();
+        assert_eq!(state.schema_version, CURRENT_SCHEMA_VERSION);
+        let roles: Vec<&str> = state.conversation_turns.iter().map(|t| t.role.as_str()).collect();
+        assert_eq!(roles, ["user", "assistant"]);
+        // p2 没有会话，不补建迭代
+        assert_eq!(state.iterations.len(), 1);
+        assert_eq!(state.iterations[0].project_id, "p1");
+    }
+
+    #[test]
+    fn upgrade_current_version_is_a_no_op() {
+        let mut doc = v2_fixture();
+        doc["schema_version"] = json!(CURRENT_SCHEMA_VERSION);
+        let state = upgrade(doc).unwrap();
+        assert!(state.iterations.is_empty());
+    }
+
+    #[test]
+    fn upgrade_rejects_newer_state() {
+        let mut doc = v2_fixture();
+        doc["schema_version"] = json!(CURRENT_SCHEMA_VERSION + 1);
+        let err = upgrade(doc).unwrap_err().to_string();
+        assert!(err.contains(&(CURRENT_SCHEMA_VERSION + 1).to_string()), "{err}");
+    }
+
+    #[test]
+    fn upgrade_rejects_non_object() {
+        assert!(upgrade(json!([])).is_err());
+    }
+}
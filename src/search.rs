@@ -0,0 +1,555 @@
+//! 全文检索：对话、PRD 与 workspace 文件。
+//!
+//! 状态存于 PostgreSQL 时使用其全文索引（tsvector + GIN），workspace 文件同步进
+//! workspace_documents 表；否则在 `.celadon/search/index.json` 维护本地倒排索引，
+//! 每次检索前按指纹增量更新。分词规则：ASCII/拉丁词按单词，中日韩文字按二元组。
+
+use crate::bundle::SKIPPED_DIRS;
+use crate::common::AppResult;
+use crate::db;
+use crate::models::{ProjectStatus, StateStore};
+use crate::store;
+use serde::{Deserialize, Serialize};
+use sqlx::Row;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+/// 超过该大小的文件不进入索引
+const MAX_FILE_BYTES: u64 = 256 * 1024;
+const SNIPPET_BEFORE: usize = 40;
+const SNIPPET_AFTER: usize = 100;
+/// BM25 参数
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocKind {
+    Conversation,
+    Prd,
+    File,
+}
+
+/// 待索引的文档；reference 为对话角色、PRD 版本（v2）或 workspace 内相对路径
+#[derive(Debug, Clone)]
+pub struct SearchDocument {
+    pub key: String,
+    pub project_id: String,
+    pub session_id: Option<String>,
+    pub kind: DocKind,
+    pub reference: String,
+    pub content: String,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub project_id: String,
+    pub project_name: String,
+    pub session_id: Option<String>,
+    pub kind: DocKind,
+    pub reference: String,
+    pub snippet: String,
+    pub score: f64,
+}
+
+pub enum SearchIndex {
+    Postgres { pool: db::Pool, user_id: Uuid },
+    Local { path: PathBuf },
+}
+
+impl SearchIndex {
+    /// 与状态后端保持一致：状态在 PostgreSQL 时检索 PostgreSQL，否则用本地索引
+    pub fn open(storage_dir: &Path, pool: Option<&db::Pool>, user_id: Option<Uuid>) -> Self {
+        let kind = store::backend_kind();
+        match (pool, user_id) {
+            (Some(pool), Some(user_id)) if kind.as_deref().is_none_or(|k| k == "postgres") => {
+                SearchIndex::Postgres {
+                    pool: pool.clone(),
+                    user_id,
+                }
+            }
+            _ => SearchIndex::Local {
+                path: storage_dir.join("search").join("index.json"),
+            },
+        }
+    }
+
+    /// state 需包含全部项目；本地索引还需要已补齐的对话与 PRD
+    pub async fn search(
+        &self,
+        state: &StateStore,
+        workspaces: &Path,
+        query: &str,
+        limit: usize,
+    ) -> AppResult<Vec<SearchHit>> {
+        let files = workspace_documents(state, workspaces);
+        match self {
+            SearchIndex::Postgres { pool, user_id } => {
+                sync_workspace_rows(pool, *user_id, &files).await?;
+                search_postgres(pool, *user_id, query, limit).await
+            }
+            SearchIndex::Local { path } => {
+                let mut index = LocalIndex::load(path);
+                let mut docs = state_documents(state);
+                docs.extend(files);
+                if index.sync(docs) {
+                    index.save(path)?;
+                }
+                Ok(index.search(state, query, limit))
+            }
+        }
+    }
+}
+
+/// 未删除项目的对话轮次与 PRD 版本
+fn state_documents(state: &StateStore) -> Vec<SearchDocument> {
+    let live = live_projects(state);
+    let mut docs = Vec::new();
+    let mut turn_index: HashMap<&str, usize> = HashMap::new();
+    for turn in &state.conversation_turns {
+        let index = turn_index.entry(turn.session_id.as_str()).or_default();
+        *index += 1;
+        let Some(session) = state.sessions.get(&turn.session_id) else {
+            continue;
+        };
+        if !live.contains(session.project_id.as_str()) {
+            continue;
+        }
+        docs.push(SearchDocument {
+            key: format!("turn:{}:{}", turn.session_id, index),
+            project_id: session.project_id.clone(),
+            session_id: Some(turn.session_id.clone()),
+            kind: DocKind::Conversation,
+            reference: turn.role.clone(),
+            fingerprint: format!("{}:{}", turn.created_at, turn.content.len()),
+            content: turn.content.clone(),
+        });
+    }
+    for prd in state.prd_versions.iter().filter(|v| live.contains(v.project_id.as_str())) {
+        docs.push(SearchDocument {
+            key: format!("prd:{}", prd.prd_id),
+            project_id: prd.project_id.clone(),
+            session_id: None,
+            kind: DocKind::Prd,
+            reference: format!("v{}", prd.version),
+            fingerprint: prd.content.len().to_string(),
+            content: prd.content.clone(),
+        });
+    }
+    docs
+}
+
+/// 未删除项目 workspace 下的文本文件（跳过依赖与构建目录、二进制和大文件）
+fn workspace_documents(state: &StateStore, workspaces: &Path) -> Vec<SearchDocument> {
+    let mut docs = Vec::new();
+    for project_id in live_projects(state) {
+        let root = workspaces.join(project_id);
+        collect_files(&root, &root, project_id, &mut docs);
+    }
+    docs
+}
+
+fn collect_files(root: &Path, dir: &Path, project_id: &str, docs: &mut Vec<SearchDocument>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_dir() {
+            if !SKIPPED_DIRS.iter().any(|s| entry.file_name() == *s) {
+                collect_files(root, &path, project_id, docs);
+            }
+            continue;
+        }
+        if meta.len() > MAX_FILE_BYTES {
+            continue;
+        }
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let relative = path
+            .strip_prefix(root)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        docs.push(SearchDocument {
+            key: format!("file:{project_id}:{relative}"),
+            project_id: project_id.to_string(),
+            session_id: None,
+            kind: DocKind::File,
+            reference: relative,
+            fingerprint: format!("{modified}:{}", meta.len()),
+            content,
+        });
+    }
+}
+
+fn live_projects(state: &StateStore) -> HashSet<&str> {
+    state
+        .projects
+        .values()
+        .filter(|p| p.status != ProjectStatus::Deleted)
+        .map(|p| p.id.as_str())
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexedDoc {
+    project_id: String,
+    session_id: Option<String>,
+    kind: DocKind,
+    reference: String,
+    fingerprint: String,
+    content: String,
+    length: u32,
+}
+
+/// 本地倒排索引：词 → (文档 → 词频)
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LocalIndex {
+    docs: HashMap<String, IndexedDoc>,
+    postings: HashMap<String, HashMap<String, u32>>,
+}
+
+impl LocalIndex {
+    /// 索引文件缺失或损坏时从空索引开始，下次 sync 会全量重建
+    fn load(path: &Path) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, path: &Path) -> AppResult<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// 增量更新：移除已消失或指纹变化的文档，再加入新文档；返回索引是否有变化
+    fn sync(&mut self, docs: Vec<SearchDocument>) -> bool {
+        let current: HashMap<&str, &str> = docs
+            .iter()
+            .map(|d| (d.key.as_str(), d.fingerprint.as_str()))
+            .collect();
+        let stale: Vec<String> = self
+            .docs
+            .iter()
+            .filter(|(key, doc)| current.get(key.as_str()) != Some(&doc.fingerprint.as_str()))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &stale {
+            self.remove(key);
+        }
+        let mut changed = !stale.is_empty();
+        for doc in docs {
+            if self.docs.contains_key(&doc.key) {
+                continue;
+            }
+            self.insert(doc);
+            changed = true;
+        }
+        changed
+    }
+
+    fn insert(&mut self, doc: SearchDocument) {
+        let tokens = tokenize(&doc.content);
+        for token in &tokens {
+            *self
+                .postings
+                .entry(token.clone())
+                .or_default()
+                .entry(doc.key.clone())
+                .or_default() += 1;
+        }
+        self.docs.insert(
+            doc.key,
+            IndexedDoc {
+                project_id: doc.project_id,
+                session_id: doc.session_id,
+                kind: doc.kind,
+                reference: doc.reference,
+                fingerprint: doc.fingerprint,
+                content: doc.content,
+                length: tokens.len() as u32,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(doc) = self.docs.remove(key) else {
+            return;
+        };
+        for token in tokenize(&doc.content) {
+            if let Some(posting) = self.postings.get_mut(&token) {
+                posting.remove(key);
+                if posting.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+
+    /// BM25 打分，按分数降序
+    fn search(&self, state: &StateStore, query: &str, limit: usize) -> Vec<SearchHit> {
+        let terms: HashSet<String> = tokenize(query).into_iter().collect();
+        let total = self.docs.len() as f64;
+        let avg_len = self.docs.values().map(|d| d.length as f64).sum::<f64>() / total.max(1.0);
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+        // 拉丁词同时按前缀匹配（webhook 命中 webhooks）
+        let postings = self.postings.iter().filter(|(token, _)| {
+            terms.iter().any(|term| {
+                *token == term || (term.is_ascii() && term.len() >= 3 && token.starts_with(term.as_str()))
+            })
+        });
+        for (_, posting) in postings {
+            let df = posting.len() as f64;
+            let idf = (1.0 + (total - df + 0.5) / (df + 0.5)).ln();
+            for (key, tf) in posting {
+                let len = self.docs[key].length as f64;
+                let tf = *tf as f64;
+                let score = idf * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * len / avg_len.max(1.0)));
+                *scores.entry(key.as_str()).or_default() += score;
+            }
+        }
+        let mut ranked: Vec<(&str, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(key, score)| {
+                let doc = &self.docs[key];
+                SearchHit {
+                    project_id: doc.project_id.clone(),
+                    project_name: project_name(state, &doc.project_id),
+                    session_id: doc.session_id.clone(),
+                    kind: doc.kind,
+                    reference: doc.reference.clone(),
+                    snippet: snippet(&doc.content, query),
+                    score,
+                }
+            })
+            .collect()
+    }
+}
+
+fn project_name(state: &StateStore, project_id: &str) -> String {
+    state
+        .projects
+        .get(project_id)
+        .map(|p| p.name.clone())
+        .unwrap_or_default()
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'   // 平假名、片假名
+        | '\u{3400}'..='\u{4dbf}' // CJK 扩展 A
+        | '\u{4e00}'..='\u{9fff}' // CJK 统一表意文字
+        | '\u{ac00}'..='\u{d7af}' // 谚文
+        | '\u{f900}'..='\u{faff}')
+}
+
+/// 拉丁词转小写（至少两个字符）；连续的中日韩文字切成二元组，单字保留为一元
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut cjk: Vec<char> = Vec::new();
+    let flush_word = |word: &mut String, tokens: &mut Vec<String>| {
+        if word.chars().count() >= 2 {
+            tokens.push(std::mem::take(word));
+        } else {
+            word.clear();
+        }
+    };
+    let flush_cjk = |cjk: &mut Vec<char>, tokens: &mut Vec<String>| {
+        match cjk.len() {
+            0 => {}
+            1 => tokens.push(cjk[0].to_string()),
+            _ => tokens.extend(cjk.windows(2).map(|w| w.iter().collect::<String>())),
+        }
+        cjk.clear();
+    };
+    for c in text.chars() {
+        if is_cjk(c) {
+            flush_word(&mut word, &mut tokens);
+            cjk.push(c);
+        } else if c.is_alphanumeric() {
+            flush_cjk(&mut cjk, &mut tokens);
+            word.extend(c.to_lowercase());
+        } else {
+            flush_word(&mut word, &mut tokens);
+            flush_cjk(&mut cjk, &mut tokens);
+        }
+    }
+    flush_word(&mut word, &mut tokens);
+    flush_cjk(&mut cjk, &mut tokens);
+    tokens
+}
+
+/// 以第一个命中的查询词为中心截取片段；都未命中时取开头
+fn snippet(content: &str, query: &str) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    let lower: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+    let position = tokenize(query)
+        .iter()
+        .filter_map(|term| {
+            let term: Vec<char> = term.chars().collect();
+            lower.windows(term.len()).position(|w| w == term.as_slice())
+        })
+        .min()
+        .unwrap_or(0);
+    let start = position.saturating_sub(SNIPPET_BEFORE);
+    let end = (position + SNIPPET_AFTER).min(chars.len());
+    let mut out: String = chars[start..end].iter().collect();
+    out = out.split_whitespace().collect::<Vec<_>>().join(" ");
+    if start > 0 {
+        out.insert(0, '…');
+    }
+    if end < chars.len() {
+        out.push('…');
+    }
+    out
+}
+
+/// 把 workspace 文件同步进 workspace_documents：指纹变化的覆盖，已消失的删除
+async fn sync_workspace_rows(pool: &db::Pool, user_id: Uuid, files: &[SearchDocument]) -> AppResult<()> {
+    let rows = sqlx::query("SELECT project_id, path, fingerprint FROM workspace_documents WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| format!("读取检索索引失败: {e}"))?;
+    let existing: HashMap<(String, String), String> = rows
+        .iter()
+        .map(|r| ((r.get("project_id"), r.get("path")), r.get("fingerprint")))
+        .collect();
+
+    let mut tx = pool
+        .begin()
+        .await
+        .map_err(|e| format!("开启事务失败: {e}"))?;
+    let mut seen = HashSet::new();
+    for file in files {
+        let key = (file.project_id.clone(), file.reference.clone());
+        let unchanged = existing.get(&key) == Some(&file.fingerprint);
+        seen.insert(key);
+        if unchanged {
+            continue;
+        }
+        // 文本里的 NUL 字符无法存入 TEXT 列
+        let content = file.content.replace('\0', "");
+        sqlx::query(
+            "INSERT INTO workspace_documents (user_id, project_id, path, content, fingerprint, updated_at)
+             VALUES ($1, $2, $3, $4, $5, now())
+             ON CONFLICT (project_id, path)
+             DO UPDATE SET content = $4, fingerprint = $5, updated_at = now()",
+        )
+        .bind(user_id)
+        .bind(&file.project_id)
+        .bind(&file.reference)
+        .bind(content)
+        .bind(&file.fingerprint)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| format!("写入检索索引失败: {e}"))?;
+    }
+    for (project_id, path) in existing.keys().filter(|k| !seen.contains(*k)) {
+        sqlx::query("DELETE FROM workspace_documents WHERE user_id = $1 AND project_id = $2 AND path = $3")
+            .bind(user_id)
+            .bind(project_id)
+            .bind(path)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| format!("清理检索索引失败: {e}"))?;
+    }
+    tx.commit().await.map_err(|e| format!("提交事务失败: {e}"))?;
+    Ok(())
+}
+
+/// tsvector 按词匹配并用 ts_rank 排序；'simple' 配置不切分中文，因此再用 ILIKE 兜底子串匹配
+async fn search_postgres(pool: &db::Pool, user_id: Uuid, query: &str, limit: usize) -> AppResult<Vec<SearchHit>> {
+    let escaped = query
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    let pattern = format!("%{escaped}%");
+    let rows = sqlx::query(
+        "WITH q AS (SELECT plainto_tsquery('simple', $2) AS query)
+         SELECT kind, project_id, project_name, session_id, reference, content, score::float8 AS score FROM (
+             SELECT 'conversation' AS kind, p.id AS project_id, p.name AS project_name,
+                    t.session_id AS session_id, t.role AS reference, t.content AS content,
+                    ts_rank(to_tsvector('simple', t.content), q.query)
+                        + CASE WHEN t.content ILIKE $3 THEN 0.1 ELSE 0 END AS score
+             FROM conversation_turns t
+             JOIN sessions s ON s.session_id = t.session_id
+             JOIN projects p ON p.id = s.project_id, q
+             WHERE p.user_id = $1 AND p.status <> 'DELETED'
+               AND (to_tsvector('simple', t.content) @@ q.query OR t.content ILIKE $3)
+             UNION ALL
+             SELECT 'prd', p.id, p.name, NULL::text, 'v' || v.version, v.content,
+                    ts_rank(to_tsvector('simple', v.content), q.query)
+                        + CASE WHEN v.content ILIKE $3 THEN 0.1 ELSE 0 END
+             FROM prd_versions v
+             JOIN projects p ON p.id = v.project_id, q
+             WHERE p.user_id = $1 AND p.status <> 'DELETED'
+               AND (to_tsvector('simple', v.content) @@ q.query OR v.content ILIKE $3)
+             UNION ALL
+             SELECT 'file', p.id, p.name, NULL::text, w.path, w.content,
+                    ts_rank(to_tsvector('simple', w.content), q.query)
+                        + CASE WHEN w.content ILIKE $3 THEN 0.1 ELSE 0 END
+             FROM workspace_documents w
+             JOIN projects p ON p.id = w.project_id, q
+             WHERE w.user_id = $1 AND p.status <> 'DELETED'
+               AND (to_tsvector('simple', w.content) @@ q.query OR w.content ILIKE $3)
+         ) hits
+         ORDER BY score DESC
+         LIMIT $4",
+    )
+    .bind(user_id)
+    .bind(query)
+    .bind(pattern)
+    .bind(limit as i64)
+    .fetch_all(pool)
+    .await
+    .map_err(|e| format!("全文检索失败: {e}"))?;
+
+    Ok(rows
+        .iter()
+        .map(|r| {
+            let content: String = r.get("content");
+            SearchHit {
+                project_id: r.get("project_id"),
+                project_name: r.get("project_name"),
+                session_id: r.get("session_id"),
+                kind: match r.get::<String, _>("kind").as_str() {
+                    "conversation" => DocKind::Conversation,
+                    "prd" => DocKind::Prd,
+                    _ => DocKind::File,
+                },
+                reference: r.get("reference"),
+                snippet: snippet(&content, query),
+                score: r.get("score"),
+            }
+        })
+        .collect())
+}
@@ -1,55 +1,91 @@
-use crate::clients::{LlmGateway, ZeneClient};
-use crate::common::AppResult;
+use crate::bundle::{self, ProjectBundle};
+use crate::clients::{DevEvent, DevRun, LlmError, LlmGateway, ZeneClient};
+use crate::common::{AppResult, StateConflict};
+use crate::context;
+use crate::crypto::{self, SecretKey};
+use crate::i18n::{self, Locale, Message, t};
 use crate::db;
 use crate::models::{
-    ConversationTurn, DeploymentRun, IdeaEvent, PrdVersion, Project, Session, Stage, StateStore,
+    Budget, ConversationSummary, ConversationTurn, DeploymentRun, IdeaEvent, Iteration, IterationStatus, PrdVersion, Project,
+    ProjectEvent, ProjectSettingsPatch, ProjectStatus, RequirementsSheet, Session, Stage, StateChange,
+    StateStore, TaskRun, TestResult, UsageRecord, UsageRole,
 };
+use crate::prompts::{self, PromptName, PromptStore, PromptVars};
+use crate::search::SearchIndex;
+use crate::store::{self, StateBackend};
+use crate::usage::{self, BudgetExceeded, BudgetScope};
 use crate::utils::{now_timestamp, suggest_project_name};
+use crate::workflow::{self, Action, StageError};
+use chrono::{DateTime, Duration, Utc};
 use serde_json::{Value, json};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
 use uuid::Uuid;
+use zene::AgentEvent;
 use axum::response::sse::Event;
 use futures_core::stream::Stream;
 use std::convert::Infallible;
 use async_stream::stream;
 use tokio::sync::mpsc;
-use zene::AgentEvent;
+use futures::StreamExt;
+
+const MAX_COMMIT_RETRIES: usize = 3;
+
+/// 流式澄清过程中推送给客户端的事件
+pub enum ClarifyEvent {
+    Started { project_id: String, session_id: String },
+    Delta(String),
+    /// 回复已完整保存，内容同 append_idea 的返回
+    Done(Value),
+    /// code 为模型错误代码（见 LlmError::code），其他失败为 None
+    Error { message: String, code: Option<&'static str> },
+}
+
+impl ClarifyEvent {
+    fn error(e: &(dyn std::error::Error + Send + Sync + 'static)) -> Self {
+        ClarifyEvent::Error {
+            message: e.to_string(),
+            code: e
+                .downcast_ref::<LlmError>()
+                .map(LlmError::code)
+                .or_else(|| e.is::<BudgetExceeded>().then_some("budget_exceeded")),
+        }
+    }
+}
+/// 开发运行推送给客户端的事件
+pub enum DevLogEvent {
+    Agent(DevEvent),
+    /// 用量达到上限，运行已中止
+    BudgetExceeded(BudgetExceeded),
+}
+
+/// 软删除后可恢复的天数，可由 CELADON_RESTORE_WINDOW_DAYS 覆盖
+const DEFAULT_RESTORE_WINDOW_DAYS: i64 = 30;
 
 pub struct CeladonService {
     storage_dir: PathBuf,
     pub state: StateStore,
+    backend: Box<dyn StateBackend>,
+    changes: Vec<ProjectEvent>,
+    /// 待提交修改所依赖对象在修改前的值，见 StateChange::precondition
+    preconditions: HashMap<String, Value>,
+    hydrated: HashSet<String>,
     zene_client: ZeneClient,
     llm_gateway: LlmGateway,
     pool: Option<db::Pool>,
     user_id: Option<Uuid>,
+    /// 提示词语言，创建时取自当前请求
+    locale: Locale,
 }
 
 impl CeladonService {
     pub async fn load(storage_dir: PathBuf) -> AppResult<Self> {
         fs::create_dir_all(&storage_dir)?;
-        let state_file = storage_dir.join("state.json");
-        let mut state: StateStore = if state_file.exists() {
-            let content = fs::read_to_string(state_file)?;
-            serde_json::from_str(&content)?
-        } else {
-            StateStore::default()
-        };
-        migrate_idea_events_to_conversation(&mut state);
-        let llm_gateway = LlmGateway::load(None).await
+        let backend = store::open_backend(&storage_dir, None, None).await?;
+        let llm_gateway = LlmGateway::load(None, None).await
             .map_err(|e| format!("{e}. 请设置 LLM API KEY"))?;
-        
-        let mut zene_client = ZeneClient::new();
-        let _ = zene_client.init(llm_gateway.to_agent_config()).await;
-        
-        Ok(Self {
-            storage_dir,
-            state,
-            zene_client,
-            llm_gateway,
-            pool: None,
-            user_id: None,
-        })
+        Self::with_backend(storage_dir, backend, llm_gateway, None, None).await
     }
 
     /// 从数据库加载对应用户状态（需已配置 DATABASE_URL）
@@ -58,24 +94,39 @@ impl CeladonService {
         pool: db::Pool,
         user_id: Uuid,
     ) -> AppResult<Self> {
-        let mut state = db::load_user_state(&pool, user_id).await?;
-        migrate_idea_events_to_conversation(&mut state);
+        let backend = store::open_backend(&storage_dir, Some(&pool), Some(user_id)).await?;
         let user_dir = storage_dir.join(user_id.to_string());
         fs::create_dir_all(&user_dir)?;
-        let llm_gateway = LlmGateway::load(Some(&pool)).await
+        let llm_gateway = LlmGateway::load(Some(&pool), Some(user_id)).await
             .map_err(|e| format!("{e}. 请在系统设置中配置 LLM API KEY"))?;
-            
+        Self::with_backend(user_dir, backend, llm_gateway, Some(pool), Some(user_id)).await
+    }
+
+    async fn with_backend(
+        storage_dir: PathBuf,
+        backend: Box<dyn StateBackend>,
+        llm_gateway: LlmGateway,
+        pool: Option<db::Pool>,
+        user_id: Option<Uuid>,
+    ) -> AppResult<Self> {
+        let state = backend.load().await?;
+
         let mut zene_client = ZeneClient::new();
         // Warm the engine immediately
         let _ = zene_client.init(llm_gateway.to_agent_config()).await;
-        
+
         Ok(Self {
-            storage_dir: user_dir,
+            storage_dir,
             state,
+            backend,
+            changes: Vec::new(),
+            preconditions: HashMap::new(),
+            hydrated: HashSet::new(),
             zene_client,
             llm_gateway,
-            pool: Some(pool),
-            user_id: Some(user_id),
+            pool,
+            user_id,
+            locale: i18n::current(),
         })
     }
 
@@ -83,16 +134,145 @@ impl CeladonService {
         self.storage_dir.join("workspaces").join(project_id)
     }
 
-    async fn persist(&self) -> AppResult<()> {
-        if let (Some(pool), Some(uid)) = (self.pool.as_ref(), self.user_id) {
-            db::save_user_state(pool, uid, &self.state).await?;
-        } else {
-            self.save()?;
+    pub fn prd_dir(&self, project_id: &str) -> PathBuf {
+        self.storage_dir.join("prd").join(project_id)
+    }
+
+    /// 以 state.revision 为期望版本提交修改；遇到并发写入冲突时，
+    /// 若本次修改依赖的对象未被他人改动，则在最新状态上重放并重试，
+    /// 否则（或多次失败后）把冲突交给调用方
+    async fn persist(&mut self) -> AppResult<()> {
+        let changes = std::mem::take(&mut self.changes);
+        let preconditions = std::mem::take(&mut self.preconditions);
+        let mut retries = 0;
+        loop {
+            match self.backend.commit(self.state.revision, &changes).await {
+                Ok(revision) => {
+                    self.state.revision = revision;
+                    return Ok(());
+                }
+                Err(e) if e.is::<StateConflict>() && retries < MAX_COMMIT_RETRIES => {
+                    retries += 1;
+                    if !self.rebase(&changes, &preconditions).await? {
+                        return Err(e);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// 重新加载最新状态并在其上重放尚未提交的修改；
+    /// 某个修改依赖的对象已被并发写入改动时返回 false，此时不能盲目重放
+    async fn rebase(
+        &mut self,
+        changes: &[ProjectEvent],
+        preconditions: &HashMap<String, Value>,
+    ) -> AppResult<bool> {
+        self.state = self.backend.load().await?;
+        let projects: Vec<String> = self.hydrated.drain().collect();
+        for project_id in projects {
+            self.hydrate_project(&project_id).await?;
+        }
+        let mut checked = HashSet::new();
+        for event in changes {
+            if let Some((key, current)) = event.change.precondition(&self.state)
+                && checked.insert(key.clone())
+                && preconditions.get(&key) != Some(&current)
+            {
+                return Ok(false);
+            }
+            event.change.apply(&mut self.state);
+        }
+        Ok(true)
+    }
+
+    /// 应用一次修改，并作为项目事件记入待写入列表。
+    /// 找不到所属项目的修改（会话已不存在）应用后也不会改变状态，直接忽略，不写入事件日志
+    fn record(&mut self, change: StateChange) {
+        let Some(project_id) = change.project_id(&self.state) else {
+            return;
+        };
+        if let Some((key, current)) = change.precondition(&self.state) {
+            self.preconditions.entry(key).or_insert(current);
+        }
+        change.apply(&mut self.state);
+        self.changes.push(ProjectEvent {
+            seq: 0,
+            project_id,
+            change,
+            created_at: now_timestamp(),
+        });
+    }
+
+    /// 按需从后端补齐项目明细（对话、PRD、任务、部署）
+    async fn hydrate_project(&mut self, project_id: &str) -> AppResult<()> {
+        if !self.hydrated.insert(project_id.to_string()) {
+            return Ok(());
+        }
+        if let Some(rows) = self.backend.load_project(project_id).await? {
+            self.state.conversation_turns.extend(rows.conversation_turns);
+            self.state.idea_events.extend(rows.idea_events);
+            self.state.prd_versions.extend(rows.prd_versions);
+            self.state.task_runs.extend(rows.task_runs);
+            self.state.deployment_runs.extend(rows.deployment_runs);
+            self.state.iterations.extend(rows.iterations);
+            self.state.usage.extend(rows.usage);
+        }
+        Ok(())
+    }
+
+    /// 本月用量已达用户或项目上限时拒绝新的模型调用；项目需已加载用量记录
+    async fn check_budget(&self, project_id: Option<&str>) -> AppResult<()> {
+        let since = usage::month_start();
+        if let Some(project) = project_id.and_then(|id| self.state.projects.get(id))
+            && !project.settings.budget.is_unlimited()
+        {
+            let spent = usage::spend_since(
+                self.state.usage.iter().filter(|u| u.project_id == project.id),
+                since,
+            );
+            BudgetExceeded::check(BudgetScope::Project, project.settings.budget, spent)?;
+        }
+        if let (Some(pool), Some(user_id)) = (&self.pool, self.user_id) {
+            let budget = db::get_user_budget(pool, user_id).await?;
+            if !budget.is_unlimited() {
+                let spent = db::user_spend(pool, user_id, since).await?;
+                BudgetExceeded::check(BudgetScope::User, budget, spent)?;
+            }
         }
         Ok(())
     }
 
+    /// 把模型网关记下的调用用量按价格表折算后记入项目
+    fn record_usage(&mut self, project_id: &str, session_id: &str) {
+        for call in self.llm_gateway.take_usage() {
+            self.record_call(project_id, session_id, call);
+        }
+    }
+
+    fn record_call(&mut self, project_id: &str, session_id: &str, call: usage::CallUsage) {
+        self.record(StateChange::UsageRecorded(UsageRecord {
+            usage_id: Uuid::new_v4().to_string(),
+            project_id: project_id.to_string(),
+            session_id: Some(session_id.to_string()),
+            role: call.role,
+            cost: usage::cost(
+                &self.llm_gateway.prices,
+                &call.model,
+                call.prompt_tokens,
+                call.completion_tokens,
+            ),
+            model: call.model,
+            prompt_tokens: call.prompt_tokens,
+            completion_tokens: call.completion_tokens,
+            estimated: call.estimated,
+            created_at: now_timestamp(),
+        }));
+    }
+
     pub async fn start(&mut self, idea: String, name: Option<String>) -> AppResult<Value> {
+        self.check_budget(None).await?;
         let now = now_timestamp();
         let project_id = Uuid::new_v4().to_string();
         let project_name = name.unwrap_or_else(|| suggest_project_name(&idea));
@@ -101,36 +281,45 @@ impl CeladonService {
         let project = Project {
             id: project_id.clone(),
             name: project_name.clone(),
-            status: "ACTIVE".to_string(),
+            status: ProjectStatus::Active,
             created_at: now.clone(),
             updated_at: now.clone(),
+            deleted_at: None,
+            settings: Default::default(),
         };
-        self.state.projects.insert(project_id.clone(), project);
-        self.state.sessions.insert(
-            session_id.clone(),
-            Session {
-                session_id: session_id.clone(),
-                project_id: project_id.clone(),
-                stage: Stage::Clarifying,
-                context_snapshot: idea.clone(),
-            },
-        );
+        self.hydrated.insert(project_id.clone());
+        self.record(StateChange::ProjectCreated(project));
+        self.record(StateChange::SessionCreated(Session {
+            session_id: session_id.clone(),
+            project_id: project_id.clone(),
+            stage: Stage::IdeaCollecting,
+            context_snapshot: idea.clone(),
+            stage_history: Vec::new(),
+            requirements: Default::default(),
+            summary: Default::default(),
+        }));
         self.append_conversation_turn(&session_id, "user", &idea)?;
-        self.append_idea_event(&session_id, idea.clone())?;
+        let event_id = self.append_idea_event(&session_id, idea.clone())?;
+        self.update_iteration(&session_id, &project_id, |it| it.idea_event_ids.push(event_id));
 
         // Create project workspace
         let workspace = self.workspace_dir(&project_id);
         fs::create_dir_all(&workspace)?;
 
         let history: Vec<(String, String)> = Vec::new();
+        let system = self.prompt(PromptName::Clarify, &project_id).await;
         let assistant_reply = self
             .llm_gateway
-            .clarify_round(crate::clients::CLARIFY_SYSTEM, &history, &idea)
+            .clarify_round(&system, &history, &idea)
             .await?;
         self.append_conversation_turn(&session_id, "assistant", &assistant_reply)?;
+        self.refresh_requirements(&session_id).await;
+        self.set_stage(&session_id, Stage::Clarifying, &idea);
+        self.record_usage(&project_id, &session_id);
         self.touch_project(&project_id);
         self.persist().await?;
 
+        let readiness = self.readiness(&session_id, &assistant_reply);
         Ok(json!({
             "message": "project/session created",
             "project_id": project_id,
@@ -141,12 +330,15 @@ impl CeladonService {
                 { "role": "assistant", "content": assistant_reply }
             ],
             "assistant_reply": assistant_reply,
-            "stage": "CLARIFYING"
+            "stage": "CLARIFYING",
+            "ready_for_prd": readiness.ready_for_prd,
+            "missing": readiness.missing,
+            "readiness_score": readiness.score
         }))
     }
 
     pub async fn join_waiting_list(&self, email: String, idea: String) -> AppResult<Value> {
-        let pool = self.pool.as_ref().ok_or_else(|| "数据库未启用".to_string())?;
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
         sqlx::query("INSERT INTO waiting_list (email, idea) VALUES (?, ?)")
             .bind(email)
             .bind(idea)
@@ -158,92 +350,337 @@ impl CeladonService {
     }
 
     pub async fn append_idea(&mut self, session_id: &str, text: String) -> AppResult<Value> {
+        let (project_id, stages) = self.prepare_clarify(session_id).await?;
+        let system = self.prompt(PromptName::Clarify, &project_id).await;
+        let reserved = self.reserved_tokens(&[&system, &text]);
+        let history = self.context_history(session_id, reserved).await;
+        let assistant_reply = self
+            .llm_gateway
+            .clarify_round(&system, &history, &text)
+            .await?;
+        self.finish_clarify(session_id, &project_id, &text, &assistant_reply, false, stages)
+            .await
+    }
+
+    /// 流式追加想法：后台任务持有 service，把增量文本推入通道；
+    /// 正常结束时保存完整回复，客户端断开或模型出错时保存带 partial 标记的回复
+    pub async fn append_idea_stream(
+        mut self,
+        session_id: String,
+        text: String,
+    ) -> AppResult<mpsc::UnboundedReceiver<ClarifyEvent>> {
+        let (project_id, stages) = self.prepare_clarify(&session_id).await?;
+        let system = self.prompt(PromptName::Clarify, &project_id).await;
+        let reserved = self.reserved_tokens(&[&system, &text]);
+        let history = self.context_history(&session_id, reserved).await;
+        let mut deltas = self
+            .llm_gateway
+            .clarify_stream(&system, &history, &text)
+            .await?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let _ = sender.send(ClarifyEvent::Started {
+            project_id: project_id.clone(),
+            session_id: session_id.clone(),
+        });
+
+        // 后台任务不在请求的语言作用域内，需带上创建时的语言
+        let locale = self.locale;
+        tokio::spawn(i18n::scope(locale, async move {
+            let mut reply = String::new();
+            let mut failure: Option<Box<dyn std::error::Error + Send + Sync>> = None;
+            loop {
+                tokio::select! {
+                    chunk = deltas.next() => match chunk {
+                        Some(Ok(delta)) => {
+                            reply.push_str(&delta);
+                            let _ = sender.send(ClarifyEvent::Delta(delta));
+                        }
+                        Some(Err(e)) => {
+                            failure = Some(e);
+                            break;
+                        }
+                        None => break,
+                    },
+                    _ = sender.closed() => {
+                        failure = Some("client disconnected".into());
+                        break;
+                    }
+                }
+            }
+            // 先释放输出流，未读完的部分也计入用量
+            drop(deltas);
+            let partial = failure.is_some();
+            let result = self
+                .finish_clarify(&session_id, &project_id, &text, &reply, partial, stages)
+                .await;
+            let event = match (result, failure) {
+                (Ok(out), None) => ClarifyEvent::Done(out),
+                (Ok(_), Some(e)) | (Err(e), _) => ClarifyEvent::error(e.as_ref()),
+            };
+            let _ = sender.send(event);
+        }));
+        Ok(receiver)
+    }
+
+    /// 流式创建项目：先建项目与会话，首轮澄清与 append_idea_stream 相同
+    pub async fn start_stream(
+        mut self,
+        idea: String,
+        name: Option<String>,
+    ) -> AppResult<mpsc::UnboundedReceiver<ClarifyEvent>> {
+        self.check_budget(None).await?;
+        let now = now_timestamp();
+        let project_id = Uuid::new_v4().to_string();
+        let session_id = Uuid::new_v4().to_string();
+        self.hydrated.insert(project_id.clone());
+        self.record(StateChange::ProjectCreated(Project {
+            id: project_id.clone(),
+            name: name.unwrap_or_else(|| suggest_project_name(&idea)),
+            status: ProjectStatus::Active,
+            created_at: now.clone(),
+            updated_at: now,
+            deleted_at: None,
+            settings: Default::default(),
+        }));
+        self.record(StateChange::SessionCreated(Session {
+            session_id: session_id.clone(),
+            project_id: project_id.clone(),
+            stage: Stage::IdeaCollecting,
+            context_snapshot: idea.clone(),
+            stage_history: Vec::new(),
+            requirements: Default::default(),
+            summary: Default::default(),
+        }));
+        fs::create_dir_all(self.workspace_dir(&project_id))?;
+        self.append_idea_stream(session_id, idea).await
+    }
+
+    /// 澄清前的校验：项目可写、阶段允许追加想法，返回项目 ID 与要经过的阶段
+    async fn prepare_clarify(&mut self, session_id: &str) -> AppResult<(String, Vec<Stage>)> {
         let session = self
             .state
             .sessions
             .get(session_id)
             .cloned()
-            .ok_or_else(|| format!("session not found: {session_id}"))?;
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
         let project_id = session.project_id.clone();
+        self.ensure_active(&project_id)?;
+        self.hydrate_project(&project_id).await?;
+        let stages = self.plan_action(&session, Action::AppendIdea)?;
+        self.check_budget(Some(&project_id)).await?;
+        Ok((project_id, stages))
+    }
 
-        let history = self.get_conversation_history(session_id);
-        let assistant_reply = self
-            .llm_gateway
-            .clarify_round(crate::clients::CLARIFY_SYSTEM, &history, &text)
-            .await?;
+    /// 记录一轮澄清：用户输入、助手回复、想法事件、迭代与阶段，然后提交
+    async fn finish_clarify(
+        &mut self,
+        session_id: &str,
+        project_id: &str,
+        text: &str,
+        reply: &str,
+        partial: bool,
+        stages: Vec<Stage>,
+    ) -> AppResult<Value> {
+        self.append_conversation_turn(session_id, "user", text)?;
+        if !reply.is_empty() || !partial {
+            self.record(StateChange::TurnAppended(ConversationTurn {
+                session_id: session_id.to_string(),
+                role: "assistant".to_string(),
+                content: reply.to_string(),
+                created_at: now_timestamp(),
+                partial,
+            }));
+        }
+        let event_id = self.append_idea_event(session_id, text.to_string())?;
+        // 上一轮已交付时，新输入开启下一轮迭代
+        let delivered = self
+            .current_iteration(project_id)
+            .is_some_and(|it| it.status == IterationStatus::Delivered);
+        if delivered {
+            let mut iteration = self.new_iteration(session_id, project_id);
+            iteration.idea_event_ids.push(event_id);
+            self.record(StateChange::IterationOpened(iteration));
+        } else {
+            self.update_iteration(session_id, project_id, |it| it.idea_event_ids.push(event_id));
+        }
 
-        self.append_conversation_turn(session_id, "user", &text)?;
-        self.append_conversation_turn(session_id, "assistant", &assistant_reply)?;
-        self.append_idea_event(session_id, text.clone())?;
+        if !partial {
+            self.refresh_requirements(session_id).await;
+        }
 
-        if let Some(s) = self.state.sessions.get_mut(session_id) {
-            s.stage = Stage::Clarifying;
-            s.context_snapshot = text.clone();
+        for stage in stages {
+            self.set_stage(session_id, stage, text);
         }
-        self.touch_project(&project_id);
+        self.record_usage(project_id, session_id);
+        self.touch_project(project_id);
         self.persist().await?;
 
-        Ok(json!({
+        let readiness = self.readiness(session_id, reply);
+        let mut out = json!({
             "message": "idea appended",
+            "project_id": project_id,
             "session_id": session_id,
             "user_text": text,
-            "assistant_reply": assistant_reply,
-            "stage": "CLARIFYING"
+            "assistant_reply": reply,
+            "partial": partial,
+            "stage": "CLARIFYING",
+            "ready_for_prd": readiness.ready_for_prd,
+            "missing": readiness.missing,
+            "readiness_score": readiness.score
+        });
+        let auto_generate = self
+            .state
+            .projects
+            .get(project_id)
+            .is_some_and(|p| p.settings.auto_generate_prd);
+        if readiness.ready_for_prd && auto_generate && !partial {
+            // 澄清已经提交，自动生成失败只在返回里说明，不影响本轮结果
+            match self.generate_prd(session_id).await {
+                Ok(prd) => out["prd"] = prd,
+                Err(e) => out["prd_error"] = json!(e.to_string()),
+            }
+        }
+        Ok(out)
+    }
+
+    fn readiness(&self, session_id: &str, reply: &str) -> workflow::Readiness {
+        let sheet = self
+            .state
+            .sessions
+            .get(session_id)
+            .map(|s| s.requirements.clone())
+            .unwrap_or_default();
+        workflow::evaluate_readiness(&sheet, reply)
+    }
+
+    pub fn project_settings(&self, project_id: &str) -> AppResult<Value> {
+        let project = self
+            .state
+            .projects
+            .get(project_id)
+            .ok_or_else(|| Message::new("project_not_found").arg("id", project_id))?;
+        Ok(json!({
+            "project_id": project_id,
+            "settings": project.settings
+        }))
+    }
+
+    pub async fn update_project_settings(
+        &mut self,
+        project_id: &str,
+        patch: ProjectSettingsPatch,
+    ) -> AppResult<Value> {
+        let mut settings = self
+            .state
+            .projects
+            .get(project_id)
+            .map(|p| p.settings.clone())
+            .ok_or_else(|| Message::new("project_not_found").arg("id", project_id))?;
+        self.ensure_active(project_id)?;
+        patch.apply(&mut settings);
+        self.record(StateChange::ProjectSettingsChanged {
+            project_id: project_id.to_string(),
+            settings: settings.clone(),
+            updated_at: now_timestamp(),
+        });
+        self.persist().await?;
+        Ok(json!({
+            "message": "project settings updated",
+            "project_id": project_id,
+            "settings": settings
         }))
     }
 
+    /// 把流式澄清事件转成 SSE：started / delta / done / error
+    pub fn stream_clarify_events(
+        mut receiver: mpsc::UnboundedReceiver<ClarifyEvent>,
+    ) -> impl Stream<Item = Result<Event, Infallible>> {
+        stream! {
+            while let Some(event) = receiver.recv().await {
+                let event = match event {
+                    ClarifyEvent::Started { project_id, session_id } => Event::default()
+                        .event("started")
+                        .data(json!({ "project_id": project_id, "session_id": session_id }).to_string()),
+                    ClarifyEvent::Delta(content) => Event::default()
+                        .event("delta")
+                        .data(json!({ "content": content }).to_string()),
+                    ClarifyEvent::Done(out) => Event::default().event("done").data(out.to_string()),
+                    ClarifyEvent::Error { message, code } => Event::default()
+                        .event("error")
+                        .data(json!({ "error": message, "code": code }).to_string()),
+                };
+                yield Ok(event);
+            }
+        }
+    }
+
     pub async fn generate_prd(&mut self, session_id: &str) -> AppResult<Value> {
         let session = self
             .state
             .sessions
             .get(session_id)
             .cloned()
-            .ok_or_else(|| format!("session not found: {session_id}"))?;
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
         let project = self
             .state
             .projects
             .get(&session.project_id)
             .cloned()
-            .ok_or_else(|| format!("project not found: {}", session.project_id))?;
+            .ok_or_else(|| Message::new("project_not_found").arg("id", &session.project_id))?;
+        self.ensure_active(&project.id)?;
+        self.hydrate_project(&project.id).await?;
+        let stages = self.plan_action(&session, Action::GeneratePrd)?;
+        self.check_budget(Some(&project.id)).await?;
+        let requirements = if session.requirements.is_empty() {
+            String::new()
+        } else {
+            format!("{}:\n{}\n\n", t("prd_input_requirements"), session.requirements.to_markdown())
+        };
+        let system = self.prompt(PromptName::PrdGenerate, &project.id).await;
+        let reserved = self.reserved_tokens(&[&system, &project.name, &requirements]);
+        let conv_text = history_text(&self.context_history(session_id, reserved).await);
         let turns: Vec<&ConversationTurn> = self
             .state
             .conversation_turns
             .iter()
             .filter(|t| t.session_id == session_id)
             .collect();
-        if turns.is_empty() {
-            return Err(format!("no conversation found for session: {session_id}").into());
-        }
-
-        let conv_text: String = turns
-            .iter()
-            .map(|t| format!("[{}] {}\n", t.role, t.content))
-            .collect();
         let raw = self
             .llm_gateway
-            .clarify_round(
-                crate::clients::PRD_GEN_SYSTEM,
-                &[],
-                &format!("项目名: {}\n\n对话记录:\n{}", project.name, conv_text),
+            .generate_prd(
+                &system,
+                &format!(
+                    "{}: {}\n\n{requirements}{}:\n{}",
+                    t("prd_input_project"),
+                    project.name,
+                    t("prd_input_conversation"),
+                    conv_text
+                ),
             )
-            .await?;
+            .await;
+        // 未配置模型时仍用对话内容生成兜底 PRD，其余模型错误直接返回
+        let raw = match raw {
+            Ok(raw) => raw,
+            Err(e) if matches!(e.downcast_ref::<LlmError>(), Some(LlmError::NotConfigured)) => String::new(),
+            Err(e) => return Err(e),
+        };
 
         // 若 LLM 返回空、过短、或明显非 PRD（无二级标题），用对话内容生成一份 PRD，避免页面“暂无内容”
         let trim = raw.trim();
         let use_fallback = trim.is_empty()
             || trim.len() < 150
-            || (!trim.contains("##") && !trim.contains("背景") && !trim.contains("功能"));
+            || (!trim.contains("##")
+                && !["背景", "功能", "Background", "Feature"].iter().any(|w| trim.contains(w)));
         let prd_content = if use_fallback {
             let fallback = turns
                 .iter()
                 .map(|t| format!("- **{}**: {}", t.role, t.content.trim()))
                 .collect::<Vec<_>>()
                 .join("\n\n");
-            format!(
-                "# PRD · {}\n\n## 背景与目标\n\n根据澄清对话整理如下。若此处为摘要而非完整 PRD，请检查后端 LLM 配置（如 DEEPSEEK_API_KEY）或重试生成。\n\n## 对话摘要\n\n{}\n\n## 功能与验收\n\n待根据上述对话由 AI 补充功能清单与验收标准。",
-                project.name,
-                fallback
-            )
+            Message::new("prd_fallback")
+                .arg("name", &project.name)
+                .arg("conversation", fallback)
+                .to_string()
         } else {
             raw
         };
@@ -263,21 +700,23 @@ impl CeladonService {
             .max_by_key(|v| v.version)
             .map(|v| v.version);
         let diff_from_prev = previous.map(|v| format!("Incremental update from PRD v{v}"));
-        self.state.prd_versions.push(PrdVersion {
+        self.record(StateChange::PrdGenerated(PrdVersion {
             prd_id: Uuid::new_v4().to_string(),
             project_id: project.id.clone(),
             version: next_version,
             content: prd_content.clone(),
             diff_from_prev: diff_from_prev.clone(),
-        });
-        self.write_prd_file(&project.id, next_version, &prd_content)?;
+        }));
+        self.update_iteration(session_id, &project.id, |it| it.prd_version = Some(next_version));
 
-        if let Some(session_ref) = self.state.sessions.get_mut(session_id) {
-            session_ref.stage = Stage::PrdConfirmed;
-            session_ref.context_snapshot = format!("PRD v{next_version} ready");
+        for stage in stages {
+            self.set_stage(session_id, stage, format!("PRD v{next_version} ready"));
         }
+        self.record_usage(&project.id, session_id);
         self.touch_project(&project.id);
         self.persist().await?;
+        // 提交成功后再写文件，冲突失败的请求不会覆盖已提交版本的 markdown
+        self.write_prd_file(&project.id, next_version, &prd_content)?;
 
         Ok(json!({
             "message": "prd generated",
@@ -290,24 +729,27 @@ impl CeladonService {
         }))
     }
 
-    pub async fn run_dev(
+    async fn run_dev(
         &mut self,
         session_id: &str,
         instruction: Option<String>,
         dry_run: bool,
-    ) -> AppResult<(Value, Option<mpsc::UnboundedReceiver<AgentEvent>>)> {
+    ) -> AppResult<(Value, Option<DevRun>)> {
         let session = self
             .state
             .sessions
             .get(session_id)
             .cloned()
-            .ok_or_else(|| format!("session not found: {session_id}"))?;
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
         let project = self
             .state
             .projects
             .get(&session.project_id)
             .cloned()
-            .ok_or_else(|| format!("project not found: {}", session.project_id))?;
+            .ok_or_else(|| Message::new("project_not_found").arg("id", &session.project_id))?;
+        self.ensure_active(&project.id)?;
+        self.hydrate_project(&project.id).await?;
+        let stages = self.plan_action(&session, Action::RunDev)?;
         let default_instruction = format!(
             "Implement the latest PRD for project `{}` and run tests.",
             project.name
@@ -318,17 +760,8 @@ impl CeladonService {
         let workspace_str = workspace.to_string_lossy().to_string();
 
         // Inject strict tool guardrails and workspace anchoring
-        final_instruction.push_str(&format!(
-            "\n\nCRITICAL SYSTEM RULES:\n\
-             1. Your project workspace is strictly restricted to: `{}`\n\
-             2. ALL file operations (read_file, write_file, list_files, search_code) MUST use ABSOLUTE paths starting with this workspace.\n\
-             3. ALL commands (`run_command`) MUST start with `cd {} && ...` to ensure they run in the correct context.\n\
-             4. You are FORBIDDEN from accessing any files outside of this workspace.\n\
-             5. When using `read_file`, you MUST provide the `path` argument (e.g., `{{\"path\": \"{}/Cargo.toml\"}}`).\n\
-             6. When using `search_code`, you MUST provide the `pattern` argument.\n\
-             DO NOT CALL THESE TOOLS WITHOUT ARGUMENTS.",
-            workspace_str, workspace_str, workspace_str
-        ));
+        final_instruction.push_str("\n\n");
+        final_instruction.push_str(&self.prompt(PromptName::DevGuardrails, &project.id).await);
         
 
         let zene_payload =
@@ -342,15 +775,30 @@ impl CeladonService {
         let zene_response = if dry_run {
             None
         } else {
-            Some(
-                self.zene_client
-                    .run_agent_stream(
-                        session_id,
-                        &final_instruction,
-                        None,
-                    )
-                    .await?,
-            )
+            self.check_budget(Some(&project.id)).await?;
+            let receiver = self
+                .zene_client
+                .run_agent_stream(session_id, &final_instruction, &workspace, None)
+                .await?;
+            let task_id = Uuid::new_v4().to_string();
+            self.record(StateChange::TaskRecorded(TaskRun {
+                task_id: task_id.clone(),
+                project_id: project.id.clone(),
+                plan_json: zene_payload.to_string(),
+                run_status: "STARTED".to_string(),
+                logs: String::new(),
+            }));
+            // 新的开发结果需要重新测试
+            self.update_iteration(session_id, &project.id, |it| {
+                it.task_ids.push(task_id);
+                it.test_result = None;
+            });
+            for stage in stages {
+                self.set_stage(session_id, stage, "development started");
+            }
+            self.touch_project(&project.id);
+            self.persist().await?;
+            Some(receiver)
         };
         Ok((json!({
             "message": if dry_run {
@@ -365,21 +813,98 @@ impl CeladonService {
         }), zene_response))
     }
 
+    /// 进入 TESTING：清空本轮之前的测试结论，等待提交新的结论
+    pub async fn start_tests(&mut self, session_id: &str) -> AppResult<Value> {
+        let session = self
+            .state
+            .sessions
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
+        let project = self
+            .state
+            .projects
+            .get(&session.project_id)
+            .cloned()
+            .ok_or_else(|| Message::new("project_not_found").arg("id", &session.project_id))?;
+        self.ensure_active(&project.id)?;
+        self.hydrate_project(&project.id).await?;
+        for stage in self.plan_action(&session, Action::StartTests)? {
+            self.set_stage(session_id, stage, "testing development output");
+        }
+        self.update_iteration(session_id, &project.id, |it| it.test_result = None);
+        self.touch_project(&project.id);
+        self.persist().await?;
+        Ok(json!({
+            "message": "testing started",
+            "project_id": project.id,
+            "session_id": session_id,
+            "stage": Stage::Testing
+        }))
+    }
+
+    /// 在 TESTING 阶段提交测试结论：通过后可以部署，未通过回到 DEVELOPING
+    pub async fn record_test_result(
+        &mut self,
+        session_id: &str,
+        passed: bool,
+        notes: String,
+    ) -> AppResult<Value> {
+        let session = self
+            .state
+            .sessions
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
+        let project = self
+            .state
+            .projects
+            .get(&session.project_id)
+            .cloned()
+            .ok_or_else(|| Message::new("project_not_found").arg("id", &session.project_id))?;
+        self.ensure_active(&project.id)?;
+        self.hydrate_project(&project.id).await?;
+        self.plan_action(&session, Action::RecordTestResult)?;
+        let result = TestResult {
+            passed,
+            notes,
+            tested_at: now_timestamp(),
+        };
+        let stage = if passed { Stage::Testing } else { Stage::Developing };
+        if !passed {
+            self.set_stage(session_id, stage, format!("tests failed: {}", result.notes));
+        }
+        let recorded = result.clone();
+        self.update_iteration(session_id, &project.id, |it| it.test_result = Some(recorded));
+        self.touch_project(&project.id);
+        self.persist().await?;
+        Ok(json!({
+            "message": "test result recorded",
+            "project_id": project.id,
+            "session_id": session_id,
+            "test_result": result,
+            "stage": stage
+        }))
+    }
+
     pub async fn run_deploy(&mut self, session_id: &str, env: String) -> AppResult<Value> {
         let session = self
             .state
             .sessions
             .get(session_id)
             .cloned()
-            .ok_or_else(|| format!("session not found: {session_id}"))?;
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
         let project = self
             .state
             .projects
             .get(&session.project_id)
             .cloned()
-            .ok_or_else(|| format!("project not found: {}", session.project_id))?;
-        if let Some(session_ref) = self.state.sessions.get_mut(session_id) {
-            session_ref.stage = Stage::Deploying;
+            .ok_or_else(|| Message::new("project_not_found").arg("id", &session.project_id))?;
+        self.ensure_active(&project.id)?;
+        self.hydrate_project(&project.id).await?;
+        let stages = self.plan_action(&session, Action::Deploy)?;
+        for stage in stages.iter().filter(|s| **s != Stage::Delivered) {
+            self.set_stage(session_id, *stage, format!("deploying to {env}"));
         }
 
         let prd_version = self
@@ -390,18 +915,22 @@ impl CeladonService {
             .max_by_key(|v| v.version)
             .map(|v| format!("prd-v{}", v.version))
             .unwrap_or_else(|| "prd-v0".to_string());
-        self.state.deployment_runs.push(DeploymentRun {
-            deploy_id: Uuid::new_v4().to_string(),
+        let deploy_id = Uuid::new_v4().to_string();
+        self.record(StateChange::DeploymentRecorded(DeploymentRun {
+            deploy_id: deploy_id.clone(),
             project_id: project.id.clone(),
             env: env.clone(),
             version: prd_version.clone(),
             result: "SIMULATED_SUCCESS".to_string(),
             rollback_hint: format!("redeploy previous stable tag for project {}", project.id),
+        }));
+        let delivered_at = now_timestamp();
+        self.update_iteration(session_id, &project.id, |it| {
+            it.deploy_id = Some(deploy_id);
+            it.status = IterationStatus::Delivered;
+            it.delivered_at = Some(delivered_at);
         });
-        if let Some(session_ref) = self.state.sessions.get_mut(session_id) {
-            session_ref.stage = Stage::Delivered;
-            session_ref.context_snapshot = format!("deployed to {env}");
-        }
+        self.set_stage(session_id, Stage::Delivered, format!("deployed to {env}"));
         self.touch_project(&project.id);
         self.persist().await?;
 
@@ -415,12 +944,14 @@ impl CeladonService {
         }))
     }
 
-    /// 列出所有项目及其最近会话，用于首页「继续之前的工作」
-    pub fn list_projects(&self) -> AppResult<Value> {
+    /// 列出指定状态（默认 ACTIVE）的项目及其最近会话，用于首页「继续之前的工作」
+    pub fn list_projects(&self, status: Option<ProjectStatus>) -> AppResult<Value> {
+        let status = status.unwrap_or_default();
         let mut list: Vec<Value> = self
             .state
             .projects
             .values()
+            .filter(|project| project.status == status)
             .filter_map(|project| {
                 let session = self
                     .state
@@ -432,6 +963,7 @@ impl CeladonService {
                     "name": project.name,
                     "status": project.status,
                     "updated_at": project.updated_at,
+                    "restorable_until": restorable_until(project).map(|t| t.to_rfc3339()),
                     "session_id": session.session_id,
                     "stage": session.stage
                 }))
@@ -445,99 +977,950 @@ impl CeladonService {
         Ok(json!({ "projects": list }))
     }
 
-    pub fn stream_dev_logs(mut receiver: mpsc::UnboundedReceiver<AgentEvent>) -> AppResult<impl Stream<Item = Result<Event, Infallible>>> {
-        let stream = stream! {
-            while let Some(event) = receiver.recv().await {
-                let json_str = serde_json::to_string(&event).unwrap_or_default();
-                yield Ok(Event::default().data(json_str));
-            }
-        };
-        Ok(stream)
-    }
-
-    pub fn status(&self, session_id: &str) -> AppResult<Value> {
-        let session = self
-            .state
-            .sessions
-            .get(session_id)
-            .cloned()
-            .ok_or_else(|| format!("session not found: {session_id}"))?;
+    /// 切换项目状态：ACTIVE ⇄ ARCHIVED，ACTIVE/ARCHIVED → DELETED（软删除），
+    /// DELETED → ACTIVE（恢复，需在恢复期内）
+    pub async fn set_project_status(&mut self, project_id: &str, status: ProjectStatus) -> AppResult<Value> {
         let project = self
             .state
             .projects
-            .get(&session.project_id)
+            .get(project_id)
             .cloned()
-            .ok_or_else(|| format!("project not found: {}", session.project_id))?;
-        let latest_prd = self
-            .state
-            .prd_versions
-            .iter()
-            .filter(|v| v.project_id == project.id)
-            .max_by_key(|v| v.version)
-            .map(|v| json!({ "version": v.version, "diff_from_prev": v.diff_from_prev }));
-        let latest_task = self
-            .state
-            .task_runs
-            .iter().rfind(|t| t.project_id == project.id)
-            .map(|t| json!({ "task_id": t.task_id, "run_status": t.run_status }));
-        let latest_deploy = self
-            .state
-            .deployment_runs
-            .iter().rfind(|d| d.project_id == project.id)
-            .map(|d| {
-                json!({
-                    "deploy_id": d.deploy_id,
-                    "env": d.env,
-                    "version": d.version,
-                    "result": d.result
-                })
-            });
-        let conversation: Vec<_> = self
-            .state
-            .conversation_turns
-            .iter()
-            .filter(|t| t.session_id == session_id)
-            .map(|t| json!({ "role": t.role, "content": t.content, "created_at": t.created_at }))
-            .collect();
+            .ok_or_else(|| Message::new("project_not_found").arg("id", project_id))?;
+        let now = now_timestamp();
+        let deleted_at = match (project.status, status) {
+            (from, to) if from == to => {
+                return Err(Message::new("project_already_in_status").arg("status", status_text(to)).into());
+            }
+            (ProjectStatus::Deleted, ProjectStatus::Archived) => {
+                return Err(Message::new("restore_before_archive").into());
+            }
+            (ProjectStatus::Deleted, ProjectStatus::Active) => {
+                if restorable_until(&project).is_some_and(|until| until < Utc::now()) {
+                    return Err(Message::new("restore_window_expired")
+                        .arg("days", restore_window().num_days())
+                        .into());
+                }
+                None
+            }
+            (_, ProjectStatus::Deleted) => Some(now.clone()),
+            _ => None,
+        };
+        self.record(StateChange::ProjectStatusChanged {
+            project_id: project_id.to_string(),
+            status,
+            deleted_at: deleted_at.clone(),
+            updated_at: now,
+        });
+        self.persist().await?;
 
+        let project = &self.state.projects[project_id];
         Ok(json!({
-            "project": {
-                "id": project.id,
-                "name": project.name,
-                "status": project.status
-            },
-            "session": {
-                "session_id": session.session_id,
-                "stage": session.stage,
-                "context_snapshot": session.context_snapshot
-            },
-            "conversation": conversation,
-            "latest_prd": latest_prd,
-            "latest_task": latest_task,
-            "latest_deployment": latest_deploy
+            "message": format!("project {}", status_text(status).to_lowercase()),
+            "project_id": project_id,
+            "status": status,
+            "deleted_at": deleted_at,
+            "restorable_until": restorable_until(project).map(|t| t.to_rfc3339())
         }))
     }
 
-    fn save(&self) -> AppResult<()> {
-        let state_file = self.storage_dir.join("state.json");
-        let payload = serde_json::to_string_pretty(&self.state)?;
-        fs::write(state_file, payload)?;
-        Ok(())
-    }
-
-    fn append_conversation_turn(&mut self, session_id: &str, role: &str, content: &str) -> AppResult<()> {
-        if !self.state.sessions.contains_key(session_id) {
-            return Err(format!("session not found: {session_id}").into());
+    /// 彻底删除项目：状态记录、事件日志、workspace、PRD 文件与 Zene 会话文件
+    pub async fn purge_project(&mut self, project_id: &str) -> AppResult<Value> {
+        if !self.state.projects.contains_key(project_id) {
+            return Err(Message::new("project_not_found").arg("id", project_id).into());
         }
-        self.state.conversation_turns.push(ConversationTurn {
+        let session_ids: Vec<String> = self
+            .state
+            .sessions
+            .values()
+            .filter(|s| s.project_id == project_id)
+            .map(|s| s.session_id.clone())
+            .collect();
+        self.record(StateChange::ProjectPurged {
+            project_id: project_id.to_string(),
+        });
+        self.persist().await?;
+        self.hydrated.remove(project_id);
+
+        self.backend.delete_events(project_id).await?;
+        for dir in [self.workspace_dir(project_id), self.prd_dir(project_id)] {
+            match fs::remove_dir_all(&dir) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        for session_id in &session_ids {
+            ZeneClient::remove_session_files(session_id)?;
+        }
+
+        Ok(json!({
+            "message": "project purged",
+            "project_id": project_id,
+            "sessions": session_ids
+        }))
+    }
+
+    /// 彻底删除超过恢复期的软删除项目；由 `celadon project purge-expired` 或服务端的定时任务调用
+    pub async fn purge_expired(&mut self) -> AppResult<Value> {
+        let now = Utc::now();
+        let expired: Vec<String> = self
+            .state
+            .projects
+            .values()
+            .filter(|p| restorable_until(p).is_some_and(|until| until < now))
+            .map(|p| p.id.clone())
+            .collect();
+        for project_id in &expired {
+            self.purge_project(project_id).await?;
+        }
+        Ok(json!({
+            "message": "expired projects purged",
+            "purged": expired
+        }))
+    }
+
+    /// 归档或删除的项目不接受新的对话、PRD、开发与部署
+    fn ensure_active(&self, project_id: &str) -> AppResult<()> {
+        match self.state.projects.get(project_id).map(|p| p.status) {
+            Some(ProjectStatus::Active) => Ok(()),
+            Some(status) => Err(Message::new("project_not_active").arg("status", status_text(status)).into()),
+            None => Err(Message::new("project_not_found").arg("id", project_id).into()),
+        }
+    }
+
+    /// 在对话、PRD 与 workspace 文件中检索，返回带项目/会话上下文的排序结果
+    pub async fn search(&mut self, query: &str, limit: usize) -> AppResult<Value> {
+        let query = query.trim();
+        if query.is_empty() {
+            return Err(Message::new("empty_search_query").into());
+        }
+        let index = SearchIndex::open(&self.storage_dir, self.pool.as_ref(), self.user_id);
+        if let SearchIndex::Local { .. } = index {
+            let project_ids: Vec<String> = self.state.projects.keys().cloned().collect();
+            for project_id in project_ids {
+                self.hydrate_project(&project_id).await?;
+            }
+        }
+        let hits = index
+            .search(&self.state, &self.storage_dir.join("workspaces"), query, limit)
+            .await?;
+        Ok(json!({
+            "query": query,
+            "total": hits.len(),
+            "hits": hits
+        }))
+    }
+
+    /// 会话当前阶段、可执行的动作（及不可执行的原因）与阶段历史
+    pub async fn next_actions(&mut self, session_id: &str) -> AppResult<Value> {
+        let session = self
+            .state
+            .sessions
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
+        self.hydrate_project(&session.project_id).await?;
+        let writable = self.ensure_active(&session.project_id);
+        let actions: Vec<Value> = Action::ALL
+            .iter()
+            .map(|action| {
+                let plan = match &writable {
+                    Ok(()) => self.plan_action(&session, *action).map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                };
+                match plan {
+                    Ok(stages) => json!({ "action": action, "allowed": true, "stages": stages }),
+                    Err(reason) => json!({ "action": action, "allowed": false, "reason": reason }),
+                }
+            })
+            .collect();
+        Ok(json!({
+            "session_id": session_id,
+            "stage": session.stage,
+            "allowed_stages": workflow::allowed_targets(session.stage),
+            "actions": actions,
+            "stage_history": session.stage_history
+        }))
+    }
+
+    /// 项目的事件日志，以及按日志重放得到的项目与会话状态
+    pub async fn project_events(&mut self, project_id: &str) -> AppResult<Value> {
+        if !self.state.projects.contains_key(project_id) {
+            return Err(Message::new("project_not_found").arg("id", project_id).into());
+        }
+        let events = self.backend.load_events(project_id).await?;
+        let complete = events.iter().any(|e| {
+            matches!(
+                e.change,
+                StateChange::ProjectCreated(_) | StateChange::ProjectSnapshot { .. }
+            )
+        });
+        // 日志启用之前创建的项目缺少开头的事件：只读接口不写入任何东西，
+        // 在内存中以当前完整状态的快照接在日志之后重放
+        let mut replay = events.clone();
+        if !complete {
+            self.hydrate_project(project_id).await?;
+            replay.push(ProjectEvent {
+                seq: 0,
+                project_id: project_id.to_string(),
+                change: StateChange::ProjectSnapshot {
+                    project_id: project_id.to_string(),
+                    snapshot: Box::new(self.state.project_snapshot(project_id)),
+                },
+                created_at: now_timestamp(),
+            });
+        }
+        let replayed = ProjectEvent::replay(&replay);
+        let sessions: Vec<_> = replayed
+            .sessions
+            .values()
+            .map(|s| json!({ "session_id": s.session_id, "stage": s.stage }))
+            .collect();
+        Ok(json!({
+            "project_id": project_id,
+            "events": events,
+            "complete": complete,
+            "replayed": {
+                "project": replayed.projects.get(project_id),
+                "sessions": sessions,
+                "conversation_turns": replayed.conversation_turns.len(),
+                "prd_versions": replayed.prd_versions.len(),
+                "deployment_runs": replayed.deployment_runs.len(),
+                "iterations": replayed.iterations.len()
+            }
+        }))
+    }
+
+    /// 把项目打包为 .tar.gz：结构化数据、PRD 文件与 workspace，返回建议文件名和内容
+    pub async fn export_project(&mut self, project_id: &str) -> AppResult<(String, Vec<u8>)> {
+        let project = self
+            .state
+            .projects
+            .get(project_id)
+            .cloned()
+            .ok_or_else(|| Message::new("project_not_found").arg("id", project_id))?;
+        self.hydrate_project(project_id).await?;
+
+        let mut bundle = ProjectBundle::new(project, now_timestamp());
+        bundle.sessions = self
+            .state
+            .sessions
+            .values()
+            .filter(|s| s.project_id == project_id)
+            .cloned()
+            .collect();
+        bundle.sessions.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        let session_ids: HashSet<&str> = bundle.sessions.iter().map(|s| s.session_id.as_str()).collect();
+        bundle.conversation_turns = self
+            .state
+            .conversation_turns
+            .iter()
+            .filter(|t| session_ids.contains(t.session_id.as_str()))
+            .cloned()
+            .collect();
+        bundle.idea_events = self
+            .state
+            .idea_events
+            .iter()
+            .filter(|e| session_ids.contains(e.session_id.as_str()))
+            .cloned()
+            .collect();
+        bundle.prd_versions = self
+            .state
+            .prd_versions
+            .iter()
+            .filter(|v| v.project_id == project_id)
+            .cloned()
+            .collect();
+        bundle.task_runs = self
+            .state
+            .task_runs
+            .iter()
+            .filter(|t| t.project_id == project_id)
+            .cloned()
+            .collect();
+        bundle.deployment_runs = self
+            .state
+            .deployment_runs
+            .iter()
+            .filter(|d| d.project_id == project_id)
+            .cloned()
+            .collect();
+
+        bundle.iterations = self
+            .state
+            .iterations
+            .iter()
+            .filter(|it| it.project_id == project_id)
+            .cloned()
+            .collect();
+
+        let bytes = bundle::write_archive(
+            &bundle,
+            &self.prd_dir(project_id),
+            &self.workspace_dir(project_id),
+        )?;
+        Ok((format!("celadon-{project_id}.tar.gz"), bytes))
+    }
+
+    /// 导入项目包：所有 ID 重新生成，避免与本地已有数据冲突
+    pub async fn import_project(&mut self, bytes: &[u8]) -> AppResult<Value> {
+        let unpacked = bundle::read_archive(bytes)?;
+        let manifest = unpacked.manifest;
+        let now = now_timestamp();
+        let project_id = Uuid::new_v4().to_string();
+        let session_ids: HashMap<String, String> = manifest
+            .sessions
+            .iter()
+            .map(|s| (s.session_id.clone(), Uuid::new_v4().to_string()))
+            .collect();
+        let remap_session = |old: &str| {
+            session_ids
+                .get(old)
+                .cloned()
+                .ok_or_else(|| Message::new("bundle_unknown_session").arg("id", old))
+        };
+
+        self.hydrated.insert(project_id.clone());
+        self.record(StateChange::ProjectCreated(Project {
+            id: project_id.clone(),
+            status: ProjectStatus::Active,
+            updated_at: now.clone(),
+            deleted_at: None,
+            ..manifest.project.clone()
+        }));
+        for session in &manifest.sessions {
+            self.record(StateChange::SessionCreated(Session {
+                session_id: remap_session(&session.session_id)?,
+                project_id: project_id.clone(),
+                ..session.clone()
+            }));
+        }
+        for turn in &manifest.conversation_turns {
+            self.record(StateChange::TurnAppended(ConversationTurn {
+                session_id: remap_session(&turn.session_id)?,
+                ..turn.clone()
+            }));
+        }
+        let mut event_ids = HashMap::new();
+        for event in &manifest.idea_events {
+            let event_id = Uuid::new_v4().to_string();
+            event_ids.insert(event.event_id.clone(), event_id.clone());
+            self.record(StateChange::IdeaAppended(IdeaEvent {
+                event_id,
+                session_id: remap_session(&event.session_id)?,
+                ..event.clone()
+            }));
+        }
+        for prd in &manifest.prd_versions {
+            self.record(StateChange::PrdGenerated(PrdVersion {
+                prd_id: Uuid::new_v4().to_string(),
+                project_id: project_id.clone(),
+                ..prd.clone()
+            }));
+        }
+        let mut task_ids = HashMap::new();
+        for task in &manifest.task_runs {
+            let task_id = Uuid::new_v4().to_string();
+            task_ids.insert(task.task_id.clone(), task_id.clone());
+            self.record(StateChange::TaskRecorded(TaskRun {
+                task_id,
+                project_id: project_id.clone(),
+                ..task.clone()
+            }));
+        }
+        let mut deploy_ids = HashMap::new();
+        for deploy in &manifest.deployment_runs {
+            let deploy_id = Uuid::new_v4().to_string();
+            deploy_ids.insert(deploy.deploy_id.clone(), deploy_id.clone());
+            self.record(StateChange::DeploymentRecorded(DeploymentRun {
+                deploy_id,
+                project_id: project_id.clone(),
+                rollback_hint: format!("redeploy previous stable tag for project {project_id}"),
+                ..deploy.clone()
+            }));
+        }
+        // 迭代只保留能在包内找到的引用
+        let remap = |ids: &[String], map: &HashMap<String, String>| -> Vec<String> {
+            ids.iter().filter_map(|id| map.get(id).cloned()).collect()
+        };
+        for iteration in &manifest.iterations {
+            self.record(StateChange::IterationOpened(Iteration {
+                iteration_id: Uuid::new_v4().to_string(),
+                project_id: project_id.clone(),
+                session_id: remap_session(&iteration.session_id)?,
+                idea_event_ids: remap(&iteration.idea_event_ids, &event_ids),
+                task_ids: remap(&iteration.task_ids, &task_ids),
+                deploy_id: iteration
+                    .deploy_id
+                    .as_ref()
+                    .and_then(|id| deploy_ids.get(id).cloned()),
+                ..iteration.clone()
+            }));
+        }
+
+        let prd_dir = self.prd_dir(&project_id);
+        for (path, content) in &unpacked.prd_files {
+            let target = prd_dir.join(path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(target, content)?;
+        }
+        // 包里缺少对应 markdown 的版本按 manifest 中的内容补写
+        for prd in &manifest.prd_versions {
+            if !prd_dir.join(format!("v{}.md", prd.version)).exists() {
+                self.write_prd_file(&project_id, prd.version, &prd.content)?;
+            }
+        }
+        let workspace = self.workspace_dir(&project_id);
+        fs::create_dir_all(&workspace)?;
+        for (path, content) in &unpacked.workspace_files {
+            let target = workspace.join(path);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(target, content)?;
+        }
+        self.persist().await?;
+
+        let sessions: Vec<_> = manifest
+            .sessions
+            .iter()
+            .map(|s| json!({ "from": s.session_id, "session_id": session_ids[&s.session_id] }))
+            .collect();
+        Ok(json!({
+            "message": "project imported",
+            "project_id": project_id,
+            "project_name": manifest.project.name,
+            "imported_from": manifest.project.id,
+            "sessions": sessions,
+            "conversation_turns": manifest.conversation_turns.len(),
+            "prd_versions": manifest.prd_versions.len(),
+            "task_runs": manifest.task_runs.len(),
+            "deployment_runs": manifest.deployment_runs.len(),
+            "iterations": manifest.iterations.len(),
+            "workspace_files": unpacked.workspace_files.len()
+        }))
+    }
+
+    /// 启动开发，并在后台任务中跟踪这次运行（见 meter_dev_run）；dry_run 时没有事件通道
+    pub async fn run_dev_stream(
+        mut self,
+        session_id: String,
+        instruction: Option<String>,
+        dry_run: bool,
+    ) -> AppResult<(Value, Option<mpsc::UnboundedReceiver<DevLogEvent>>)> {
+        let (out, run) = self.run_dev(&session_id, instruction, dry_run).await?;
+        Ok((out, run.map(|run| self.meter_dev_run(session_id, run))))
+    }
+
+    /// 跟踪开发运行直到结束，不依赖客户端是否订阅事件：按执行模型估算 Zene 事件的用量，
+    /// 记入会话所属项目。每一步开始时以及每次记入用量后检查用户与项目上限，超限则中止
+    /// Zene 任务（该步的模型请求随之取消）并把任务标记为 BUDGET_EXCEEDED。
+    /// 事件转发到返回的通道，没有接收方时直接丢弃
+    fn meter_dev_run(mut self, session_id: String, mut run: DevRun) -> mpsc::UnboundedReceiver<DevLogEvent> {
+        let project_id = self
+            .state
+            .sessions
+            .get(&session_id)
+            .map(|s| s.project_id.clone());
+        let model = self.llm_gateway.role_model(UsageRole::Executor);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let locale = self.locale;
+        tokio::spawn(i18n::scope(locale, async move {
+            if let Some(project_id) = &project_id
+                && let Err(e) = self.hydrate_project(project_id).await
+            {
+                tracing::warn!("加载项目用量失败（{project_id}）: {e}");
+            }
+            let mut meter = usage::RunMeter::default();
+            while let Some(event) = run.events.recv().await {
+                let Some(project_id) = &project_id else {
+                    let _ = sender.send(DevLogEvent::Agent(event));
+                    continue;
+                };
+                let mut exceeded = None;
+                if event.starts_step() {
+                    exceeded = self.record_run_usage(project_id, &session_id, &model, &mut meter).await;
+                }
+                if exceeded.is_none() {
+                    // 工具结果回传给模型后即产生用量，不必等到下一步开始
+                    let tool_result = event.agent().is_some_and(|agent| {
+                        meter.observe(&model, &agent);
+                        matches!(*agent, AgentEvent::ToolResult { .. })
+                    });
+                    let _ = sender.send(DevLogEvent::Agent(event));
+                    if tool_result {
+                        exceeded = self.record_run_usage(project_id, &session_id, &model, &mut meter).await;
+                    }
+                }
+                if let Some(exceeded) = exceeded {
+                    run.stop();
+                    self.stop_dev_task(project_id, "BUDGET_EXCEEDED").await;
+                    let _ = sender.send(DevLogEvent::BudgetExceeded(exceeded));
+                    break;
+                }
+            }
+            // 运行结束或中止后记入剩余的估算用量
+            if let Some(project_id) = &project_id {
+                self.record_run_usage(project_id, &session_id, &model, &mut meter).await;
+            }
+        }));
+        receiver
+    }
+
+    /// 记入开发运行累计的估算用量，再检查用户与项目上限
+    async fn record_run_usage(
+        &mut self,
+        project_id: &str,
+        session_id: &str,
+        model: &str,
+        meter: &mut usage::RunMeter,
+    ) -> Option<BudgetExceeded> {
+        if let Some(call) = meter.take(model) {
+            self.record_call(project_id, session_id, call);
+            if let Err(e) = self.persist().await {
+                tracing::error!("记录开发用量失败（会话 {session_id}）: {e}");
+            }
+        }
+        self.budget_exceeded(project_id).await
+    }
+
+    /// 把开发事件转成 SSE：Zene 事件原样作为 data；超限时发送 budget_exceeded 事件
+    /// （含 scope 与本月用量），运行随之结束
+    pub fn stream_dev_logs(
+        mut receiver: mpsc::UnboundedReceiver<DevLogEvent>,
+    ) -> impl Stream<Item = Result<Event, Infallible>> {
+        stream! {
+            while let Some(event) = receiver.recv().await {
+                let event = match event {
+                    DevLogEvent::Agent(event) => {
+                        Event::default().data(serde_json::to_string(&event).unwrap_or_default())
+                    }
+                    DevLogEvent::BudgetExceeded(exceeded) => Event::default().event("budget_exceeded").data(
+                        json!({
+                            "error": exceeded.to_string(),
+                            "code": "budget_exceeded",
+                            "scope": exceeded.scope,
+                            "spent": exceeded.spent
+                        })
+                        .to_string(),
+                    ),
+                };
+                yield Ok(event);
+            }
+        }
+    }
+
+    /// 用户或项目本月用量已达上限时返回超限信息；检查本身失败时只记日志，不中断运行
+    async fn budget_exceeded(&mut self, project_id: &str) -> Option<BudgetExceeded> {
+        match self.check_budget(Some(project_id)).await {
+            Ok(()) => None,
+            Err(e) => match e.downcast::<BudgetExceeded>() {
+                Ok(exceeded) => Some(*exceeded),
+                Err(e) => {
+                    tracing::warn!("检查预算失败（{project_id}）: {e}");
+                    None
+                }
+            },
+        }
+    }
+
+    /// 把项目最近一个进行中的开发任务标记为 status
+    async fn stop_dev_task(&mut self, project_id: &str, status: &str) {
+        let Some(mut task) = self
+            .state
+            .task_runs
+            .iter()
+            .rfind(|t| t.project_id == project_id && t.run_status == "STARTED")
+            .cloned()
+        else {
+            return;
+        };
+        task.run_status = status.to_string();
+        self.record(StateChange::TaskRecorded(task));
+        if let Err(e) = self.persist().await {
+            tracing::error!("更新开发任务状态失败（{project_id}）: {e}");
+        }
+    }
+
+    /// 项目用量报表；不指定项目时为当前用户的全部项目
+    pub async fn usage_report(&mut self, project_id: Option<&str>) -> AppResult<Value> {
+        if let Some(id) = project_id
+            && self.state.projects.contains_key(id)
+        {
+            self.hydrate_project(id).await?;
+        }
+        let rows = match (&self.pool, self.user_id) {
+            (Some(pool), Some(user_id)) => db::usage_rows(pool, Some(user_id), project_id).await?,
+            _ => usage::aggregate(
+                self.state
+                    .usage
+                    .iter()
+                    .filter(|u| project_id.is_none_or(|id| u.project_id == id)),
+            ),
+        };
+        let since = usage::month_start();
+        let mut out = usage::report(rows);
+        if let Some(project) = project_id.and_then(|id| self.state.projects.get(id)) {
+            out["project_budget"] = json!({
+                "budget": project.settings.budget,
+                "spent": usage::spend_since(
+                    self.state.usage.iter().filter(|u| u.project_id == project.id),
+                    since,
+                )
+            });
+        }
+        if let (Some(pool), Some(user_id)) = (&self.pool, self.user_id) {
+            out["user_budget"] = json!({
+                "budget": db::get_user_budget(pool, user_id).await?,
+                "spent": db::user_spend(pool, user_id, since).await?
+            });
+        }
+        Ok(out)
+    }
+
+    /// 所有用户本月的预算与用量
+    pub async fn user_budgets(&self) -> AppResult<Value> {
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
+        Ok(json!({
+            "since": usage::month_start().to_rfc3339(),
+            "users": db::list_user_budgets(pool, usage::month_start()).await?
+        }))
+    }
+
+    pub async fn set_user_budget(&self, user_id: Uuid, budget: Budget) -> AppResult<Value> {
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
+        db::set_user_budget(pool, user_id, budget).await?;
+        Ok(json!({
+            "message": "budget updated",
+            "user_id": user_id.to_string(),
+            "budget": budget
+        }))
+    }
+
+    /// 当前用户自带的模型 Key，Key 只返回掩码
+    pub async fn llm_keys(&self) -> AppResult<Value> {
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
+        let user_id = self.user_id.ok_or_else(|| Message::new("login_required"))?;
+        let master = SecretKey::from_env(crypto::MASTER_KEY_VAR).ok();
+        let keys = db::list_user_llm_keys(pool, user_id).await?;
+        Ok(Value::Array(
+            keys.into_iter()
+                .map(|key| {
+                    // 主密钥变更后无法解密的 Key 仍列出，掩码留空，提示用户重新保存
+                    let plain = master.as_ref().and_then(|m| m.decrypt(&key.api_key).ok());
+                    json!({
+                        "role": key.role,
+                        "provider": key.provider,
+                        "model": key.model,
+                        "api_key": plain.as_deref().map_or_else(|| "…".to_string(), crypto::mask),
+                        "readable": plain.is_some(),
+                        "updated_at": key.updated_at
+                    })
+                })
+                .collect(),
+        ))
+    }
+
+    /// 加密保存当前用户某个角色的服务商、模型与 Key，之后该用户的调用优先使用
+    pub async fn set_llm_key(
+        &self,
+        role: UsageRole,
+        provider: &str,
+        model: &str,
+        api_key: &str,
+    ) -> AppResult<Value> {
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
+        let user_id = self.user_id.ok_or_else(|| Message::new("login_required"))?;
+        let (provider, model, api_key) = (provider.trim(), model.trim(), api_key.trim());
+        if provider.is_empty() || model.is_empty() || api_key.is_empty() {
+            return Err(Message::new("llm_key_incomplete").into());
+        }
+        let master = SecretKey::from_env(crypto::MASTER_KEY_VAR)?;
+        let key = db::UserLlmKey {
+            role,
+            provider: provider.to_string(),
+            model: model.to_string(),
+            api_key: master.encrypt(api_key)?,
+            updated_at: now_timestamp(),
+        };
+        db::set_user_llm_key(pool, user_id, &key).await?;
+        Ok(json!({
+            "message": "llm key saved",
+            "role": role,
+            "provider": key.provider,
+            "model": key.model,
+            "api_key": crypto::mask(api_key)
+        }))
+    }
+
+    /// 删除后该角色回到全局设置
+    pub async fn delete_llm_key(&self, role: UsageRole) -> AppResult<Value> {
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
+        let user_id = self.user_id.ok_or_else(|| Message::new("login_required"))?;
+        if !db::delete_user_llm_key(pool, user_id, role).await? {
+            return Err(Message::new("llm_key_not_found").into());
+        }
+        Ok(json!({ "message": "llm key deleted", "role": role }))
+    }
+
+    /// 全部用户的用量，按用户、项目、角色与模型汇总
+    pub async fn admin_usage_report(&self) -> AppResult<Value> {
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
+        Ok(usage::report(db::usage_rows(pool, None, None).await?))
+    }
+
+    pub async fn status(&mut self, session_id: &str) -> AppResult<Value> {
+        let session = self
+            .state
+            .sessions
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
+        let project = self
+            .state
+            .projects
+            .get(&session.project_id)
+            .cloned()
+            .ok_or_else(|| Message::new("project_not_found").arg("id", &session.project_id))?;
+        self.hydrate_project(&project.id).await?;
+        let latest_prd = self
+            .state
+            .prd_versions
+            .iter()
+            .filter(|v| v.project_id == project.id)
+            .max_by_key(|v| v.version)
+            .map(|v| json!({ "version": v.version, "diff_from_prev": v.diff_from_prev }));
+        let latest_task = self
+            .state
+            .task_runs
+            .iter().rfind(|t| t.project_id == project.id)
+            .map(|t| json!({ "task_id": t.task_id, "run_status": t.run_status }));
+        let latest_deploy = self
+            .state
+            .deployment_runs
+            .iter().rfind(|d| d.project_id == project.id)
+            .map(|d| {
+                json!({
+                    "deploy_id": d.deploy_id,
+                    "env": d.env,
+                    "version": d.version,
+                    "result": d.result
+                })
+            });
+        let conversation: Vec<_> = self
+            .state
+            .conversation_turns
+            .iter()
+            .filter(|t| t.session_id == session_id)
+            .map(|t| json!({ "role": t.role, "content": t.content, "created_at": t.created_at }))
+            .collect();
+        let mut iterations: Vec<&Iteration> = self
+            .state
+            .iterations
+            .iter()
+            .filter(|it| it.project_id == project.id)
+            .collect();
+        iterations.sort_by_key(|it| it.number);
+        let iterations: Vec<Value> = iterations.iter().map(|it| self.iteration_summary(it)).collect();
+
+        Ok(json!({
+            "project": {
+                "id": project.id,
+                "name": project.name,
+                "status": project.status
+            },
+            "session": {
+                "session_id": session.session_id,
+                "stage": session.stage,
+                "context_snapshot": session.context_snapshot,
+                "stage_history": session.stage_history,
+                "requirements": session.requirements,
+                "summary": session.summary
+            },
+            "conversation": conversation,
+            "latest_prd": latest_prd,
+            "latest_task": latest_task,
+            "latest_deployment": latest_deploy,
+            "current_iteration": iterations.last(),
+            "iterations": iterations
+        }))
+    }
+
+    fn append_conversation_turn(&mut self, session_id: &str, role: &str, content: &str) -> AppResult<()> {
+        if !self.state.sessions.contains_key(session_id) {
+            return Err(Message::new("session_not_found").arg("id", session_id).into());
+        }
+        self.record(StateChange::TurnAppended(ConversationTurn {
             session_id: session_id.to_string(),
             role: role.to_string(),
             content: content.to_string(),
             created_at: now_timestamp(),
-        });
+            partial: false,
+        }));
         Ok(())
     }
 
+    pub fn requirements(&self, session_id: &str) -> AppResult<Value> {
+        let session = self
+            .state
+            .sessions
+            .get(session_id)
+            .cloned()
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
+        Ok(json!({
+            "session_id": session_id,
+            "project_id": session.project_id,
+            "requirements": session.requirements
+        }))
+    }
+
+    /// 用户手动修改需求清单，整体替换；之后的澄清轮次以此为基础继续整理
+    pub async fn update_requirements(
+        &mut self,
+        session_id: &str,
+        mut requirements: RequirementsSheet,
+    ) -> AppResult<Value> {
+        let project_id = self
+            .state
+            .sessions
+            .get(session_id)
+            .map(|s| s.project_id.clone())
+            .ok_or_else(|| Message::new("session_not_found").arg("id", session_id))?;
+        self.ensure_active(&project_id)?;
+        self.hydrate_project(&project_id).await?;
+        requirements.updated_at = Some(now_timestamp());
+        self.record(StateChange::RequirementsUpdated {
+            session_id: session_id.to_string(),
+            requirements: requirements.clone(),
+        });
+        self.touch_project(&project_id);
+        self.persist().await?;
+        Ok(json!({
+            "message": "requirements updated",
+            "session_id": session_id,
+            "project_id": project_id,
+            "requirements": requirements
+        }))
+    }
+
+    /// 根据目前的对话重新整理需求清单。整理失败不影响澄清本身，保留原清单
+    async fn refresh_requirements(&mut self, session_id: &str) {
+        let Some(current) = self.state.sessions.get(session_id).map(|s| s.requirements.clone())
+        else {
+            return;
+        };
+        let system = self.session_prompt(PromptName::Requirements, session_id).await;
+        let current_json = serde_json::to_string_pretty(&current).unwrap_or_default();
+        let reserved = self.reserved_tokens(&[&system, &current_json]);
+        let conversation = history_text(&self.context_history(session_id, reserved).await);
+        match self
+            .llm_gateway
+            .extract_requirements(&system, &conversation, &current)
+            .await
+        {
+            Ok(Some(mut sheet)) => {
+                sheet.updated_at = current.updated_at.clone();
+                if sheet != current {
+                    sheet.updated_at = Some(now_timestamp());
+                    self.record(StateChange::RequirementsUpdated {
+                        session_id: session_id.to_string(),
+                        requirements: sheet,
+                    });
+                }
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("整理需求清单失败（会话 {session_id}）: {e}"),
+        }
+    }
+
+    /// 在上下文预算内组装历史：超出时先把较早轮次折叠进滚动摘要，
+    /// 返回摘要（作为 system 消息）加最近的轮次。reserved 为系统提示与本轮输入占用的 token。
+    /// 摘要失败时保留旧摘要，只发送预算内的最近轮次
+    async fn context_history(&mut self, session_id: &str, reserved: usize) -> Vec<(String, String)> {
+        let turns = self.get_conversation_history(session_id);
+        let mut summary = self
+            .state
+            .sessions
+            .get(session_id)
+            .map(|s| s.summary.clone())
+            .unwrap_or_default();
+        let model = self.llm_gateway.planner_model.clone();
+        let plan = context::plan(
+            &model,
+            &turns,
+            summary.covered_turns,
+            context::message_tokens(&model, &summary.content),
+            context::budget().saturating_sub(reserved),
+        );
+        if !plan.fold.is_empty() {
+            let folded = history_text(&turns[plan.fold.clone()]);
+            let system = self.session_prompt(PromptName::Summary, session_id).await;
+            match self.llm_gateway.summarize(&system, &summary.content, &folded).await {
+                Ok(Some(content)) => {
+                    summary = ConversationSummary {
+                        content,
+                        covered_turns: plan.keep_from,
+                        updated_at: Some(now_timestamp()),
+                    };
+                    self.record(StateChange::SummaryUpdated {
+                        session_id: session_id.to_string(),
+                        summary: summary.clone(),
+                    });
+                }
+                Ok(None) => {}
+                Err(e) => tracing::warn!("压缩对话历史失败（会话 {session_id}）: {e}"),
+            }
+        }
+
+        let mut history = Vec::new();
+        if !summary.content.is_empty() {
+            history.push(("system".to_string(), format!("{}\n{}", t("summary_prefix"), summary.content)));
+        }
+        history.extend(turns.into_iter().skip(plan.keep_from));
+        history
+    }
+
+    /// 当前生效的提示词并替换项目变量；模板存储读取失败时退回内置默认，不阻断流程
+    async fn prompt(&self, name: PromptName, project_id: &str) -> String {
+        let store = PromptStore::open(self.pool.as_ref());
+        let template = match store.resolve(name, Some(project_id), self.locale).await {
+            Ok(resolved) => resolved.content,
+            Err(e) => {
+                tracing::warn!("读取提示词 {} 失败，使用内置默认: {e}", name.key());
+                name.builtin(self.locale).to_string()
+            }
+        };
+        prompts::render(&template, &self.prompt_vars(project_id))
+    }
+
+    async fn session_prompt(&self, name: PromptName, session_id: &str) -> String {
+        let project_id = self
+            .state
+            .sessions
+            .get(session_id)
+            .map(|s| s.project_id.clone())
+            .unwrap_or_default();
+        self.prompt(name, &project_id).await
+    }
+
+    fn prompt_vars(&self, project_id: &str) -> PromptVars {
+        let project_name = self
+            .state
+            .projects
+            .get(project_id)
+            .map(|p| p.name.clone())
+            .unwrap_or_default();
+        let workspace = self.workspace_dir(project_id);
+        prompts::variables(&project_name, &workspace.to_string_lossy(), self.locale.code())
+    }
+
+    fn reserved_tokens(&self, parts: &[&str]) -> usize {
+        let model = &self.llm_gateway.planner_model;
+        parts.iter().map(|part| context::message_tokens(model, part)).sum()
+    }
+
     fn get_conversation_history(&self, session_id: &str) -> Vec<(String, String)> {
         self.state
             .conversation_turns
@@ -547,43 +1930,295 @@ impl CeladonService {
             .collect()
     }
 
-    fn append_idea_event(&mut self, session_id: &str, text: String) -> AppResult<()> {
+    fn append_idea_event(&mut self, session_id: &str, text: String) -> AppResult<String> {
         if !self.state.sessions.contains_key(session_id) {
-            return Err(format!("session not found: {session_id}").into());
+            return Err(Message::new("session_not_found").arg("id", session_id).into());
         }
-        self.state.idea_events.push(IdeaEvent {
-            event_id: Uuid::new_v4().to_string(),
+        let event_id = Uuid::new_v4().to_string();
+        self.record(StateChange::IdeaAppended(IdeaEvent {
+            event_id: event_id.clone(),
             session_id: session_id.to_string(),
             user_input: text,
             created_at: now_timestamp(),
+        }));
+        Ok(event_id)
+    }
+
+    /// 项目当前（编号最大）的迭代
+    fn current_iteration(&self, project_id: &str) -> Option<&Iteration> {
+        self.state
+            .iterations
+            .iter()
+            .filter(|it| it.project_id == project_id)
+            .max_by_key(|it| it.number)
+    }
+
+    fn new_iteration(&self, session_id: &str, project_id: &str) -> Iteration {
+        let number = self.current_iteration(project_id).map_or(0, |it| it.number) + 1;
+        Iteration {
+            iteration_id: Uuid::new_v4().to_string(),
+            project_id: project_id.to_string(),
+            session_id: session_id.to_string(),
+            number,
+            idea_event_ids: Vec::new(),
+            prd_version: None,
+            task_ids: Vec::new(),
+            test_result: None,
+            deploy_id: None,
+            status: IterationStatus::Open,
+            opened_at: now_timestamp(),
+            delivered_at: None,
+        }
+    }
+
+    /// 修改当前迭代；项目还没有迭代时开启第一轮
+    fn update_iteration(&mut self, session_id: &str, project_id: &str, update: impl FnOnce(&mut Iteration)) {
+        let change = match self.current_iteration(project_id).cloned() {
+            Some(mut iteration) => {
+                update(&mut iteration);
+                StateChange::IterationUpdated(iteration)
+            }
+            None => {
+                let mut iteration = self.new_iteration(session_id, project_id);
+                update(&mut iteration);
+                StateChange::IterationOpened(iteration)
+            }
+        };
+        self.record(change);
+    }
+
+    /// 迭代摘要：触发想法、PRD、任务、部署以及当前进度
+    fn iteration_summary(&self, iteration: &Iteration) -> Value {
+        let ideas: Vec<&str> = self
+            .state
+            .idea_events
+            .iter()
+            .filter(|e| iteration.idea_event_ids.contains(&e.event_id))
+            .map(|e| e.user_input.as_str())
+            .collect();
+        let deployment = iteration.deploy_id.as_ref().and_then(|id| {
+            self.state
+                .deployment_runs
+                .iter()
+                .find(|d| &d.deploy_id == id)
+                .map(|d| json!({ "deploy_id": d.deploy_id, "env": d.env, "version": d.version, "result": d.result }))
         });
-        Ok(())
+        let progress = match iteration {
+            it if it.status == IterationStatus::Delivered => "DELIVERED",
+            it if it.test_result.as_ref().is_some_and(|t| t.passed) => "TESTED",
+            it if !it.task_ids.is_empty() => "DEVELOPING",
+            it if it.prd_version.is_some() => "PRD_READY",
+            _ => "CLARIFYING",
+        };
+        json!({
+            "iteration_id": iteration.iteration_id,
+            "number": iteration.number,
+            "status": iteration.status,
+            "progress": progress,
+            "ideas": ideas,
+            "prd_version": iteration.prd_version,
+            "task_ids": iteration.task_ids,
+            "test_result": iteration.test_result,
+            "deployment": deployment,
+            "opened_at": iteration.opened_at,
+            "delivered_at": iteration.delivered_at
+        })
     }
 
     fn write_prd_file(&self, project_id: &str, version: u32, content: &str) -> AppResult<()> {
-        let prd_dir = self.storage_dir.join("prd").join(project_id);
+        let prd_dir = self.prd_dir(project_id);
         fs::create_dir_all(&prd_dir)?;
         let target = prd_dir.join(format!("v{version}.md"));
         fs::write(target, content)?;
         Ok(())
     }
 
+    fn set_stage(&mut self, session_id: &str, stage: Stage, snapshot: impl Into<String>) {
+        self.record(StateChange::StageChanged {
+            session_id: session_id.to_string(),
+            stage,
+            context_snapshot: snapshot.into(),
+            changed_at: now_timestamp(),
+        });
+    }
+
+    /// 校验动作在当前阶段是否可执行、前置产物是否齐全，返回要依次进入的阶段；
+    /// 调用前需已补齐项目明细
+    fn plan_action(&self, session: &Session, action: Action) -> Result<Vec<Stage>, StageError> {
+        let stages = action.plan(session.stage)?;
+        let missing = match action {
+            Action::AppendIdea => None,
+            Action::GeneratePrd => (!self
+                .state
+                .conversation_turns
+                .iter()
+                .any(|t| t.session_id == session.session_id))
+            .then_some("prereq_clarify"),
+            Action::RunDev => (!self.has_prd(&session.project_id)).then_some("prereq_prd"),
+            Action::StartTests => {
+                let developed = self
+                    .current_iteration(&session.project_id)
+                    .is_some_and(|it| !it.task_ids.is_empty());
+                (!developed).then_some("prereq_dev")
+            }
+            Action::RecordTestResult => None,
+            // 已交付的迭代重新部署时代码未变，不要求再次测试
+            Action::Deploy if !self.has_prd(&session.project_id) => Some("prereq_prd"),
+            Action::Deploy => {
+                let tested = self.current_iteration(&session.project_id).is_some_and(|it| {
+                    it.status == IterationStatus::Delivered
+                        || it.test_result.as_ref().is_some_and(|t| t.passed)
+                });
+                (!tested).then_some("prereq_test_pass")
+            }
+        };
+        match missing {
+            Some(missing) => Err(StageError::MissingPrerequisite { action, missing }),
+            None => Ok(stages),
+        }
+    }
+
+    fn has_prd(&self, project_id: &str) -> bool {
+        self.state.prd_versions.iter().any(|v| v.project_id == project_id)
+    }
+
     fn touch_project(&mut self, project_id: &str) {
-        if let Some(project) = self.state.projects.get_mut(project_id) {
-            project.updated_at = now_timestamp();
+        self.record(StateChange::ProjectTouched {
+            project_id: project_id.to_string(),
+            updated_at: now_timestamp(),
+        });
+    }
+
+    pub async fn list_prompts(&self) -> AppResult<Value> {
+        PromptStore::open(self.pool.as_ref()).list(self.locale).await
+    }
+
+    /// 某个提示词当前生效的内容与版本历史；带 project_id 时查看该项目的覆盖，
+    /// 带 locale 时查看该语言的模板，否则为不限语言的模板
+    pub async fn prompt_history(
+        &self,
+        name: &str,
+        project_id: Option<&str>,
+        locale: Option<Locale>,
+    ) -> AppResult<Value> {
+        let name = prompt_name(name)?;
+        let store = PromptStore::open(self.pool.as_ref());
+        let effective = locale.unwrap_or(self.locale);
+        let active = store.resolve(name, project_id, effective).await?;
+        let versions = store.history(name, project_id, locale).await?;
+        Ok(json!({
+            "name": name,
+            "project_id": project_id,
+            "locale": locale,
+            "active": active,
+            "builtin": name.builtin(effective),
+            "versions": versions
+        }))
+    }
+
+    pub async fn save_prompt(
+        &self,
+        name: &str,
+        project_id: Option<&str>,
+        locale: Option<Locale>,
+        content: &str,
+        note: Option<&str>,
+    ) -> AppResult<Value> {
+        let name = prompt_name(name)?;
+        if content.trim().is_empty() {
+            return Err(Message::new("empty_prompt").into());
         }
+        let version = PromptStore::open(self.pool.as_ref())
+            .save(name, project_id, locale, content, note, self.user_id)
+            .await?;
+        Ok(json!({
+            "message": "prompt saved",
+            "name": name,
+            "project_id": project_id,
+            "locale": locale,
+            "version": version
+        }))
+    }
+
+    /// 预览替换变量后的提示词：content 为空时预览当前生效的版本，variables 覆盖默认变量
+    pub async fn preview_prompt(
+        &self,
+        name: &str,
+        project_id: Option<&str>,
+        locale: Option<Locale>,
+        content: Option<String>,
+        variables: PromptVars,
+    ) -> AppResult<Value> {
+        let name = prompt_name(name)?;
+        let locale = locale.unwrap_or(self.locale);
+        let template = match content {
+            Some(content) => content,
+            None => {
+                PromptStore::open(self.pool.as_ref())
+                    .resolve(name, project_id, locale)
+                    .await?
+                    .content
+            }
+        };
+        let mut vars = match project_id {
+            Some(project_id) => self.prompt_vars(project_id),
+            None => prompts::variables("demo-project", "/path/to/workspace", locale.code()),
+        };
+        vars.insert("locale".to_string(), locale.code().to_string());
+        vars.extend(variables);
+        Ok(json!({
+            "name": name,
+            "project_id": project_id,
+            "variables": vars,
+            "rendered": prompts::render(&template, &vars)
+        }))
+    }
+
+    pub async fn rollback_prompt(
+        &self,
+        name: &str,
+        project_id: Option<&str>,
+        locale: Option<Locale>,
+        version: i32,
+    ) -> AppResult<Value> {
+        let name = prompt_name(name)?;
+        let new_version = PromptStore::open(self.pool.as_ref())
+            .rollback(name, project_id, locale, version, self.user_id)
+            .await?;
+        Ok(json!({
+            "message": "prompt rolled back",
+            "name": name,
+            "project_id": project_id,
+            "locale": locale,
+            "rolled_back_to": version,
+            "version": new_version
+        }))
+    }
+
+    /// 更换 CELADON_SETTINGS_KEK 后（旧值放入 CELADON_SETTINGS_KEK_PREVIOUS）重新加密各项的数据密钥，
+    /// 同时加密仍为明文的密钥类设置；服务启动时也会自动执行
+    pub async fn rotate_settings_key(&self) -> AppResult<Value> {
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
+        let (sealed, rewrapped) = db::seal_secret_settings(pool).await?;
+        Ok(json!({ "sealed": sealed, "rewrapped": rewrapped }))
     }
 
     pub async fn list_all_settings(&self) -> AppResult<Value> {
-        let pool = self.pool.as_ref().ok_or_else(|| "数据库未启用".to_string())?;
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
         db::list_system_settings(pool).await
     }
 
-    pub async fn update_setting(&mut self, key: &str, value: &str) -> AppResult<()> {
-        let pool = self.pool.as_ref().ok_or_else(|| "数据库未启用".to_string())?;
-        db::set_system_setting(pool, key, value).await?;
+    /// secret 为 None 时沿用设置原有的密钥标记
+    pub async fn update_setting(&mut self, key: &str, value: &str, secret: Option<bool>) -> AppResult<()> {
+        let pool = self.pool.as_ref().ok_or_else(|| Message::new("database_disabled"))?;
+        // 界面原样提交列表中的掩码时视为未修改，保留原值
+        let value = match db::get_system_setting(pool, key).await.ok().flatten() {
+            Some(current) if value.contains('…') && crypto::mask(&current) == value => current,
+            _ => value.to_string(),
+        };
+        db::set_system_setting(pool, key, &value, secret).await?;
         // 立即尝试重载 Gateway 和 ZeneClient
-        if let Ok(new_gateway) = LlmGateway::load(Some(pool)).await {
+        if let Ok(new_gateway) = LlmGateway::load(Some(pool), self.user_id).await {
             let agent_config = new_gateway.to_agent_config();
             self.llm_gateway = new_gateway;
             let _ = self.zene_client.init(agent_config).await;
@@ -592,15 +2227,38 @@ impl CeladonService {
     }
 }
 
-fn migrate_idea_events_to_conversation(state: &mut StateStore) {
-    if state.conversation_turns.is_empty() && !state.idea_events.is_empty() {
-        for e in &state.idea_events {
-            state.conversation_turns.push(ConversationTurn {
-                session_id: e.session_id.clone(),
-                role: "user".to_string(),
-                content: e.user_input.clone(),
-                created_at: e.created_at.clone(),
-            });
-        }
+fn prompt_name(key: &str) -> AppResult<PromptName> {
+    PromptName::parse(key).ok_or_else(|| Message::new("unknown_prompt").arg("name", key).into())
+}
+
+fn history_text(history: &[(String, String)]) -> String {
+    history
+        .iter()
+        .map(|(role, content)| format!("[{role}] {content}\n"))
+        .collect()
+}
+
+fn restore_window() -> Duration {
+    let days = std::env::var("CELADON_RESTORE_WINDOW_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_RESTORE_WINDOW_DAYS);
+    Duration::days(days)
+}
+
+/// 软删除项目的恢复截止时间；未删除的项目返回 None
+fn restorable_until(project: &Project) -> Option<DateTime<Utc>> {
+    if project.status != ProjectStatus::Deleted {
+        return None;
+    }
+    let deleted_at = DateTime::parse_from_rfc3339(project.deleted_at.as_deref()?).ok()?;
+    Some(deleted_at.with_timezone(&Utc) + restore_window())
+}
+
+fn status_text(status: ProjectStatus) -> &'static str {
+    match status {
+        ProjectStatus::Active => "ACTIVE",
+        ProjectStatus::Archived => "ARCHIVED",
+        ProjectStatus::Deleted => "DELETED",
     }
 }
@@ -0,0 +1,852 @@
+//! 可插拔的状态存储后端：本地文件、PostgreSQL 与内嵌 SQLite
+
+use crate::common::{AppResult, StateConflict};
+use crate::db;
+use crate::i18n::Message;
+use crate::models::{ProjectEvent, StateStore};
+use crate::schema;
+use futures::future::BoxFuture;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePoolOptions};
+use sqlx::{Row, SqlitePool};
+use std::fs::{self, File, OpenOptions};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// state.json 默认保留的备份份数（CELADON_STATE_BACKUPS 可覆盖）
+const DEFAULT_STATE_BACKUPS: usize = 5;
+
+/// 状态存储后端：CeladonService 只通过该 trait 读写状态
+pub trait StateBackend: Send + Sync {
+    /// 读取状态（无则返回默认）。逐行存储的后端只返回项目与会话索引
+    fn load(&self) -> BoxFuture<'_, AppResult<StateStore>>;
+
+    /// 读取单个项目的明细（对话、PRD、任务、部署）；
+    /// 返回 None 表示 load 已包含全部数据
+    fn load_project<'a>(&'a self, _project_id: &'a str) -> BoxFuture<'a, AppResult<Option<StateStore>>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// 以 compare-and-swap 方式提交一批事件：存储中的 revision 必须等于
+    /// expected，否则返回 StateConflict；成功时应用事件、追加到事件日志，
+    /// 并返回新的 revision
+    fn commit<'a>(
+        &'a self,
+        expected: u64,
+        events: &'a [ProjectEvent],
+    ) -> BoxFuture<'a, AppResult<u64>>;
+
+    /// 按 seq 升序读取项目的事件日志
+    fn load_events<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<Vec<ProjectEvent>>>;
+
+    /// 删除项目的全部事件日志，仅用于彻底删除项目
+    fn delete_events<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<()>>;
+}
+
+/// CAS 校验后把事件应用到状态并推进 revision
+fn apply_events(state: &mut StateStore, expected: u64, events: &[ProjectEvent]) -> AppResult<()> {
+    if state.revision != expected {
+        return Err(StateConflict {
+            expected,
+            actual: state.revision,
+        }
+        .into());
+    }
+    for event in events {
+        event.change.apply(state);
+    }
+    state.revision += 1;
+    Ok(())
+}
+
+/// CELADON_STATE_BACKEND 的取值（小写，未配置为 None）
+pub fn backend_kind() -> Option<String> {
+    std::env::var("CELADON_STATE_BACKEND")
+        .ok()
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+}
+
+/// 各项目事件日志文件的 (文件长度, 最后一条 seq)；长度不符说明被其他进程追加过，需重新读取
+static JOURNAL_SEQS: LazyLock<std::sync::Mutex<HashMap<PathBuf, (u64, u64)>>> =
+    LazyLock::new(|| std::sync::Mutex::new(HashMap::new()));
+
+/// 进程内按数据库文件共享的 SQLite 连接池，避免每个请求各建一个
+static SQLITE_POOLS: LazyLock<Mutex<HashMap<PathBuf, SqlitePool>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn sqlite_path(storage_dir: &Path) -> PathBuf {
+    std::env::var("CELADON_SQLITE_PATH")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| storage_dir.join("state.db"))
+}
+
+/// 启动时按配置预先建立共享的存储连接（目前只有 SQLite 需要），配置错误在启动时暴露
+pub async fn prepare(storage_dir: &Path) -> AppResult<()> {
+    if backend_kind().as_deref() == Some("sqlite") {
+        sqlite_pool(&sqlite_path(storage_dir)).await?;
+    }
+    Ok(())
+}
+
+/// 按配置选择后端：CELADON_STATE_BACKEND = file | sqlite | postgres。
+/// 未配置时，有数据库与登录用户则用 postgres，否则用 file。
+pub async fn open_backend(
+    storage_dir: &Path,
+    pool: Option<&db::Pool>,
+    user_id: Option<Uuid>,
+) -> AppResult<Box<dyn StateBackend>> {
+    let kind = backend_kind();
+    match (kind.as_deref(), pool, user_id) {
+        (Some("sqlite"), _, _) => {
+            let path = sqlite_path(storage_dir);
+            let scope = user_id.map(|u| u.to_string()).unwrap_or_else(|| "local".to_string());
+            Ok(Box::new(SqliteBackend::open(&path, scope).await?))
+        }
+        (Some("file"), _, user_id) | (None, _, user_id @ None) | (None, None, user_id) => {
+            let dir = match user_id {
+                Some(uid) => storage_dir.join(uid.to_string()),
+                None => storage_dir.to_path_buf(),
+            };
+            Ok(Box::new(FileBackend::new(dir)?))
+        }
+        (Some("postgres") | None, Some(pool), Some(uid)) => {
+            Ok(Box::new(PostgresBackend::new(pool.clone(), uid)))
+        }
+        (Some("postgres"), _, _) => Err(Message::new("state_backend_needs_database").into()),
+        (Some(other), _, _) => Err(Message::new("state_backend_unknown").arg("backend", other).into()),
+    }
+}
+
+/// 当前后端中存有状态的用户（None 为未登录时的本地状态），供后台任务逐个处理；
+/// 与 open_backend 的选择规则一致，不依赖某一种后端的表结构
+pub async fn stored_users(storage_dir: &Path, pool: Option<&db::Pool>) -> AppResult<Vec<Option<Uuid>>> {
+    let kind = backend_kind();
+    match (kind.as_deref(), pool) {
+        (Some("sqlite"), _) => {
+            let pool = sqlite_pool(&sqlite_path(storage_dir)).await?;
+            sqlite_users(&pool).await
+        }
+        (Some("file"), pool) => file_users(storage_dir, pool.is_some()),
+        // 有数据库时登录用户在 PostgreSQL，未登录的命令行仍写本地文件
+        (None, Some(pool)) => {
+            let mut users = file_users(storage_dir, false)?;
+            users.extend(db::users_with_deleted_projects(pool).await?.into_iter().map(Some));
+            Ok(users)
+        }
+        (Some("postgres"), Some(pool)) => {
+            Ok(db::users_with_deleted_projects(pool).await?.into_iter().map(Some).collect())
+        }
+        (None, None) => file_users(storage_dir, false),
+        (Some("postgres"), None) => Err(Message::new("state_backend_needs_database").into()),
+        (Some(other), _) => Err(Message::new("state_backend_unknown").arg("backend", other).into()),
+    }
+}
+
+/// 根目录的 state.json 对应本地用户；per_user 时还包括 `<user_id>/state.json`
+fn file_users(storage_dir: &Path, per_user: bool) -> AppResult<Vec<Option<Uuid>>> {
+    let mut users = Vec::new();
+    if storage_dir.join("state.json").exists() {
+        users.push(None);
+    }
+    if per_user && storage_dir.is_dir() {
+        for entry in fs::read_dir(storage_dir)?.filter_map(|e| e.ok()) {
+            let user_id = entry.file_name().to_str().and_then(|name| Uuid::parse_str(name).ok());
+            if let Some(user_id) = user_id
+                && entry.path().join("state.json").exists()
+            {
+                users.push(Some(user_id));
+            }
+        }
+    }
+    Ok(users)
+}
+
+/// SQLite 中每个 scope 一行状态，"local" 为本地用户
+async fn sqlite_users(pool: &SqlitePool) -> AppResult<Vec<Option<Uuid>>> {
+    let scopes: Vec<String> = sqlx::query_scalar("SELECT scope FROM celadon_state ORDER BY scope")
+        .fetch_all(pool)
+        .await
+        .map_err(|e| Message::new("sqlite_read_state_failed").arg("error", e))?;
+    Ok(scopes.iter().map(|scope| Uuid::parse_str(scope).ok()).collect())
+}
+
+/// 本地 `.celadon/state.json`。
+///
+/// 写入先落到临时文件再 rename，崩溃时不会留下半个文件；每次读-改-写
+/// 都持有 `state.lock` 上的进程间排他锁，CLI 与 `celadon serve` 可以共用
+/// 同一目录；覆盖前把上一份完好的状态轮转进 `backups/`，主文件损坏时
+/// 自动从最近的备份恢复。事件日志 `events/<project_id>.jsonl` 在同一把锁内
+/// 与状态一起写入，见 commit。
+pub struct FileBackend {
+    dir: PathBuf,
+    state_file: PathBuf,
+    backups: usize,
+}
+
+impl FileBackend {
+    pub fn new(dir: PathBuf) -> AppResult<Self> {
+        fs::create_dir_all(&dir)?;
+        let backups = std::env::var("CELADON_STATE_BACKUPS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_STATE_BACKUPS);
+        Ok(Self {
+            state_file: dir.join("state.json"),
+            dir,
+            backups,
+        })
+    }
+
+    fn backup_file(&self, index: usize) -> PathBuf {
+        self.dir.join("backups").join(format!("state.{index}.json"))
+    }
+
+    /// 阻塞获取排他锁（放到 blocking 线程，避免卡住 tokio worker）；
+    /// 返回的文件句柄被 drop 时释放锁
+    async fn lock(&self) -> AppResult<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.dir.join("state.lock"))?;
+        let file = tokio::task::spawn_blocking(move || file.lock().map(|_| file)).await??;
+        Ok(file)
+    }
+
+    /// 读取主文件；损坏时从备份恢复并改写 state.json，调用方需持有锁
+    fn read(&self) -> AppResult<StateStore> {
+        if !self.state_file.exists() {
+            return Ok(StateStore::default());
+        }
+        match read_state_file(&self.state_file) {
+            Ok(state) => Ok(state),
+            Err(e) => self.recover().ok_or_else(|| {
+                Message::new("state_file_unrecoverable").arg("error", e).into()
+            }),
+        }
+    }
+
+    /// 按新旧顺序尝试备份，找到第一份可解析的并写回主文件
+    fn recover(&self) -> Option<StateStore> {
+        for index in 1..=self.backups {
+            let backup = self.backup_file(index);
+            if let Ok(state) = read_state_file(&backup) {
+                let corrupt = self.dir.join(format!("state.corrupt-{}.json", Uuid::new_v4()));
+                let _ = fs::rename(&self.state_file, &corrupt);
+                let payload = serde_json::to_vec_pretty(&state).ok()?;
+                self.write_atomic(&self.state_file, &payload).ok()?;
+                tracing::warn!(
+                    "state.json 已损坏，已从 {} 恢复（原文件保留为 {}）",
+                    backup.display(),
+                    corrupt.display()
+                );
+                return Some(state);
+            }
+        }
+        None
+    }
+
+    fn write(&self, state: &StateStore) -> AppResult<()> {
+        if self.backups > 0 && read_state_file(&self.state_file).is_ok() {
+            fs::create_dir_all(self.dir.join("backups"))?;
+            for index in (1..self.backups).rev() {
+                let from = self.backup_file(index);
+                if from.exists() {
+                    fs::rename(&from, self.backup_file(index + 1))?;
+                }
+            }
+            fs::copy(&self.state_file, self.backup_file(1))?;
+        }
+        self.write_atomic(&self.state_file, &serde_json::to_vec_pretty(state)?)
+    }
+
+    fn journal_file(&self, project_id: &str) -> PathBuf {
+        self.dir.join("events").join(format!("{project_id}.jsonl"))
+    }
+
+    /// 已分配 seq、尚未全部追加到日志的一次提交，先于 state.json 落盘
+    fn pending_file(&self) -> PathBuf {
+        self.dir.join("events").join("pending.json")
+    }
+
+    /// 日志文件最后一条事件的 seq（无文件为 0），优先取进程内缓存
+    fn last_seq(&self, path: &Path) -> AppResult<u64> {
+        let len = match fs::metadata(path) {
+            Ok(meta) => meta.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let mut seqs = JOURNAL_SEQS.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((cached_len, seq)) = seqs.get(path)
+            && *cached_len == len
+        {
+            return Ok(*seq);
+        }
+        #[derive(serde::Deserialize)]
+        struct Seq {
+            seq: u64,
+        }
+        let content = fs::read_to_string(path)?;
+        let seq = complete_lines(&content)
+            .last()
+            .map(|line| serde_json::from_str::<Seq>(line).map(|s| s.seq))
+            .transpose()?
+            .unwrap_or(0);
+        seqs.insert(path.to_path_buf(), (len, seq));
+        Ok(seq)
+    }
+
+    /// 按项目接着日志中最后的 seq 编号
+    fn assign_seqs(&self, events: &[ProjectEvent]) -> AppResult<Vec<ProjectEvent>> {
+        let mut next: HashMap<&str, u64> = HashMap::new();
+        let mut numbered = Vec::with_capacity(events.len());
+        for event in events {
+            let seq = match next.get_mut(event.project_id.as_str()) {
+                Some(seq) => seq,
+                None => next
+                    .entry(&event.project_id)
+                    .or_insert(self.last_seq(&self.journal_file(&event.project_id))?),
+            };
+            *seq += 1;
+            numbered.push(ProjectEvent {
+                seq: *seq,
+                ..event.clone()
+            });
+        }
+        Ok(numbered)
+    }
+
+    /// 追加到 `events/<project_id>.jsonl`；seq 不大于日志末尾的事件已写过，跳过，
+    /// 因此中断后重复执行是安全的。调用方需持有锁
+    fn append_journal(&self, events: &[ProjectEvent]) -> AppResult<()> {
+        fs::create_dir_all(self.dir.join("events"))?;
+        for event in events {
+            let path = self.journal_file(&event.project_id);
+            truncate_torn_line(&path)?;
+            if event.seq <= self.last_seq(&path)? {
+                continue;
+            }
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            writeln!(file, "{}", serde_json::to_string(event)?)?;
+            file.sync_all()?;
+            let len = file.metadata()?.len();
+            JOURNAL_SEQS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(path, (len, event.seq));
+        }
+        Ok(())
+    }
+
+    /// 先记下待追加的事件（seq 已分配）与提交后的 revision，再写 state.json
+    fn write_pending(&self, revision: u64, events: &[ProjectEvent]) -> AppResult<()> {
+        fs::create_dir_all(self.dir.join("events"))?;
+        let pending = serde_json::json!({ "revision": revision, "events": events });
+        self.write_atomic(&self.pending_file(), &serde_json::to_vec(&pending)?)
+    }
+
+    /// 处理上次中断的提交：state.json 已写入（revision 已达到）则把日志补齐，
+    /// 否则该提交并未生效，丢弃。调用方需持有锁
+    fn finish_pending(&self, state: &StateStore) -> AppResult<()> {
+        #[derive(serde::Deserialize)]
+        struct Pending {
+            revision: u64,
+            events: Vec<ProjectEvent>,
+        }
+        let content = match fs::read_to_string(self.pending_file()) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let pending: Pending = serde_json::from_str(&content)?;
+        if state.revision >= pending.revision {
+            self.append_journal(&pending.events)?;
+        }
+        fs::remove_file(self.pending_file())?;
+        Ok(())
+    }
+
+    fn write_atomic(&self, target: &Path, payload: &[u8]) -> AppResult<()> {
+        let tmp = self.dir.join(format!("write.{}.tmp", Uuid::new_v4()));
+        let mut file = File::create(&tmp)?;
+        file.write_all(payload)?;
+        file.sync_all()?;
+        fs::rename(&tmp, target)?;
+        // rename 本身也要落盘，否则掉电后目录项可能仍指向旧文件
+        if let Some(dir) = target.parent().and_then(|dir| File::open(dir).ok()) {
+            let _ = dir.sync_all();
+        }
+        Ok(())
+    }
+}
+
+impl StateBackend for FileBackend {
+    fn load(&self) -> BoxFuture<'_, AppResult<StateStore>> {
+        Box::pin(async move {
+            // 不加锁的读取只在文件完好时返回；损坏时拿到锁后再读，
+            // 期间可能已被其他写入者修复，否则在锁内从备份恢复，不会覆盖并发写入的结果
+            if let Ok(state) = read_state_file(&self.state_file) {
+                return Ok(state);
+            }
+            let _lock = self.lock().await?;
+            self.read()
+        })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        expected: u64,
+        events: &'a [ProjectEvent],
+    ) -> BoxFuture<'a, AppResult<u64>> {
+        Box::pin(async move {
+            let _lock = self.lock().await?;
+            let mut state = self.read()?;
+            self.finish_pending(&state)?;
+            apply_events(&mut state, expected, events)?;
+            // 顺序为 pending → state.json → 日志：任一步中断，下次提交都能按 revision 补齐或丢弃，
+            // 日志不会落后于状态，也不会多出未生效的事件
+            let events = self.assign_seqs(events)?;
+            self.write_pending(state.revision, &events)?;
+            self.write(&state)?;
+            self.append_journal(&events)?;
+            fs::remove_file(self.pending_file())?;
+            Ok(state.revision)
+        })
+    }
+
+    fn load_events<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<Vec<ProjectEvent>>> {
+        Box::pin(async move {
+            let content = match fs::read_to_string(self.journal_file(project_id)) {
+                Ok(content) => content,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+                Err(e) => return Err(e.into()),
+            };
+            let mut events = Vec::new();
+            for line in complete_lines(&content) {
+                events.push(serde_json::from_str(line)?);
+            }
+            Ok(events)
+        })
+    }
+
+    fn delete_events<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            let _lock = self.lock().await?;
+            let path = self.journal_file(project_id);
+            JOURNAL_SEQS.lock().unwrap_or_else(|e| e.into_inner()).remove(&path);
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+/// 以换行结尾的非空行；末尾没有换行的是正在写入或中断时残留的半行，忽略
+fn complete_lines(content: &str) -> impl Iterator<Item = &str> {
+    let end = content.rfind('\n').map_or(0, |i| i + 1);
+    content[..end].lines().filter(|l| !l.trim().is_empty())
+}
+
+/// 截掉日志末尾中断时残留的半行；正常情况下只读最后一个字节
+fn truncate_torn_line(path: &Path) -> AppResult<()> {
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if file.metadata()?.len() == 0 {
+        return Ok(());
+    }
+    let mut last = [0u8];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    if last[0] == b'\n' {
+        return Ok(());
+    }
+    let content = fs::read(path)?;
+    let end = content.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    file.set_len(end as u64)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn read_state_file(path: &Path) -> AppResult<StateStore> {
+    let content = fs::read_to_string(path)?;
+    schema::upgrade(serde_json::from_str(&content)?)
+}
+
+/// PostgreSQL 中按用户隔离的规范化表（projects / sessions / conversation_turns …）
+pub struct PostgresBackend {
+    pool: db::Pool,
+    user_id: Uuid,
+}
+
+impl PostgresBackend {
+    pub fn new(pool: db::Pool, user_id: Uuid) -> Self {
+        Self { pool, user_id }
+    }
+}
+
+impl StateBackend for PostgresBackend {
+    fn load(&self) -> BoxFuture<'_, AppResult<StateStore>> {
+        Box::pin(async move {
+            db::upgrade_user_state(&self.pool, self.user_id).await?;
+            db::load_user_index(&self.pool, self.user_id).await
+        })
+    }
+
+    fn load_project<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<Option<StateStore>>> {
+        Box::pin(async move {
+            let rows = db::load_project_rows(&self.pool, self.user_id, project_id).await?;
+            Ok(Some(rows))
+        })
+    }
+
+    fn commit<'a>(
+        &'a self,
+        expected: u64,
+        events: &'a [ProjectEvent],
+    ) -> BoxFuture<'a, AppResult<u64>> {
+        Box::pin(db::apply_state_changes(&self.pool, self.user_id, expected, events))
+    }
+
+    fn load_events<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<Vec<ProjectEvent>>> {
+        Box::pin(db::load_project_events(&self.pool, self.user_id, project_id))
+    }
+
+    fn delete_events<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(db::delete_project_events(&self.pool, self.user_id, project_id))
+    }
+}
+
+/// 内嵌 SQLite，适合单机部署；scope 区分不同用户（本地 CLI 为 "local"）
+pub struct SqliteBackend {
+    pool: SqlitePool,
+    scope: String,
+}
+
+/// 取出（首次则建立并初始化）该文件的共享连接池
+async fn sqlite_pool(path: &Path) -> AppResult<SqlitePool> {
+    let mut pools = SQLITE_POOLS.lock().await;
+    if let Some(pool) = pools.get(path) {
+        return Ok(pool.clone());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let options = SqliteConnectOptions::new()
+        .filename(path)
+        .create_if_missing(true);
+    let pool = SqlitePoolOptions::new()
+        .max_connections(4)
+        .connect_with(options)
+        .await
+        .map_err(|e| Message::new("sqlite_open_failed").arg("error", e))?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS celadon_state (
+            scope TEXT PRIMARY KEY,
+            state_json TEXT NOT NULL,
+            updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        )",
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| Message::new("sqlite_init_failed").arg("error", e))?;
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS celadon_events (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            scope TEXT NOT NULL,
+            project_id TEXT NOT NULL,
+            event_json TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| Message::new("sqlite_init_failed").arg("error", e))?;
+    pools.insert(path.to_path_buf(), pool.clone());
+    Ok(pool)
+}
+
+/// SQLITE_BUSY / SQLITE_LOCKED：另一写入者正持有写锁
+fn is_busy(e: &sqlx::Error) -> bool {
+    e.as_database_error()
+        .and_then(|e| e.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| matches!(code & 0xff, 5 | 6))
+}
+
+impl SqliteBackend {
+    pub async fn open(path: &Path, scope: String) -> AppResult<Self> {
+        Ok(Self {
+            pool: sqlite_pool(path).await?,
+            scope,
+        })
+    }
+
+    async fn read<'e, E>(&self, executor: E) -> AppResult<StateStore>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+    {
+        let row = sqlx::query("SELECT state_json FROM celadon_state WHERE scope = ?")
+            .bind(&self.scope)
+            .fetch_optional(executor)
+            .await
+            .map_err(|e| Message::new("sqlite_read_state_failed").arg("error", e))?;
+        match row {
+            Some(r) => {
+                let json: String = r.get("state_json");
+                let doc = serde_json::from_str(&json).map_err(|e| Message::new("state_decode_failed").arg("error", e))?;
+                schema::upgrade(doc)
+            }
+            None => Ok(StateStore::default()),
+        }
+    }
+
+    async fn write<'e, E>(&self, executor: E, state: &StateStore) -> AppResult<()>
+    where
+        E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+    {
+        let json = serde_json::to_string(state).map_err(|e| Message::new("state_encode_failed").arg("error", e))?;
+        sqlx::query(
+            "INSERT INTO celadon_state (scope, state_json, updated_at) VALUES (?, ?, CURRENT_TIMESTAMP)
+             ON CONFLICT (scope) DO UPDATE SET state_json = excluded.state_json, updated_at = CURRENT_TIMESTAMP",
+        )
+        .bind(&self.scope)
+        .bind(json)
+        .execute(executor)
+        .await
+        .map_err(|e| Message::new("sqlite_write_state_failed").arg("error", e))?;
+        Ok(())
+    }
+}
+
+impl SqliteBackend {
+    async fn insert_events(&self, conn: &mut SqliteConnection, events: &[ProjectEvent]) -> AppResult<()> {
+        for event in events {
+            let json = serde_json::to_string(event).map_err(|e| Message::new("event_encode_failed").arg("error", e))?;
+            sqlx::query(
+                "INSERT INTO celadon_events (scope, project_id, event_json, created_at) VALUES (?, ?, ?, ?)",
+            )
+            .bind(&self.scope)
+            .bind(&event.project_id)
+            .bind(json)
+            .bind(&event.created_at)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| Message::new("sqlite_write_events_failed").arg("error", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl StateBackend for SqliteBackend {
+    fn load(&self) -> BoxFuture<'_, AppResult<StateStore>> {
+        Box::pin(self.read(&self.pool))
+    }
+
+    fn commit<'a>(
+        &'a self,
+        expected: u64,
+        events: &'a [ProjectEvent],
+    ) -> BoxFuture<'a, AppResult<u64>> {
+        Box::pin(async move {
+            // 拿不到写锁说明另一写入者正在提交，按冲突处理，由服务层在最新状态上重放
+            let conflict = |e: sqlx::Error, key: &'static str| -> Box<dyn std::error::Error + Send + Sync> {
+                if is_busy(&e) {
+                    StateConflict {
+                        expected,
+                        actual: expected + 1,
+                    }
+                    .into()
+                } else {
+                    Message::new(key).arg("error", e).into()
+                }
+            };
+            let mut tx = self
+                .pool
+                .begin()
+                .await
+                .map_err(|e| conflict(e, "sqlite_begin_failed"))?;
+            // sqlx 0.8.0 没有 begin_with("BEGIN IMMEDIATE")：先执行一条写语句取得写锁，
+            // 效果等同 IMMEDIATE 事务。并发写入者在这里按 busy_timeout 排队，
+            // 而不是各自读完后升级写锁时互相 SQLITE_BUSY
+            sqlx::query("UPDATE celadon_state SET scope = scope WHERE scope = ?")
+                .bind(&self.scope)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| conflict(e, "sqlite_lock_failed"))?;
+            let mut state = self.read(&mut *tx).await?;
+            apply_events(&mut state, expected, events)?;
+            self.write(&mut *tx, &state).await?;
+            self.insert_events(&mut tx, events).await?;
+            tx.commit()
+                .await
+                .map_err(|e| conflict(e, "sqlite_commit_failed"))?;
+            Ok(state.revision)
+        })
+    }
+
+    fn load_events<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<Vec<ProjectEvent>>> {
+        Box::pin(async move {
+            let rows = sqlx::query(
+                "SELECT seq, event_json FROM celadon_events WHERE scope = ? AND project_id = ? ORDER BY seq",
+            )
+            .bind(&self.scope)
+            .bind(project_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| Message::new("sqlite_read_events_failed").arg("error", e))?;
+            let mut events = Vec::new();
+            for r in rows {
+                let json: String = r.get("event_json");
+                let mut event: ProjectEvent =
+                    serde_json::from_str(&json).map_err(|e| Message::new("event_decode_failed").arg("error", e))?;
+                event.seq = r.get::<i64, _>("seq") as u64;
+                events.push(event);
+            }
+            Ok(events)
+        })
+    }
+
+    fn delete_events<'a>(&'a self, project_id: &'a str) -> BoxFuture<'a, AppResult<()>> {
+        Box::pin(async move {
+            sqlx::query("DELETE FROM celadon_events WHERE scope = ? AND project_id = ?")
+                .bind(&self.scope)
+                .bind(project_id)
+                .execute(&self.pool)
+                .await
+                .map_err(|e| Message::new("sqlite_delete_events_failed").arg("error", e))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::StateChange;
+
+    fn touched(project_id: &str) -> Vec<ProjectEvent> {
+        vec![ProjectEvent {
+            seq: 0,
+            project_id: project_id.to_string(),
+            change: StateChange::ProjectTouched {
+                project_id: project_id.to_string(),
+                updated_at: "2024-01-01T00:00:00Z".to_string(),
+            },
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+        }]
+    }
+
+    #[tokio::test]
+    async fn concurrent_sqlite_commits_conflict_instead_of_failing() {
+        let path = std::env::temp_dir().join(format!("celadon-store-{}.db", Uuid::new_v4()));
+        let backend = SqliteBackend::open(&path, "local".to_string()).await.unwrap();
+        let events = touched("p1");
+
+        // 同一 revision 上的并发提交：只有一个成功，其余都应是可重试的冲突
+        let results = futures::future::join_all((0..4).map(|_| backend.commit(0, &events))).await;
+        let committed = results.iter().filter(|r| r.is_ok()).count();
+        assert_eq!(committed, 1);
+        for result in results.iter().filter(|r| r.is_err()) {
+            let err = result.as_ref().unwrap_err();
+            assert!(err.is::<StateConflict>(), "expected a conflict, got {err}");
+        }
+        assert_eq!(backend.load().await.unwrap().revision, 1);
+
+        // 同一文件再次打开复用同一个连接池
+        let reopened = SqliteBackend::open(&path, "local".to_string()).await.unwrap();
+        assert_eq!(reopened.commit(1, &events).await.unwrap(), 2);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn corrupt_state_file_is_recovered_only_under_the_lock() {
+        let dir = std::env::temp_dir().join(format!("celadon-store-{}", Uuid::new_v4()));
+        let backend = FileBackend::new(dir.clone()).unwrap();
+        backend.commit(0, &touched("p1")).await.unwrap();
+        backend.commit(1, &touched("p1")).await.unwrap();
+        fs::write(dir.join("state.json"), "{ not json").unwrap();
+
+        // 其他写入者持有锁时，读取方不能抢先用备份覆盖 state.json
+        let lock = backend.lock().await.unwrap();
+        let waiting = tokio::time::timeout(std::time::Duration::from_millis(200), backend.load()).await;
+        assert!(waiting.is_err(), "load must wait for the lock before recovering");
+        assert_eq!(fs::read_to_string(dir.join("state.json")).unwrap(), "{ not json");
+        drop(lock);
+
+        let state = backend.load().await.unwrap();
+        assert_eq!(state.revision, 1);
+        let kept = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .any(|e| e.file_name().to_string_lossy().starts_with("state.corrupt-"));
+        assert!(kept, "the corrupt file should be kept for inspection");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn interrupted_file_commits_are_completed_or_discarded() {
+        let dir = std::env::temp_dir().join(format!("celadon-store-{}", Uuid::new_v4()));
+        let backend = FileBackend::new(dir.clone()).unwrap();
+        backend.commit(0, &touched("p1")).await.unwrap();
+
+        // 中断在 state.json 写入之后、追加日志之前：下次提交先补齐日志
+        let mut state = backend.read().unwrap();
+        apply_events(&mut state, 1, &touched("p1")).unwrap();
+        let events = backend.assign_seqs(&touched("p1")).unwrap();
+        backend.write_pending(state.revision, &events).unwrap();
+        backend.write(&state).unwrap();
+        backend.commit(2, &touched("p1")).await.unwrap();
+
+        // 中断在 state.json 写入之前：该提交未生效，待追加的事件被丢弃
+        let events = backend.assign_seqs(&touched("p1")).unwrap();
+        backend.write_pending(4, &events).unwrap();
+        backend.commit(3, &touched("p1")).await.unwrap();
+
+        // 追加到一半的行：读取时忽略，下次提交前截掉
+        let mut journal = OpenOptions::new().append(true).open(backend.journal_file("p1")).unwrap();
+        journal.write_all(b"{\"seq\":5,\"proj").unwrap();
+        assert_eq!(backend.load_events("p1").await.unwrap().len(), 4);
+        backend.commit(4, &touched("p1")).await.unwrap();
+
+        let seqs: Vec<u64> = backend.load_events("p1").await.unwrap().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+        assert_eq!(backend.load().await.unwrap().revision, 5);
+        assert!(!backend.pending_file().exists());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn stored_users_come_from_the_backend_itself() {
+        let dir = std::env::temp_dir().join(format!("celadon-store-{}", Uuid::new_v4()));
+        let user = Uuid::new_v4();
+        FileBackend::new(dir.clone()).unwrap().commit(0, &touched("p1")).await.unwrap();
+        FileBackend::new(dir.join(user.to_string())).unwrap().commit(0, &touched("p2")).await.unwrap();
+        fs::create_dir_all(dir.join("workspaces")).unwrap();
+
+        assert_eq!(file_users(&dir, false).unwrap(), vec![None]);
+        let mut users = file_users(&dir, true).unwrap();
+        users.sort();
+        assert_eq!(users, vec![None, Some(user)]);
+
+        let path = dir.join("state.db");
+        SqliteBackend::open(&path, "local".to_string()).await.unwrap().commit(0, &touched("p1")).await.unwrap();
+        let backend = SqliteBackend::open(&path, user.to_string()).await.unwrap();
+        backend.commit(0, &touched("p2")).await.unwrap();
+        let mut users = sqlite_users(&backend.pool).await.unwrap();
+        users.sort();
+        assert_eq!(users, vec![None, Some(user)]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,224 @@
+//! 模型用量与费用：价格表、估算 Zene 开发运行的用量，以及按项目/角色/模型汇总。
+//!
+//! 价格表来自 CELADON_LLM_PRICES（环境变量或系统设置），为 JSON 对象，单位是每百万
+//! token 的美元价格：`{"deepseek-chat": {"prompt": 0.27, "completion": 1.1}}`。
+//! 模型名先精确匹配，再取最长的前缀匹配；没有价格的模型费用记为 0。
+
+use crate::context;
+use crate::i18n::Message;
+use crate::models::{Budget, UsageRecord, UsageRole};
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use zene::AgentEvent;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+pub type PriceTable = HashMap<String, ModelPrice>;
+
+pub fn parse_prices(text: &str) -> PriceTable {
+    if text.trim().is_empty() {
+        return PriceTable::new();
+    }
+    serde_json::from_str(text).unwrap_or_else(|e| {
+        tracing::warn!("CELADON_LLM_PRICES 格式无效，已忽略: {e}");
+        PriceTable::new()
+    })
+}
+
+pub fn cost(prices: &PriceTable, model: &str, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+    let price = prices.get(model).or_else(|| {
+        prices
+            .iter()
+            .filter(|(name, _)| model.starts_with(name.as_str()))
+            .max_by_key(|(name, _)| name.len())
+            .map(|(_, price)| price)
+    });
+    price.map_or(0.0, |p| {
+        (prompt_tokens as f64 * p.prompt + completion_tokens as f64 * p.completion) / 1_000_000.0
+    })
+}
+
+/// 一次调用的用量，尚未归属到项目
+#[derive(Debug, Clone)]
+pub struct CallUsage {
+    pub role: UsageRole,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub estimated: bool,
+}
+
+/// 开发运行的用量估算。Zene 的事件不带 token 数，按执行模型估算文本：ThoughtDelta 是
+/// 模型输出，ToolResult 会作为下一轮输入回传给模型。累计到取走时记为一次 executor 调用
+#[derive(Debug, Default)]
+pub struct RunMeter {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+impl RunMeter {
+    pub fn observe(&mut self, model: &str, event: &AgentEvent) {
+        match event {
+            AgentEvent::ThoughtDelta(text) => {
+                self.completion_tokens += context::count_tokens(model, text) as u64;
+            }
+            AgentEvent::ToolResult { result, .. } => {
+                self.prompt_tokens += context::message_tokens(model, result) as u64;
+            }
+            _ => {}
+        }
+    }
+
+    /// 取走累计的估算用量；没有新增时返回 None
+    pub fn take(&mut self, model: &str) -> Option<CallUsage> {
+        let meter = std::mem::take(self);
+        (meter.prompt_tokens + meter.completion_tokens > 0).then(|| CallUsage {
+            role: UsageRole::Executor,
+            model: model.to_string(),
+            prompt_tokens: meter.prompt_tokens,
+            completion_tokens: meter.completion_tokens,
+            estimated: true,
+        })
+    }
+}
+
+/// 汇总后的一行：同一用户、项目、角色与模型
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageRow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    pub project_id: String,
+    pub role: UsageRole,
+    pub model: String,
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+}
+
+pub fn aggregate<'a>(records: impl IntoIterator<Item = &'a UsageRecord>) -> Vec<UsageRow> {
+    let mut rows: BTreeMap<(String, UsageRole, String), UsageRow> = BTreeMap::new();
+    for r in records {
+        let row = rows
+            .entry((r.project_id.clone(), r.role, r.model.clone()))
+            .or_insert_with(|| UsageRow {
+                user: None,
+                project_id: r.project_id.clone(),
+                role: r.role,
+                model: r.model.clone(),
+                calls: 0,
+                prompt_tokens: 0,
+                completion_tokens: 0,
+                cost: 0.0,
+            });
+        row.calls += 1;
+        row.prompt_tokens += r.prompt_tokens;
+        row.completion_tokens += r.completion_tokens;
+        row.cost += r.cost;
+    }
+    rows.into_values().collect()
+}
+
+/// 报表：合计与明细
+pub fn report(rows: Vec<UsageRow>) -> Value {
+    let total = rows.iter().fold((0, 0, 0, 0.0), |acc, r| {
+        (
+            acc.0 + r.calls,
+            acc.1 + r.prompt_tokens,
+            acc.2 + r.completion_tokens,
+            acc.3 + r.cost,
+        )
+    });
+    json!({
+        "total": {
+            "calls": total.0,
+            "prompt_tokens": total.1,
+            "completion_tokens": total.2,
+            "cost": total.3
+        },
+        "rows": rows
+    })
+}
+
+/// 一段时间内的用量
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Spend {
+    pub tokens: u64,
+    pub cost: f64,
+}
+
+/// 预算按 UTC 自然月计算
+pub fn month_start() -> DateTime<Utc> {
+    let now = Utc::now();
+    Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .single()
+        .unwrap_or(now)
+}
+
+pub fn spend_since<'a>(records: impl IntoIterator<Item = &'a UsageRecord>, since: DateTime<Utc>) -> Spend {
+    records
+        .into_iter()
+        .filter(|r| {
+            DateTime::parse_from_rfc3339(&r.created_at).is_ok_and(|at| at >= since)
+        })
+        .fold(Spend::default(), |acc, r| Spend {
+            tokens: acc.tokens + r.prompt_tokens + r.completion_tokens,
+            cost: acc.cost + r.cost,
+        })
+}
+
+/// 预算的适用范围
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BudgetScope {
+    User,
+    Project,
+}
+
+/// 本月用量已达上限，拒绝新的模型调用
+#[derive(Debug)]
+pub struct BudgetExceeded {
+    pub scope: BudgetScope,
+    pub budget: Budget,
+    pub spent: Spend,
+}
+
+impl BudgetExceeded {
+    /// 用量达到任一上限时返回错误
+    pub fn check(scope: BudgetScope, budget: Budget, spent: Spend) -> Result<(), Self> {
+        let over_tokens = budget.max_tokens.is_some_and(|max| spent.tokens >= max);
+        let over_cost = budget.max_cost.is_some_and(|max| spent.cost >= max);
+        if over_tokens || over_cost {
+            Err(Self { scope, budget, spent })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 触发的那一项上限
+    fn limit(&self) -> String {
+        match self.budget.max_tokens.filter(|max| self.spent.tokens >= *max) {
+            Some(tokens) => format!("{tokens} tokens"),
+            None => format!("${:.2}", self.budget.max_cost.unwrap_or_default()),
+        }
+    }
+}
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = match self.scope {
+            BudgetScope::User => "budget_exceeded_user",
+            BudgetScope::Project => "budget_exceeded_project",
+        };
+        write!(f, "{}", Message::new(key).arg("limit", self.limit()))
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
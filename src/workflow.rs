@@ -0,0 +1,174 @@
+//! 会话阶段状态机，对应 docs/development-process.md §7.4：
+//!
+//! `IDEA_COLLECTING -> CLARIFYING -> PRD_CONFIRMED -> DEVELOPING -> TESTING -> DEPLOYING -> DELIVERED`
+//!
+//! 追加新需求时从 PRD_CONFIRMED 及之后的阶段回到 IDEA_COLLECTING 开始增量迭代；
+//! 测试或部署失败时回到 DEVELOPING 重试。开始测试与提交测试结论是两个动作，
+//! 部署只能从 TESTING（或已交付后重新部署）开始，且当前迭代的测试须已通过。
+
+use crate::i18n::{self, Message};
+use crate::models::{RequirementsSheet, Stage};
+use serde::Serialize;
+use std::fmt;
+
+/// 每个阶段允许进入的下一阶段（含自环，如多轮澄清、重新生成 PRD）
+pub fn allowed_targets(from: Stage) -> &'static [Stage] {
+    use Stage::*;
+    match from {
+        IdeaCollecting => &[Clarifying],
+        Clarifying => &[Clarifying, PrdConfirmed],
+        PrdConfirmed => &[PrdConfirmed, Developing, IdeaCollecting],
+        Developing => &[Developing, Testing, IdeaCollecting],
+        Testing => &[Testing, Developing, Deploying, IdeaCollecting],
+        Deploying => &[Delivered, Developing, IdeaCollecting],
+        Delivered => &[Deploying, IdeaCollecting],
+    }
+}
+
+pub fn can_transition(from: Stage, to: Stage) -> bool {
+    allowed_targets(from).contains(&to)
+}
+
+/// 会话上可执行的动作，对应 idea / prd generate / dev run / test start / test result / deploy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    AppendIdea,
+    GeneratePrd,
+    RunDev,
+    StartTests,
+    RecordTestResult,
+    Deploy,
+}
+
+impl Action {
+    pub const ALL: [Action; 6] = [
+        Action::AppendIdea,
+        Action::GeneratePrd,
+        Action::RunDev,
+        Action::StartTests,
+        Action::RecordTestResult,
+        Action::Deploy,
+    ];
+
+    /// 动作会依次经过的阶段；实际执行时从当前阶段可进入的第一个开始
+    fn route(self) -> &'static [Stage] {
+        match self {
+            Action::AppendIdea => &[Stage::IdeaCollecting, Stage::Clarifying],
+            Action::GeneratePrd => &[Stage::PrdConfirmed],
+            Action::RunDev => &[Stage::Developing],
+            Action::StartTests | Action::RecordTestResult => &[Stage::Testing],
+            Action::Deploy => &[Stage::Deploying, Stage::Delivered],
+        }
+    }
+
+    /// 只能在这些阶段执行的动作；None 表示只要能进入 route 即可
+    fn sources(self) -> Option<&'static [Stage]> {
+        match self {
+            Action::RecordTestResult => Some(&[Stage::Testing]),
+            _ => None,
+        }
+    }
+
+    /// 从 from 出发执行该动作要经过的阶段序列，不可执行时返回 IllegalTransition
+    pub fn plan(self, from: Stage) -> Result<Vec<Stage>, StageError> {
+        let route = self.route();
+        let illegal = StageError::IllegalTransition {
+            action: self,
+            from,
+            to: route[route.len() - 1],
+        };
+        if self.sources().is_some_and(|sources| !sources.contains(&from)) {
+            return Err(illegal);
+        }
+        let start = route
+            .iter()
+            .position(|to| can_transition(from, *to))
+            .ok_or(illegal)?;
+        Ok(route[start..].to_vec())
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = match self {
+            Action::AppendIdea => "action_append_idea",
+            Action::GeneratePrd => "action_generate_prd",
+            Action::RunDev => "action_run_dev",
+            Action::StartTests => "action_start_tests",
+            Action::RecordTestResult => "action_record_test_result",
+            Action::Deploy => "action_deploy",
+        };
+        f.write_str(&i18n::t(key))
+    }
+}
+
+/// 阶段校验失败：当前阶段不允许该动作，或缺少前置产物（missing 为文案 key）
+#[derive(Debug)]
+pub enum StageError {
+    IllegalTransition { action: Action, from: Stage, to: Stage },
+    MissingPrerequisite { action: Action, missing: &'static str },
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            StageError::IllegalTransition { action, from, to } => Message::new("stage_illegal")
+                .arg("action", action)
+                .arg("from", stage_name(*from))
+                .arg("to", stage_name(*to)),
+            StageError::MissingPrerequisite { action, missing } => Message::new("stage_missing")
+                .arg("action", action)
+                .arg("missing", i18n::t(missing)),
+        };
+        write!(f, "{message}")
+    }
+}
+
+impl std::error::Error for StageError {}
+
+/// 阶段的 serde 名称，如 PRD_CONFIRMED
+pub fn stage_name(stage: Stage) -> String {
+    serde_json::to_value(stage)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// 澄清提示词要求模型在信息充分时给出的结束语（见 CLARIFY_SYSTEM 及其英文版）
+const READY_PHRASES: &[&str] = &["需求已澄清", "Requirements are clear"];
+
+/// 达到该完整度即认为可以生成 PRD
+const READY_SCORE: u32 = 80;
+
+/// 澄清完整度：按需求清单逐项打分，得出是否可以进入 PRD 生成
+#[derive(Debug, Clone, Serialize)]
+pub struct Readiness {
+    /// 0-100
+    pub score: u32,
+    pub ready_for_prd: bool,
+    pub missing: Vec<String>,
+}
+
+/// 需求清单各项的权重，合计 100；范围与成功标准是生成 PRD 的必要信息
+pub fn evaluate_readiness(sheet: &RequirementsSheet, assistant_reply: &str) -> Readiness {
+    let checks: [(&'static str, u32, bool); 6] = [
+        ("req_scope", 25, sheet.scope.is_some()),
+        ("req_success_criteria", 25, !sheet.success_criteria.is_empty()),
+        ("req_tech_stack", 15, !sheet.tech_stack.is_empty()),
+        ("req_deadline", 10, sheet.deadline.is_some()),
+        ("req_budget", 10, sheet.budget.is_some()),
+        ("req_open_questions", 15, sheet.open_questions.is_empty()),
+    ];
+    let score = checks.iter().filter(|c| c.2).map(|c| c.1).sum();
+    let missing: Vec<String> = checks.iter().filter(|c| !c.2).map(|c| i18n::t(c.0)).collect();
+    let essentials = sheet.scope.is_some() && !sheet.success_criteria.is_empty();
+    // 模型明确表示已澄清时，只要求必要信息齐全
+    let ready_for_prd =
+        essentials && (score >= READY_SCORE || READY_PHRASES.iter().any(|p| assistant_reply.contains(p)));
+    Readiness {
+        score,
+        ready_for_prd,
+        missing,
+    }
+}
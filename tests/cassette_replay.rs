@@ -0,0 +1,74 @@
+//! 用仓库中的录制文件回放 start → idea → prd → dev：不配置任何模型，
+//! 回复与开发事件全部取自 tests/fixtures/mock_flow.cassette.json（由 mock 模式录制）。
+
+mod common;
+
+use common::{Sandbox, failure, json_output, non_empty};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::process::Command;
+
+const IDEA: &str = "做一个团队记账小工具";
+const ANSWER: &str = "MVP 就行，用 Rust 和 React";
+
+fn fixture() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/mock_flow.cassette.json")
+}
+
+fn replay(sandbox: &Sandbox, args: &[&str]) -> Command {
+    let mut command = sandbox.command(args);
+    command
+        .env("CELADON_CASSETTE", "replay")
+        .env("CELADON_CASSETTE_PATH", fixture());
+    command
+}
+
+/// 录制文件中第 index 条调用的回复内容
+fn recorded_content(index: usize) -> String {
+    let tape: Value = serde_json::from_str(&std::fs::read_to_string(fixture()).unwrap()).unwrap();
+    tape["interactions"][index]["response"]["content"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+#[test]
+fn replays_the_recorded_flow_without_a_provider() {
+    let sandbox = Sandbox::new("cassette-replay");
+
+    let started = json_output(&mut replay(&sandbox, &["start", "--idea", IDEA]));
+    assert_eq!(started["assistant_reply"], recorded_content(0));
+    let session_id = started["session_id"].as_str().unwrap().to_string();
+    let project_id = started["project_id"].as_str().unwrap().to_string();
+
+    let answered = json_output(&mut replay(&sandbox, &["idea", "--session-id", &session_id, ANSWER]));
+    assert_eq!(answered["assistant_reply"], recorded_content(2));
+    // 需求抽取的结构化结果同样来自录制文件
+    assert_eq!(sandbox.state()["sessions"][&session_id]["requirements"]["scope"], "MVP");
+
+    let prd = json_output(&mut replay(&sandbox, &["prd", "generate", "--session-id", &session_id]));
+    assert_eq!(prd["content"], recorded_content(4));
+    assert!(non_empty(&sandbox.path(&format!("prd/{project_id}/v1.md"))));
+
+    json_output(&mut replay(&sandbox, &["dev", "run", "--session-id", &session_id]));
+
+    // 对话调用的用量沿用录制时的模型名；开发运行按执行模型估算
+    let state = sandbox.state();
+    let usage = state["usage"].as_array().unwrap();
+    let (executor, chat): (Vec<&Value>, Vec<&Value>) = usage.iter().partition(|u| u["role"] == "executor");
+    assert!(!chat.is_empty() && chat.iter().all(|u| u["model"] == "mock"));
+    assert!(!executor.is_empty() && executor.iter().all(|u| u["estimated"] == true));
+}
+
+#[test]
+fn mismatched_request_fails_with_the_cassette_path() {
+    let sandbox = Sandbox::new("cassette-mismatch");
+
+    let started = json_output(&mut replay(&sandbox, &["start", "--idea", IDEA]));
+    let session_id = started["session_id"].as_str().unwrap();
+
+    // 从未录制过的请求不能被回放
+    let stderr = failure(&mut replay(&sandbox, &["idea", "--session-id", session_id, "换成做一个读书笔记应用"]));
+    assert!(stderr.contains("cassette_mismatch"), "{stderr}");
+    assert!(stderr.contains(&fixture().display().to_string()), "{stderr}");
+}
@@ -0,0 +1,72 @@
+//! 集成测试共用：在临时目录中运行 celadon 命令行，状态写在该目录的 `.celadon/`。
+
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+pub struct Sandbox {
+    dir: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("celadon-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Self { dir }
+    }
+
+    /// 沙盒目录中的 celadon 命令，已清除会改变行为的环境变量
+    pub fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_celadon"));
+        command.args(args).current_dir(&self.dir);
+        for var in [
+            "DATABASE_URL",
+            "CELADON_STATE_BACKEND",
+            "CELADON_CASSETTE",
+            "CELADON_CASSETTE_PATH",
+            "CELADON_MOCK_SCRIPT",
+            "ZENE_PLANNER_PROVIDER",
+        ] {
+            command.env_remove(var);
+        }
+        command
+    }
+
+    pub fn path(&self, relative: &str) -> PathBuf {
+        self.dir.join(".celadon").join(relative)
+    }
+
+    pub fn state(&self) -> Value {
+        serde_json::from_str(&fs::read_to_string(self.path("state.json")).unwrap()).unwrap()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// 运行命令并解析其 JSON 输出；命令失败时带上 stderr 使测试失败
+pub fn json_output(command: &mut Command) -> Value {
+    let output: Output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{command:?} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+/// 运行预期失败的命令，返回其 stderr
+pub fn failure(command: &mut Command) -> String {
+    let output = command.output().unwrap();
+    assert!(!output.status.success(), "{command:?} should have failed");
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+pub fn non_empty(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.len() > 0)
+}
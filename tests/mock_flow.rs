@@ -0,0 +1,131 @@
+//! 用 ZENE_PLANNER_PROVIDER=mock 离线走通 start → idea → prd → dev → deploy，
+//! 每步调用命令行并检查写入 `.celadon/` 的状态。
+
+mod common;
+
+use common::{Sandbox, failure, json_output, non_empty};
+use serde_json::Value;
+
+fn run(sandbox: &Sandbox, args: &[&str]) -> Value {
+    json_output(sandbox.command(args).env("ZENE_PLANNER_PROVIDER", "mock"))
+}
+
+fn run_failing(sandbox: &Sandbox, args: &[&str]) -> String {
+    failure(sandbox.command(args).env("ZENE_PLANNER_PROVIDER", "mock"))
+}
+
+#[test]
+fn mock_provider_runs_the_whole_flow() {
+    let sandbox = Sandbox::new("mock-flow");
+
+    let started = run(&sandbox, &["start", "--idea", "做一个团队记账小工具"]);
+    let session_id = started["session_id"].as_str().unwrap().to_string();
+    let project_id = started["project_id"].as_str().unwrap().to_string();
+    assert_eq!(started["stage"], "CLARIFYING");
+    assert!(!started["assistant_reply"].as_str().unwrap().is_empty());
+    let initial_score = started["readiness_score"].as_u64().unwrap();
+
+    let answered = run(&sandbox, &["idea", "--session-id", &session_id, "MVP 就行，用 Rust 和 React"]);
+    assert!(answered["readiness_score"].as_u64().unwrap() > initial_score);
+
+    let prd = run(&sandbox, &["prd", "generate", "--session-id", &session_id]);
+    assert_eq!(prd["version"], 1);
+    assert!(non_empty(&sandbox.path(&format!("prd/{project_id}/v1.md"))));
+
+    let dev = run(&sandbox, &["dev", "run", "--session-id", &session_id]);
+    assert_eq!(dev["message"], "development workflow executed");
+    for file in ["README.md", "index.html", "app.js"] {
+        assert!(
+            non_empty(&sandbox.path(&format!("workspaces/{project_id}/{file}"))),
+            "mock dev run should write {file}"
+        );
+    }
+
+    // 部署必须先进入测试并提交通过的结论
+    let skipped = run_failing(&sandbox, &["deploy", "--session-id", &session_id]);
+    assert!(skipped.contains("IllegalTransition"), "{skipped}");
+    let testing = run(&sandbox, &["test", "start", "--session-id", &session_id]);
+    assert_eq!(testing["stage"], "TESTING");
+    let untested = run_failing(&sandbox, &["deploy", "--session-id", &session_id]);
+    assert!(untested.contains("prereq_test_pass"), "{untested}");
+
+    let failed = run(&sandbox, &["test", "result", "--session-id", &session_id, "--passed", "false", "--notes", "按钮无响应"]);
+    assert_eq!(failed["stage"], "DEVELOPING");
+    run(&sandbox, &["test", "start", "--session-id", &session_id]);
+    let passed = run(&sandbox, &["test", "result", "--session-id", &session_id, "--passed", "true"]);
+    assert_eq!(passed["stage"], "TESTING");
+
+    let deploy = run(&sandbox, &["deploy", "--session-id", &session_id]);
+    assert_eq!(deploy["result"], "SIMULATED_SUCCESS");
+    assert_eq!(deploy["version"], "prd-v1");
+
+    let status = run(&sandbox, &["status", "--session-id", &session_id]);
+    assert_eq!(status["session"]["stage"], "DELIVERED");
+
+    let state = sandbox.state();
+    assert_eq!(state["sessions"][&session_id]["stage"], "DELIVERED");
+    let tasks = state["task_runs"].as_array().unwrap();
+    assert_eq!(tasks.len(), 1);
+    let deploys = state["deployment_runs"].as_array().unwrap();
+    assert_eq!(deploys.len(), 1);
+
+    let iterations = state["iterations"].as_array().unwrap();
+    assert_eq!(iterations.len(), 1);
+    let iteration = &iterations[0];
+    assert_eq!(iteration["status"], "DELIVERED");
+    assert_eq!(iteration["prd_version"], 1);
+    assert_eq!(iteration["task_ids"], serde_json::json!([tasks[0]["task_id"]]));
+    assert_eq!(iteration["deploy_id"], deploys[0]["deploy_id"]);
+    assert_eq!(iteration["test_result"]["passed"], true);
+    let stages: Vec<&str> = state["sessions"][&session_id]["stage_history"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|t| t["to"].as_str().unwrap())
+        .collect();
+    assert!(stages.ends_with(&["TESTING", "DEVELOPING", "TESTING", "DEPLOYING", "DELIVERED"]), "{stages:?}");
+
+    // 澄清、PRD 与开发运行都按 mock 记录用量，开发运行的用量为估算
+    let usage = state["usage"].as_array().unwrap();
+    assert!(usage.iter().all(|u| u["model"] == "mock"));
+    assert!(usage.iter().any(|u| u["role"] == "planner"));
+    let executor: Vec<&Value> = usage.iter().filter(|u| u["role"] == "executor").collect();
+    assert!(!executor.is_empty() && executor.iter().all(|u| u["estimated"] == true));
+
+    assert!(non_empty(&sandbox.path(&format!("events/{project_id}.jsonl"))));
+}
+
+#[test]
+fn budget_stops_a_mock_dev_run_without_a_stream_consumer() {
+    let sandbox = Sandbox::new("mock-budget");
+
+    let started = run(&sandbox, &["start", "--idea", "做一个团队记账小工具"]);
+    let session_id = started["session_id"].as_str().unwrap().to_string();
+    let project_id = started["project_id"].as_str().unwrap().to_string();
+    run(&sandbox, &["idea", "--session-id", &session_id, "MVP 就行，用 Rust 和 React"]);
+    run(&sandbox, &["prd", "generate", "--session-id", &session_id]);
+
+    // 上限只比已用的 token 多 1：开发运行第一次记入估算用量后即超限
+    let spent: u64 = sandbox.state()["usage"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|u| u["prompt_tokens"].as_u64().unwrap() + u["completion_tokens"].as_u64().unwrap())
+        .sum();
+    let limit = (spent + 1).to_string();
+    run(&sandbox, &["project", "settings", "--project", &project_id, "--max-tokens", &limit]);
+
+    let dev = run(&sandbox, &["dev", "run", "--session-id", &session_id]);
+    assert_eq!(dev["message"], "development workflow executed");
+
+    let state = sandbox.state();
+    let tasks = state["task_runs"].as_array().unwrap();
+    assert_eq!(tasks.len(), 1);
+    assert_eq!(tasks[0]["run_status"], "BUDGET_EXCEEDED");
+    let usage = state["usage"].as_array().unwrap();
+    assert!(usage.iter().any(|u| u["role"] == "executor" && u["estimated"] == true));
+
+    // 超限后不能再启动开发
+    let refused = run_failing(&sandbox, &["dev", "run", "--session-id", &session_id]);
+    assert!(refused.contains("BudgetExceeded"), "{refused}");
+}
@@ -143,6 +143,26 @@ export async function apiDevRun(
   return data as unknown as DevRunResult;
 }
 
+export interface TestResult {
+  passed: boolean;
+  notes: string;
+  tested_at: string;
+}
+
+export async function apiStartTests(sessionId: string): Promise<{ stage: string }> {
+  const data = await postJson("/api/test/start", { session_id: sessionId });
+  return data as unknown as { stage: string };
+}
+
+export async function apiRecordTestResult(
+  sessionId: string,
+  passed: boolean,
+  notes = ""
+): Promise<{ stage: string; test_result: TestResult }> {
+  const data = await postJson("/api/test/result", { session_id: sessionId, passed, notes });
+  return data as unknown as { stage: string; test_result: TestResult };
+}
+
 export interface DeployResult {
   session_id: string;
   env: string;